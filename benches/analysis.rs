@@ -0,0 +1,93 @@
+//! Throughput of the analysis of a synthetic IVF file, in frames per second.
+//!
+//! Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use elevator::{AnalysisOptions, FileFormat};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Write};
+
+/// Number of frames in the synthetic stream
+const FRAME_COUNT: u32 = 3000;
+
+/// Size of the tile data of each frame in bytes
+const TILE_DATA_SIZE: usize = 1000;
+
+/// Sequence header OBU: profile 0, level 2.0, 64x64 8-bit 4:2:0 frames, every optional tool disabled
+const SEQUENCE_HEADER: [u8; 11] = [
+    0x0A, 0x09, 0x00, 0x00, 0x00, 0x02, 0xAF, 0xFF, 0x80, 0x00, 0x01,
+];
+
+const TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
+
+/// Frame header OBU: shown key frame with a single tile, base_q_idx 100, and every filter disabled
+const FRAME_HEADER: [u8; 8] = [0x1A, 0x06, 0x11, 0xB2, 0x00, 0x00, 0x00, 0x40];
+
+/// Builds an IVF file of key frames at 30 fps, whose tile data is never parsed by the analysis.
+fn ivf_stream() -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(b"DKIF");
+    data.extend_from_slice(&0_u16.to_le_bytes()); // version
+    data.extend_from_slice(&32_u16.to_le_bytes()); // header size
+    data.extend_from_slice(b"AV01");
+    data.extend_from_slice(&64_u16.to_le_bytes()); // width
+    data.extend_from_slice(&64_u16.to_le_bytes()); // height
+    data.extend_from_slice(&30_u32.to_le_bytes()); // time base denominator
+    data.extend_from_slice(&1_u32.to_le_bytes()); // time base numerator
+    data.extend_from_slice(&FRAME_COUNT.to_le_bytes());
+    data.extend_from_slice(&0_u32.to_le_bytes()); // unused
+
+    // Tile group OBU, with a 2-byte size field
+    let mut tile_group = vec![
+        0x22,
+        (TILE_DATA_SIZE & 0x7F) as u8 | 0x80,
+        (TILE_DATA_SIZE >> 7) as u8,
+    ];
+    tile_group.resize(tile_group.len() + TILE_DATA_SIZE, 0xA5);
+
+    for i in 0..FRAME_COUNT {
+        let mut frame = TEMPORAL_DELIMITER.to_vec();
+        if i == 0 {
+            frame.extend_from_slice(&SEQUENCE_HEADER);
+        }
+        frame.extend_from_slice(&FRAME_HEADER);
+        frame.extend_from_slice(&tile_group);
+
+        data.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        data.extend_from_slice(&u64::from(i).to_le_bytes());
+        data.extend_from_slice(&frame);
+    }
+
+    data
+}
+
+fn bench_analysis(c: &mut Criterion) {
+    // The stream is read from a file like in the CLI, so that the cost of seeking is measured.
+    let path = std::env::temp_dir().join("elevator-bench.ivf");
+    let stream = ivf_stream();
+    File::create(&path)
+        .and_then(|mut file| file.write_all(&stream))
+        .unwrap();
+
+    let mut group = c.benchmark_group("analysis");
+    group.throughput(Throughput::Elements(u64::from(FRAME_COUNT)));
+    group.bench_function("ivf", |b| {
+        b.iter(|| {
+            let reader = BufReader::new(File::open(&path).unwrap());
+            elevator::analyze(reader, FileFormat::IVF, &AnalysisOptions::default()).unwrap()
+        })
+    });
+    // Memory-mapped input, as read by the CLI with the mmap feature, is analyzed like a slice.
+    group.bench_function("ivf_in_memory", |b| {
+        b.iter(|| {
+            let reader = Cursor::new(stream.as_slice());
+            elevator::analyze(reader, FileFormat::IVF, &AnalysisOptions::default()).unwrap()
+        })
+    });
+    group.finish();
+
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, bench_analysis);
+criterion_main!(benches);
@@ -0,0 +1,201 @@
+use crate::error::ElevatorError;
+use av1parser::obu;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+/// A frame unit located in an Annex B stream
+pub struct FrameUnit {
+    /// Absolute position of the first byte of the frame unit, after its size field
+    pub offset: u64,
+    /// Size of the frame unit in bytes
+    pub size: u32,
+    /// Index of the temporal unit containing the frame unit
+    pub temporal_unit: u64,
+}
+
+/// Walks the frame units of an Annex B (length-delimited) stream.
+#[derive(Clone)]
+pub struct AnnexBReader {
+    /// Position of the next size field to read
+    position: u64,
+    /// Position of the end of the stream
+    end: u64,
+    /// Position of the end of the current temporal unit
+    tu_end: u64,
+    /// Number of temporal units started so far
+    tu_count: u64,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a leb128-coded value, returning the value and its length in bytes.
+pub fn read_leb128<R: Read>(reader: &mut R) -> io::Result<(u32, u32)> {
+    let mut value = 0_u64;
+    let mut byte = [0; 1];
+
+    for i in 0..8 {
+        reader.read_exact(&mut byte)?;
+        value |= u64::from(byte[0] & 0x7F) << (i * 7);
+
+        if byte[0] & 0x80 == 0 {
+            if value > u64::from(std::u32::MAX) {
+                return Err(invalid_data("leb128 value out of range"));
+            }
+
+            return Ok((value as u32, i + 1));
+        }
+    }
+
+    Err(invalid_data("leb128 value is too long"))
+}
+
+/// Encodes a leb128 value, padded with continuation bytes to at least `min_len` bytes.
+pub fn encode_leb128(mut value: u32, min_len: usize) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    loop {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+
+        if value == 0 && bytes.len() >= min_len {
+            break;
+        }
+    }
+
+    if let Some(last) = bytes.last_mut() {
+        *last &= 0x7F;
+    }
+
+    bytes
+}
+
+/// Reads the leading temporal unit, frame unit and OBU sizes, and the first OBU header byte.
+fn read_prefix<R: Read>(reader: &mut R) -> io::Result<(u32, u32, u32, u8)> {
+    let (temporal_unit_size, _) = read_leb128(reader)?;
+    let (frame_unit_size, _) = read_leb128(reader)?;
+    let (obu_length, _) = read_leb128(reader)?;
+    let mut obu_header = [0; 1];
+    reader.read_exact(&mut obu_header)?;
+
+    Ok((
+        temporal_unit_size,
+        frame_unit_size,
+        obu_length,
+        obu_header[0],
+    ))
+}
+
+/// Checks whether the stream starts like an Annex B stream, i.e. with a temporal unit,
+/// frame unit, and OBU length followed by a temporal delimiter OBU.
+pub fn probe<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+    reader.seek(SeekFrom::Start(0))?;
+    let prefix = read_prefix(reader);
+    reader.seek(SeekFrom::Start(0))?;
+
+    Ok(match prefix {
+        Ok((temporal_unit_size, frame_unit_size, obu_length, obu_header)) => {
+            obu_length > 0
+                && obu_length <= frame_unit_size
+                && frame_unit_size < temporal_unit_size
+                && obu_header & 0x80 == 0 // obu_forbidden_bit
+                && (obu_header >> 3) & 0x0F == obu::OBU_TEMPORAL_DELIMITER
+        }
+        Err(_) => false,
+    })
+}
+
+impl AnnexBReader {
+    pub fn new(end: u64) -> Self {
+        AnnexBReader {
+            position: 0,
+            end,
+            tu_end: 0,
+            tu_count: 0,
+        }
+    }
+
+    /// Locates the next frame unit, reading the size fields that precede it.
+    pub fn next_frame_unit<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<FrameUnit>, ElevatorError> {
+        if self.position >= self.end {
+            return Ok(None);
+        }
+
+        reader.seek(SeekFrom::Start(self.position))?;
+
+        if self.position >= self.tu_end {
+            let (temporal_unit_size, len) = read_leb128(reader)?;
+            self.position += u64::from(len);
+            self.tu_end = self.position + u64::from(temporal_unit_size);
+            self.tu_count += 1;
+
+            if self.tu_end > self.end {
+                return Err(ElevatorError::TruncatedFrame {
+                    position: self.position,
+                    size: temporal_unit_size,
+                });
+            }
+        }
+
+        let (frame_unit_size, len) = read_leb128(reader)?;
+        let offset = self.position + u64::from(len);
+        self.position = offset + u64::from(frame_unit_size);
+
+        if self.position > self.tu_end {
+            return Err(ElevatorError::TruncatedFrame {
+                position: offset,
+                size: frame_unit_size,
+            });
+        }
+
+        Ok(Some(FrameUnit {
+            offset,
+            size: frame_unit_size,
+            temporal_unit: self.tu_count - 1,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // One temporal unit holding a temporal delimiter and a 2-byte frame header OBU.
+    const STREAM: [u8; 8] = [7, 2, 1, 0x10, 3, 2, 0x30, 0xAA];
+
+    #[test]
+    fn test_probe_annexb() {
+        assert!(probe(&mut Cursor::new(&STREAM)).unwrap());
+        assert!(!probe(&mut Cursor::new(&[0x12, 0x00])).unwrap());
+    }
+
+    #[test]
+    fn test_leb128_round_trip() {
+        for &(value, min_len) in &[(0, 1), (127, 1), (128, 1), (5, 3), (std::u32::MAX, 1)] {
+            let bytes = encode_leb128(value, min_len);
+            let (decoded, len) = read_leb128(&mut Cursor::new(&bytes)).unwrap();
+
+            assert_eq!((value, bytes.len() as u32), (decoded, len));
+            assert!(bytes.len() >= min_len);
+        }
+    }
+
+    #[test]
+    fn test_frame_units_exclude_length_fields() {
+        let mut reader = Cursor::new(&STREAM);
+        let mut units = AnnexBReader::new(STREAM.len() as u64);
+
+        let unit = units.next_frame_unit(&mut reader).unwrap().unwrap();
+        assert_eq!((2, 2, 0), (unit.offset, unit.size, unit.temporal_unit));
+
+        let unit = units.next_frame_unit(&mut reader).unwrap().unwrap();
+        assert_eq!((5, 3, 0), (unit.offset, unit.size, unit.temporal_unit));
+
+        assert!(units.next_frame_unit(&mut reader).unwrap().is_none());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{ElevatorError, Patch};
+use std::io;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+
+/// First line of a backup file, identifying its format
+const BACKUP_HEADER: &str = "elevator-backup 1";
+
+/// Number of bytes checked on each side of a backed up range before restoring it
+const CONTEXT_LEN: u64 = 32;
+
+/// Bytes of a file replaced by in-place patches, with the context needed to undo them safely
+pub struct Backup {
+    /// Size of the patched file in bytes
+    pub file_size: u64,
+    pub ranges: Vec<BackupRange>,
+}
+
+/// A single range of bytes replaced by a patch
+pub struct BackupRange {
+    /// Absolute position of the first byte of the range
+    pub position: u64,
+    /// Bytes of the range before patching
+    pub original: Vec<u8>,
+    /// Bytes of the range after patching
+    pub patched: Vec<u8>,
+    /// Hash of the bytes surrounding the range in the patched file
+    context_hash: u64,
+}
+
+/// Hashes bytes with 64-bit FNV-1a, which is stable across platforms and releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses hexadecimal digits, which fails if their number is odd.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn malformed() -> ElevatorError {
+    ElevatorError::InvalidBackup(String::from("malformed backup file"))
+}
+
+/// Reads the bytes from `start` to `end`, as they are after applying `patches`.
+fn read_patched<R: Read + Seek>(
+    reader: &mut R,
+    patches: &[Patch],
+    start: u64,
+    end: u64,
+) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; (end - start) as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut bytes)?;
+
+    for patch in patches {
+        for (i, &byte) in patch.bytes.iter().enumerate() {
+            let position = patch.position + i as u64;
+
+            if position >= start && position < end {
+                bytes[(position - start) as usize] = byte;
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Hashes the bytes surrounding a range, given the bytes from `start` to `start + bytes.len()`.
+fn context_hash(bytes: &[u8], start: u64, position: u64, len: usize) -> u64 {
+    let before = (position - start) as usize;
+    let context = [&bytes[..before], &bytes[before + len..]].concat();
+
+    fnv1a(&context)
+}
+
+/// Provides the start and end of the context of a range, which is clamped to the file.
+fn context_bounds(position: u64, len: usize, file_size: u64) -> (u64, u64) {
+    (
+        position.saturating_sub(CONTEXT_LEN),
+        (position + len as u64 + CONTEXT_LEN).min(file_size),
+    )
+}
+
+impl Backup {
+    /// Saves the bytes that in-place patches are about to replace, which are read from `reader`.
+    pub fn new<R: Read + Seek>(mut reader: R, patches: &[Patch]) -> Result<Self, ElevatorError> {
+        let file_size = reader.seek(SeekFrom::End(0))?;
+        let mut ranges = Vec::new();
+
+        for patch in patches {
+            if patch.replaced_len != patch.bytes.len() {
+                return Err(ElevatorError::InvalidBackup(String::from(
+                    "only patches that keep the size of the file can be backed up",
+                )));
+            }
+
+            let len = patch.bytes.len();
+            let (start, end) = context_bounds(patch.position, len, file_size);
+
+            let mut original = vec![0; len];
+            reader.seek(SeekFrom::Start(patch.position))?;
+            reader.read_exact(&mut original)?;
+
+            // The context is hashed as it will be once every patch is applied, since patches can be close together.
+            let bytes = read_patched(&mut reader, patches, start, end)?;
+
+            ranges.push(BackupRange {
+                position: patch.position,
+                original,
+                patched: patch.bytes.clone(),
+                context_hash: context_hash(&bytes, start, patch.position, len),
+            });
+        }
+
+        Ok(Backup { file_size, ranges })
+    }
+
+    /// Writes the backup in a line-based text format.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(writer, "{}", BACKUP_HEADER)?;
+        writeln!(writer, "file_size {}", self.file_size)?;
+
+        for range in &self.ranges {
+            writeln!(
+                writer,
+                "range {} {} {} {:016x}",
+                range.position,
+                to_hex(&range.original),
+                to_hex(&range.patched),
+                range.context_hash
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a backup written by [`Backup::write`].
+    pub fn read<R: BufRead>(reader: R) -> Result<Self, ElevatorError> {
+        let mut lines = reader.lines();
+
+        if lines.next().transpose()?.as_deref() != Some(BACKUP_HEADER) {
+            return Err(malformed());
+        }
+
+        let file_size = match lines.next().transpose()? {
+            Some(line) => match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["file_size", size] => size.parse().map_err(|_| malformed())?,
+                _ => return Err(malformed()),
+            },
+            None => return Err(malformed()),
+        };
+
+        let mut ranges = Vec::new();
+
+        for line in lines {
+            let line = line?;
+            let range = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["range", position, original, patched, hash] => BackupRange {
+                    position: position.parse().map_err(|_| malformed())?,
+                    original: from_hex(original).ok_or_else(malformed)?,
+                    patched: from_hex(patched).ok_or_else(malformed)?,
+                    context_hash: u64::from_str_radix(hash, 16).map_err(|_| malformed())?,
+                },
+                _ => return Err(malformed()),
+            };
+
+            if range.original.len() != range.patched.len() {
+                return Err(malformed());
+            }
+
+            ranges.push(range);
+        }
+
+        Ok(Backup { file_size, ranges })
+    }
+
+    /// Writes the original bytes back into the patched file.
+    ///
+    /// The file is left untouched if it no longer holds the patched bytes and their context,
+    /// which means that it changed since the backup was made.
+    pub fn restore<F: Read + Write + Seek>(&self, mut file: F) -> Result<(), ElevatorError> {
+        let file_size = file.seek(SeekFrom::End(0))?;
+
+        if file_size != self.file_size {
+            return Err(ElevatorError::InvalidBackup(format!(
+                "the file is {} bytes long instead of {}",
+                file_size, self.file_size
+            )));
+        }
+
+        for range in &self.ranges {
+            let len = range.patched.len();
+
+            if range.position > file_size || len as u64 > file_size - range.position {
+                return Err(malformed());
+            }
+
+            let (start, end) = context_bounds(range.position, len, file_size);
+            let bytes = read_patched(&mut file, &[], start, end)?;
+            let offset = (range.position - start) as usize;
+
+            if bytes[offset..offset + len] != range.patched[..]
+                || context_hash(&bytes, start, range.position, len) != range.context_hash
+            {
+                return Err(ElevatorError::InvalidBackup(format!(
+                    "the file changed around byte {} since the backup was made",
+                    range.position
+                )));
+            }
+        }
+
+        for range in &self.ranges {
+            file.seek(SeekFrom::Start(range.position))?;
+            file.write_all(&range.original)?;
+        }
+
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PatchTarget;
+    use std::io::Cursor;
+
+    fn patch(position: u64, bytes: Vec<u8>) -> Patch {
+        Patch {
+            target: PatchTarget::ConfigRecord,
+            position,
+            replaced_len: bytes.len(),
+            bytes,
+        }
+    }
+
+    #[test]
+    fn test_backup_round_trip() {
+        let original = (0..100).collect::<Vec<u8>>();
+        let patches = [patch(10, vec![0xFF, 0xFE]), patch(40, vec![0xFD])];
+
+        let backup = Backup::new(Cursor::new(&original), &patches).unwrap();
+        let mut text = Vec::new();
+        backup.write(&mut text).unwrap();
+        let backup = Backup::read(Cursor::new(text)).unwrap();
+
+        let mut file = Cursor::new(original.clone());
+        crate::apply_patches(&mut file, &patches).unwrap();
+        assert_ne!(file.get_ref(), &original);
+
+        backup.restore(&mut file).unwrap();
+        assert_eq!(file.get_ref(), &original);
+
+        // The restored file no longer holds the patched bytes.
+        assert!(backup.restore(&mut file).is_err());
+    }
+
+    #[test]
+    fn test_backup_refuses_changed_file() {
+        let original = (0..100).collect::<Vec<u8>>();
+        let patches = [patch(50, vec![0xFF])];
+        let backup = Backup::new(Cursor::new(&original), &patches).unwrap();
+
+        let mut file = Cursor::new(original.clone());
+        crate::apply_patches(&mut file, &patches).unwrap();
+        file.get_mut()[70] = 0;
+
+        assert!(backup.restore(&mut file).is_err());
+        assert_eq!(file.get_ref()[50], 0xFF);
+
+        // Bytes beyond the context are not checked.
+        file.get_mut()[70] = 70;
+        file.get_mut()[90] = 0;
+        backup.restore(&mut file).unwrap();
+        assert_eq!(file.get_ref()[50], 50);
+    }
+
+    #[test]
+    fn test_malformed_backup() {
+        assert!(Backup::read(Cursor::new("elevator-backup 1\n")).is_err());
+        assert!(
+            Backup::read(Cursor::new("elevator-backup 1\nfile_size 10\nrange 0 ab\n")).is_err()
+        );
+        assert!(Backup::read(Cursor::new(
+            "elevator-backup 1\nfile_size 10\nrange 0 ab cdef 0000000000000000\n"
+        ))
+        .is_err());
+    }
+}
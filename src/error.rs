@@ -0,0 +1,172 @@
+use crate::level::LEVELS;
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+/// Errors that can occur while analyzing or patching a stream
+#[derive(Debug)]
+pub enum ElevatorError {
+    /// The input file format or codec is not supported
+    UnsupportedFormat(String),
+    /// A time scale is required for input without container timing
+    MissingTimeScale,
+    /// No sequence header precedes the frames of the stream
+    MissingSequenceHeader,
+    /// An OBU extends past the end of the container frame holding it
+    TruncatedObu {
+        /// Absolute position of the OBU header
+        position: u64,
+    },
+    /// A container frame extends past the end of the file, or of the temporal unit holding it in Annex B streams
+    TruncatedFrame {
+        /// Absolute position of the first byte of the frame
+        position: u64,
+        /// Size of the frame in bytes, as declared by the container
+        size: u32,
+    },
+    /// The bitstream could not be parsed
+    MalformedBitstream {
+        /// Absolute position of the OBU payload that could not be parsed
+        position: u64,
+        reason: String,
+    },
+    /// The requested operating point is not signaled in the sequence header
+    InvalidOperatingPoint {
+        index: usize,
+        /// Number of operating points signaled in the sequence header
+        count: usize,
+    },
+    /// The stream uses a feature that is not supported yet
+    UnsupportedFeature(&'static str),
+    /// A sequence header changes a parameter of the previous one, which would mix up two configurations
+    SequenceHeaderChanged {
+        /// Absolute position of the sequence header OBU payload
+        position: u64,
+        /// Name of the parameter that changes
+        parameter: &'static str,
+    },
+    /// The codec configuration record and the sequence header declare different levels
+    ConfigRecordMismatch {
+        /// Level index declared in the codec configuration record
+        record_level: u8,
+        /// Level index declared in the sequence header
+        sequence_level: u8,
+    },
+    /// The level found at the location to patch differs from the one parsed during analysis
+    LevelMismatch {
+        /// Absolute position of the sequence header OBU payload
+        position: u64,
+        /// Level index parsed during analysis
+        expected: u8,
+        /// Level index found at the location to patch
+        found: u8,
+    },
+    /// The patched stream does not signal the requested level
+    VerificationFailed {
+        /// Absolute position of the patched bytes
+        position: u64,
+        reason: String,
+    },
+    /// The parameters of the stream exceed the limits of every level, including the maximum parameters
+    NoConformingLevel {
+        /// Name of the constraint that rules out the highest level, as listed in [`crate::level::CONSTRAINTS`]
+        constraint: &'static str,
+    },
+    /// The requested level cannot be patched into the stream
+    Unpatchable(String),
+    /// A backup of patched bytes is malformed or does not match the file to restore
+    InvalidBackup(String),
+    /// An I/O error, including malformed container data
+    Io(io::Error),
+}
+
+impl Display for ElevatorError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ElevatorError::UnsupportedFormat(msg) => write!(f, "unsupported format: {}", msg),
+            ElevatorError::MissingTimeScale => write!(
+                f,
+                "a time scale must be specified for raw OBU and Annex B input"
+            ),
+            ElevatorError::MissingSequenceHeader => {
+                write!(f, "no sequence header found before the first frame")
+            }
+            ElevatorError::TruncatedObu { position } => write!(
+                f,
+                "the OBU at byte {} extends past the end of its container frame",
+                position
+            ),
+            ElevatorError::TruncatedFrame { position, size } => write!(
+                f,
+                "the container frame at byte {} declares {} bytes, more than the data that remains",
+                position, size
+            ),
+            ElevatorError::MalformedBitstream { position, reason } => {
+                write!(f, "malformed bitstream at byte {}: {}", position, reason)
+            }
+            ElevatorError::InvalidOperatingPoint { index, count } => write!(
+                f,
+                "operating point {} was requested, but the stream only has {}",
+                index, count
+            ),
+            ElevatorError::UnsupportedFeature(feature) => {
+                write!(f, "{} is not yet supported", feature)
+            }
+            ElevatorError::SequenceHeaderChanged {
+                position,
+                parameter,
+            } => write!(
+                f,
+                "the sequence header at byte {} changes the {} of the stream, which is not supported",
+                position, parameter
+            ),
+            ElevatorError::ConfigRecordMismatch {
+                record_level,
+                sequence_level,
+            } => write!(
+                f,
+                "the codec configuration record declares level {} but the sequence header declares level {}",
+                LEVELS[usize::from(*record_level)],
+                LEVELS[usize::from(*sequence_level)]
+            ),
+            ElevatorError::LevelMismatch {
+                position,
+                expected,
+                found,
+            } => write!(
+                f,
+                "expected level index {} in the sequence header at byte {}, but found {}",
+                expected, position, found
+            ),
+            ElevatorError::VerificationFailed { position, reason } => write!(
+                f,
+                "verification of the patched stream failed at byte {}: {}",
+                position, reason
+            ),
+            ElevatorError::NoConformingLevel { constraint } => write!(
+                f,
+                "no level conforms to the stream, which exceeds the {} limit of every level including {}",
+                constraint, LEVELS[31]
+            ),
+            ElevatorError::Unpatchable(msg) => write!(f, "cannot patch the level: {}", msg),
+            ElevatorError::InvalidBackup(msg) => write!(f, "cannot restore the backup: {}", msg),
+            ElevatorError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ElevatorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ElevatorError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ElevatorError {
+    fn from(err: io::Error) -> Self {
+        ElevatorError::Io(err)
+    }
+}
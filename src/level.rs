@@ -1,6 +1,12 @@
+use crate::error::ElevatorError;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter, Result};
+use std::str::FromStr;
 
-#[derive(Debug, PartialEq)]
+/// Tier of an operating point, written and parsed as "main" or "high"
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Tier {
     Main,
     High,
@@ -12,9 +18,31 @@ impl Default for Tier {
     }
 }
 
+impl Display for Tier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Tier::Main => write!(f, "main"),
+            Tier::High => write!(f, "high"),
+        }
+    }
+}
+
+impl FromStr for Tier {
+    type Err = String;
+
+    /// Parses a tier from its name, ignoring case.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "main" => Ok(Tier::Main),
+            "high" => Ok(Tier::High),
+            _ => Err(format!("invalid tier: {}", s)),
+        }
+    }
+}
+
 /// Describes the maximum parameters relevant to level restrictions
 /// encountered in a sequence.
-#[derive(Default)]
+#[derive(Clone, Copy, Default, Serialize)]
 pub struct SequenceContext {
     pub tier: Tier,
     pub pic_size: (u16, u16), // (width, height)
@@ -22,13 +50,13 @@ pub struct SequenceContext {
     pub decode_rate: u64,
     pub header_rate: u16,
     pub mbps: f64,
-    pub tiles: u8,
-    pub tile_cols: u8,
+    pub tiles: u16,
+    pub tile_cols: u16,
 }
 
 impl Display for SequenceContext {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        writeln!(f, "Tier: {:?}", self.tier)?;
+        writeln!(f, "Tier: {}", self.tier)?;
         writeln!(f, "Picture Size: {}x{}", self.pic_size.0, self.pic_size.1)?;
         writeln!(
             f,
@@ -42,20 +70,29 @@ impl Display for SequenceContext {
     }
 }
 
+/// Limits on the sequence parameters at a given level, as defined in Annex A of the AV1 specification
 #[derive(Copy, Clone)]
-struct LevelLimits {
-    max_pic_size: u32,
-    max_h_size: u16,
-    max_v_size: u16,
-    max_display_rate: u64,
-    max_decode_rate: u64,
-    max_header_rate: u16,
-    main_mbps: f64,
-    high_mbps: f64,
-    main_cr: u8,
-    high_cr: u8,
-    max_tiles: u8,
-    max_tile_cols: u8,
+pub struct LevelLimits {
+    /// Maximum number of luma samples in a frame
+    pub max_pic_size: u32,
+    pub max_h_size: u16,
+    pub max_v_size: u16,
+    /// Maximum number of luma samples displayed per second
+    pub max_display_rate: u64,
+    /// Maximum number of luma samples decoded per second
+    pub max_decode_rate: u64,
+    /// Maximum number of frame headers per second
+    pub max_header_rate: u16,
+    /// Maximum bitrate in megabits per second in Main tier
+    pub main_mbps: f64,
+    /// Maximum bitrate in megabits per second in High tier, which is only defined for levels 4.0 and above
+    pub high_mbps: f64,
+    /// Minimum compressed ratio basis in Main tier
+    pub main_cr: u8,
+    /// Minimum compressed ratio basis in High tier
+    pub high_cr: u8,
+    pub max_tiles: u16,
+    pub max_tile_cols: u16,
 }
 
 #[derive(Copy, Clone)]
@@ -65,25 +102,128 @@ impl Level {
     pub fn is_valid(&self) -> bool {
         self.1.is_some()
     }
-}
 
-impl Display for Level {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+    /// Provides the limits of the level, unless it is reserved.
+    pub fn limits(&self) -> Option<LevelLimits> {
+        self.1
+    }
+
+    /// Provides the maximum number of luma samples in a frame, unless the level is reserved.
+    pub fn max_pic_size(&self) -> Option<u32> {
+        self.1.map(|limits| limits.max_pic_size)
+    }
+
+    /// Provides the maximum number of luma samples displayed per second, unless the level is reserved.
+    pub fn max_display_rate(&self) -> Option<u64> {
+        self.1.map(|limits| limits.max_display_rate)
+    }
+
+    /// Provides the maximum number of luma samples decoded per second, unless the level is reserved.
+    pub fn max_decode_rate(&self) -> Option<u64> {
+        self.1.map(|limits| limits.max_decode_rate)
+    }
+
+    /// Provides the maximum number of frame headers per second, unless the level is reserved.
+    pub fn max_header_rate(&self) -> Option<u16> {
+        self.1.map(|limits| limits.max_header_rate)
+    }
+
+    /// Provides the maximum bitrate in megabits per second in the given tier, unless the level is reserved.
+    /// Levels below 4.0 only have a Main tier, whose limit applies to both.
+    pub fn max_mbps(&self, tier: Tier) -> Option<f64> {
+        self.1.map(|limits| {
+            if tier == Tier::Main || self.0 <= 7 {
+                limits.main_mbps
+            } else {
+                limits.high_mbps
+            }
+        })
+    }
+
+    /// Provides the maximum number of tiles in a frame, unless the level is reserved.
+    pub fn max_tiles(&self) -> Option<u16> {
+        self.1.map(|limits| limits.max_tiles)
+    }
+
+    /// Provides the maximum number of tile columns in a frame, unless the level is reserved.
+    pub fn max_tile_cols(&self) -> Option<u16> {
+        self.1.map(|limits| limits.max_tile_cols)
+    }
+
+    /// Checks the parameters against each constraint of the level, unless it is reserved.
+    pub fn check(&self, context: &SequenceContext) -> Option<Vec<ConstraintCheck>> {
+        self.1.map(|limits| limits.check(self.0, context))
+    }
+
+    /// Provides the name of the level, e.g. "4.0", without its index.
+    pub fn name(&self) -> String {
         let index = self.0;
 
         if index == 31 {
-            write!(f, "Maximum parameters")
+            String::from("Maximum parameters")
         } else if index >= 24 {
-            write!(f, "Reserved")
+            String::from("Reserved")
         } else {
             let x = 2 + (index >> 2);
             let y = index & 3;
 
-            write!(f, "{}.{} ({})", x, y, self.0)
+            format!("{}.{}", x, y)
         }
     }
 }
 
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.0 >= 24 {
+            write!(f, "{}", self.name())
+        } else {
+            write!(f, "{} ({})", self.name(), self.0)
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    /// Parses a level from either its index (e.g. "8") or its dotted name (e.g. "4.0").
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let index = match s.find('.') {
+            Some(dot) => {
+                let x = s[..dot].parse::<u8>().ok();
+                let y = s[dot + 1..].parse::<u8>().ok();
+
+                match (x, y) {
+                    (Some(x), Some(y)) if (2..=9).contains(&x) && y <= 3 => (x - 2) << 2 | y,
+                    _ => return Err(format!("invalid level: {}", s)),
+                }
+            }
+            None => match s.parse::<u8>() {
+                Ok(index) if usize::from(index) < LEVELS.len() => index,
+                _ => return Err(format!("invalid level index: {}", s)),
+            },
+        };
+
+        let level = LEVELS[usize::from(index)];
+
+        if level.is_valid() {
+            Ok(level)
+        } else {
+            Err(format!("level {} ({}) is not defined", level.name(), index))
+        }
+    }
+}
+
+// The level limits are an implementation detail, so only the index and name are serialized.
+impl Serialize for Level {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Level", 2)?;
+        state.serialize_field("index", &self.0)?;
+        state.serialize_field("name", &self.name())?;
+        state.end()
+    }
+}
+
 macro_rules! level {
     ($level: expr, $limits: expr) => {
         Level($level, Some($limits))
@@ -362,13 +502,40 @@ pub const LEVELS: [Level; 32] = [
             high_mbps: std::f64::MAX,
             main_cr: std::u8::MAX,
             high_cr: std::u8::MAX,
-            max_tiles: std::u8::MAX,
-            max_tile_cols: std::u8::MAX,
+            max_tiles: std::u16::MAX,
+            max_tile_cols: std::u16::MAX,
         }
     ),
 ];
 
-pub fn calculate_min_pic_compress_ratio(tier: Tier, display_rate: f64) -> [f64; 32] {
+/// Provides the uncompressed size of a picture in bytes, which its compressed size is compared with for the
+/// compressed ratio constraint.
+///
+/// The specification derives it from the profile (PicSizeProfileFactor) rather than from the bit depth and chroma
+/// subsampling of the stream, using the largest format each profile allows: 10-bit 4:2:0 for Main, 10-bit 4:4:4 for
+/// High, and 12-bit 4:4:4 for Professional.
+pub fn uncompressed_size(seq_profile: u8, picture_size: usize) -> usize {
+    let pic_size_profile_factor = match seq_profile {
+        0 => 15,
+        1 => 30,
+        _ => 36,
+    };
+
+    (picture_size * pic_size_profile_factor) >> 3
+}
+
+/// Provides the minimum compressed ratio (MinPicCompressRatio) that the frames must have at each level, indexed by
+/// level index, given the tier, the number of luma samples displayed per second, and the still_picture flag of the
+/// sequence header. Reserved levels have a zero ratio.
+///
+/// The ratio of a level scales with the fraction of its display rate the stream uses, and is never lower than 0.8,
+/// so frames may then be larger than their uncompressed size. Still pictures, which have no display rate to speak of,
+/// only have to meet that 0.8 floor at every level.
+pub fn calculate_min_pic_compress_ratio(
+    tier: Tier,
+    display_rate: f64,
+    still_picture: bool,
+) -> [f64; 32] {
     let mut min_pic_compress_ratio = [0_f64; 32];
 
     for i in 0..32 {
@@ -380,51 +547,198 @@ pub fn calculate_min_pic_compress_ratio(tier: Tier, display_rate: f64) -> [f64;
                 limits.high_cr
             };
 
-            // assuming still_picture is equal to 0
-            min_pic_compress_ratio[i] = 0.8_f64.max(f64::from(min_comp_basis) * speed_adjustment);
+            min_pic_compress_ratio[i] = if still_picture {
+                0.8
+            } else {
+                0.8_f64.max(f64::from(min_comp_basis) * speed_adjustment)
+            };
         }
     }
 
     min_pic_compress_ratio
 }
 
-pub fn calculate_level(context: &SequenceContext) -> Level {
+/// Provides the index of the lowest level whose minimum compressed ratio is satisfied by the frames of a temporal unit,
+/// given the tier, the number of luma samples displayed per second, the minimum compressed ratio of its frames,
+/// which is their uncompressed size, from [`uncompressed_size`], over their compressed size, and the still_picture
+/// flag of the sequence header.
+///
+/// This is the level floor set by the compressed ratio constraint, which [`calculate_level`] does not account for,
+/// as it only depends on the sequence context. Reserved levels are skipped, and frames compressed less than every
+/// level requires need the maximum parameters (index 31).
+pub fn min_cr_level_index(
+    tier: Tier,
+    display_rate: f64,
+    min_compressed_ratio: f64,
+    still_picture: bool,
+) -> usize {
+    calculate_min_pic_compress_ratio(tier, display_rate, still_picture)
+        .iter()
+        .enumerate()
+        .position(|(i, &ratio)| LEVELS[i].is_valid() && min_compressed_ratio >= ratio)
+        .unwrap_or(31)
+}
+
+/// Names of the constraints checked against the level limits, in the order they are checked
+pub const CONSTRAINTS: [&str; 9] = [
+    "max_pic_size",
+    "max_h_size",
+    "max_v_size",
+    "max_display_rate",
+    "max_decode_rate",
+    "max_header_rate",
+    "max_mbps",
+    "max_tiles",
+    "max_tile_cols",
+];
+
+/// Result of checking a single constraint of a level against the sequence parameters
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct ConstraintCheck {
+    /// Name of the constraint, as listed in [`CONSTRAINTS`]
+    pub name: &'static str,
+    /// Value of the parameter in the sequence
+    pub value: f64,
+    /// Maximum value of the parameter at the level
+    pub limit: f64,
+    pub passed: bool,
+}
+
+impl LevelLimits {
+    /// Checks the parameters against each constraint of the level, in the order of [`CONSTRAINTS`].
+    fn check(&self, index: u8, context: &SequenceContext) -> Vec<ConstraintCheck> {
+        // Only Main tier exists for low levels.
+        let max_mbps = if context.tier == Tier::Main || index <= 7 {
+            self.main_mbps
+        } else {
+            self.high_mbps
+        };
+
+        let pic_size = u32::from(context.pic_size.0) * u32::from(context.pic_size.1);
+        let values = [
+            (f64::from(pic_size), f64::from(self.max_pic_size)),
+            (f64::from(context.pic_size.0), f64::from(self.max_h_size)),
+            (f64::from(context.pic_size.1), f64::from(self.max_v_size)),
+            (context.display_rate as f64, self.max_display_rate as f64),
+            (context.decode_rate as f64, self.max_decode_rate as f64),
+            (
+                f64::from(context.header_rate),
+                f64::from(self.max_header_rate),
+            ),
+            (context.mbps, max_mbps),
+            (f64::from(context.tiles), f64::from(self.max_tiles)),
+            (f64::from(context.tile_cols), f64::from(self.max_tile_cols)),
+        ];
+
+        CONSTRAINTS
+            .iter()
+            .zip(values.iter())
+            .map(|(&name, &(value, limit))| ConstraintCheck {
+                name,
+                value,
+                limit,
+                passed: value <= limit,
+            })
+            .collect()
+    }
+}
+
+/// Index of the highest level whose limits are defined by the specification, below the maximum parameters level
+pub const MAX_DEFINED_LEVEL: u8 = 19;
+
+pub fn calculate_level(context: &SequenceContext) -> std::result::Result<Level, ElevatorError> {
+    calculate_level_with_constraint(context).map(|(level, _)| level)
+}
+
+/// Calculates the minimum level satisfying the parameters, along with the name of the constraint
+/// that rules out the previous valid level, if any.
+///
+/// Parameters exceeding every defined level only satisfy level index 31, and those that do not even satisfy its
+/// limits, like an unbounded bitrate, are an error.
+pub fn calculate_level_with_constraint(
+    context: &SequenceContext,
+) -> std::result::Result<(Level, Option<&'static str>), ElevatorError> {
+    let mut binding_constraint = None;
+
     for level in LEVELS.iter() {
         if let Some(limits) = level.1 {
-            // Only Main tier exists for low levels.
-            let mbps_valid = if context.tier == Tier::Main || level.0 <= 7 {
-                limits.main_mbps >= context.mbps
-            } else {
-                limits.high_mbps >= context.mbps
-            };
-
-            if limits.max_pic_size >= u32::from(context.pic_size.0) * u32::from(context.pic_size.1)
-                && limits.max_h_size >= context.pic_size.0
-                && limits.max_v_size >= context.pic_size.1
-                && limits.max_display_rate >= context.display_rate
-                && limits.max_decode_rate >= context.decode_rate
-                && limits.max_header_rate >= context.header_rate
-                && mbps_valid
-                && limits.max_tiles >= context.tiles
-                && limits.max_tile_cols >= context.tile_cols
+            match limits
+                .check(level.0, context)
+                .iter()
+                .find(|check| !check.passed)
             {
-                return *level;
+                Some(check) => binding_constraint = Some(check.name),
+                None => return Ok((*level, binding_constraint)),
             }
         }
     }
 
-    unreachable!("no suitable level found");
+    Err(ElevatorError::NoConformingLevel {
+        constraint: binding_constraint.unwrap_or("unknown"),
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_level_limit_accessors() {
+        let level_5_0: Level = "5.0".parse().unwrap();
+
+        assert_eq!(Some(8_912_896), level_5_0.max_pic_size());
+        assert_eq!(Some(267_386_880), level_5_0.max_display_rate());
+        assert_eq!(Some(300), level_5_0.max_header_rate());
+        assert_eq!(Some(30.0), level_5_0.max_mbps(Tier::Main));
+        assert_eq!(Some(100.0), level_5_0.max_mbps(Tier::High));
+
+        // Levels below 4.0 have no High tier limits.
+        assert_eq!(Some(6.0), LEVELS[4].max_mbps(Tier::High));
+        assert!(LEVELS[2].max_decode_rate().is_none());
+    }
+
+    #[test]
+    fn test_tier_round_trip() {
+        for &tier in &[Tier::Main, Tier::High] {
+            assert_eq!(Ok(tier), tier.to_string().parse());
+        }
+
+        assert_eq!(Ok(Tier::High), "High".parse());
+        assert_eq!(Ok(Tier::Main), " MAIN ".parse());
+        assert!("medium".parse::<Tier>().is_err());
+    }
+
+    #[test]
+    fn test_min_pic_compress_ratio_floor() {
+        // A stream displaying few samples per second only has to compress its frames down to 0.8 of their size.
+        let ratios = calculate_min_pic_compress_ratio(Tier::Main, 1000.0, false);
+        assert_eq!(0.8, ratios[0]);
+        assert_eq!(0.0, ratios[2]);
+
+        assert_eq!(0, min_cr_level_index(Tier::Main, 1000.0, 0.8, false));
+        assert_eq!(31, min_cr_level_index(Tier::Main, 1000.0, 0.79, false));
+    }
+
+    #[test]
+    fn test_min_pic_compress_ratio_still_picture() {
+        // A 4K frame compressed to half its size, displayed as if at 60 fps, requires level 6.1.
+        let display_rate = 3840.0 * 2160.0 * 60.0;
+        assert_eq!(17, min_cr_level_index(Tier::Main, display_rate, 2.0, false));
+
+        // As a still picture, it only has to meet the 0.8 floor.
+        let ratios = calculate_min_pic_compress_ratio(Tier::High, display_rate, true);
+        assert!(LEVELS
+            .iter()
+            .zip(&ratios)
+            .all(|(level, &ratio)| !level.is_valid() || ratio == 0.8));
+        assert_eq!(0, min_cr_level_index(Tier::Main, display_rate, 2.0, true));
+    }
+
     #[test]
     fn test_calculate_level_minimum_parameters() {
         let seq_ctx_min = SequenceContext::default();
 
-        assert_eq!(0, calculate_level(&seq_ctx_min).0);
+        assert_eq!(0, calculate_level(&seq_ctx_min).unwrap().0);
     }
 
     #[test]
@@ -436,10 +750,140 @@ mod tests {
             decode_rate: std::u64::MAX,
             header_rate: std::u16::MAX,
             mbps: std::f64::MAX,
-            tiles: std::u8::MAX,
-            tile_cols: std::u8::MAX,
+            tiles: std::u16::MAX,
+            tile_cols: std::u16::MAX,
+        };
+
+        assert_eq!(31, calculate_level(&seq_ctx_max).unwrap().0);
+    }
+
+    #[test]
+    fn test_calculate_level_no_conforming_level() {
+        // 8K at 240 fps exceeds level 6.3, but not the maximum parameters.
+        let seq_ctx = SequenceContext {
+            pic_size: (7680, 4320),
+            display_rate: 7680 * 4320 * 240,
+            decode_rate: 7680 * 4320 * 240,
+            header_rate: 240,
+            ..SequenceContext::default()
         };
+        assert_eq!(31, calculate_level(&seq_ctx).unwrap().0);
+
+        // Only the decode rate exceeds level 6.3, which binds the maximum parameters.
+        let seq_ctx = SequenceContext {
+            pic_size: (7680, 4320),
+            display_rate: 7680 * 4320 * 120,
+            decode_rate: 4_706_009_088 + 1,
+            header_rate: 120,
+            ..SequenceContext::default()
+        };
+        let (level, constraint) = calculate_level_with_constraint(&seq_ctx).unwrap();
+        assert_eq!((31, Some("max_decode_rate")), (level.0, constraint));
+
+        let seq_ctx = SequenceContext {
+            mbps: std::f64::INFINITY,
+            ..SequenceContext::default()
+        };
+        assert!(matches!(
+            calculate_level(&seq_ctx),
+            Err(ElevatorError::NoConformingLevel {
+                constraint: "max_mbps"
+            })
+        ));
+    }
+
+    #[test]
+    fn test_calculate_level_binding_constraint() {
+        let (level, constraint) =
+            calculate_level_with_constraint(&SequenceContext::default()).unwrap();
+        assert_eq!((0, None), (level.0, constraint));
+
+        // 1080p60 fits level 4.0 by picture size, but its display rate requires level 4.1.
+        let seq_ctx = SequenceContext {
+            pic_size: (1920, 1080),
+            display_rate: 1920 * 1080 * 60,
+            decode_rate: 1920 * 1080 * 60,
+            header_rate: 60,
+            ..SequenceContext::default()
+        };
+
+        let (level, constraint) = calculate_level_with_constraint(&seq_ctx).unwrap();
+        assert_eq!((9, Some("max_display_rate")), (level.0, constraint));
+
+        let seq_ctx = SequenceContext {
+            pic_size: (1920, 1080),
+            tiles: 64,
+            ..SequenceContext::default()
+        };
+
+        let (level, constraint) = calculate_level_with_constraint(&seq_ctx).unwrap();
+        assert_eq!((12, Some("max_tiles")), (level.0, constraint));
+    }
+
+    #[test]
+    fn test_check_level() {
+        let seq_ctx = SequenceContext {
+            pic_size: (1920, 1080),
+            display_rate: 1920 * 1080 * 60,
+            decode_rate: 1920 * 1080 * 60,
+            header_rate: 60,
+            mbps: 15.0,
+            ..SequenceContext::default()
+        };
+
+        let failed = |level: Level| {
+            level
+                .check(&seq_ctx)
+                .unwrap()
+                .iter()
+                .filter(|check| !check.passed)
+                .map(|check| check.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["max_display_rate", "max_decode_rate", "max_mbps"],
+            failed(LEVELS[8])
+        );
+        assert!(failed(LEVELS[9]).is_empty());
+        assert!(LEVELS[2].check(&seq_ctx).is_none());
+    }
+
+    #[test]
+    fn test_reserved_levels_have_no_limits() {
+        for level in LEVELS.iter() {
+            let reserved = matches!(level.0, 2 | 3 | 6 | 7 | 10 | 11 | 20..=30);
+
+            assert_eq!(reserved, level.limits().is_none(), "level {}", level.0);
+        }
+    }
+
+    #[test]
+    fn test_uncompressed_size() {
+        // 1920x1080 is 3110400 bytes as 8-bit 4:2:0, but the sizes only depend on the profile.
+        assert_eq!(uncompressed_size(0, 1920 * 1080), 3_888_000);
+        assert_eq!(uncompressed_size(1, 1920 * 1080), 7_776_000);
+        assert_eq!(uncompressed_size(2, 1920 * 1080), 9_331_200);
+    }
+
+    #[test]
+    fn test_level_display() {
+        assert_eq!("4.0 (8)", LEVELS[8].to_string());
+        assert_eq!("Reserved", LEVELS[24].to_string());
+        assert_eq!("Maximum parameters", LEVELS[31].to_string());
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(8, "8".parse::<Level>().unwrap().0);
+        assert_eq!(8, "4.0".parse::<Level>().unwrap().0);
+        assert_eq!(13, "5.1".parse::<Level>().unwrap().0);
+        assert_eq!(31, "31".parse::<Level>().unwrap().0);
 
-        assert_eq!(31, calculate_level(&seq_ctx_max).0);
+        assert!("2.2".parse::<Level>().is_err()); // reserved
+        assert!("24".parse::<Level>().is_err());
+        assert!("4.4".parse::<Level>().is_err());
+        assert!("32".parse::<Level>().is_err());
+        assert!("four".parse::<Level>().is_err());
     }
 }
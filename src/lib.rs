@@ -0,0 +1,3833 @@
+//! Level calculation and patching for AV1 streams.
+//!
+//! [`analyze`] walks the stream in a supported container and determines the
+//! minimum level it conforms to, and [`patch_level`] rewrites the level signaled in
+//! the sequence headers (and codec configuration record, if any).
+
+extern crate av1parser;
+extern crate serde;
+
+mod annexb;
+mod backup;
+mod error;
+mod ivf;
+pub mod level;
+mod mkv;
+mod mp4;
+mod obu;
+mod rate;
+
+pub use av1parser::obu::Obu;
+pub use av1parser::{probe_fileformat, FileFormat};
+pub use backup::{Backup, BackupRange};
+pub use error::ElevatorError;
+pub use level::{
+    calculate_level, calculate_level_with_constraint, calculate_min_pic_compress_ratio,
+    min_cr_level_index, Level, SequenceContext, Tier, CONSTRAINTS, LEVELS,
+};
+pub use obu::OperatingParameters;
+
+use av1parser as av1p;
+use level::*;
+use rate::{BitrateWindow, RateWindow, TemporalUnitCounts, TimestampClock};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::vec::Vec;
+
+/// Container-level stream metadata
+#[derive(Serialize)]
+pub struct ContainerMetadata {
+    /// Temporal resolution, such that `time_scale` units represent one second of real time
+    /// Represented as a rational (numerator, denominator)
+    pub time_scale: (u32, u32),
+    /// Frame width and height in pixels, as declared by the container unless
+    /// [`AnalysisOptions::use_sequence_resolution`] is set.
+    /// The rates are measured with the size of each frame instead.
+    pub resolution: (u16, u16),
+    /// Number of frames declared in the file header, for IVF input
+    pub frame_count: Option<u32>,
+}
+
+/// Parameters of the analysis of a stream
+#[derive(Clone, Default)]
+pub struct AnalysisOptions {
+    /// Time scale for raw OBU and Annex B input, which carry no timing information,
+    /// as a rational (numerator, denominator)
+    pub time_scale: Option<(u32, u32)>,
+    /// Whether the resolution is the maximum frame size of the first sequence header,
+    /// rather than the resolution declared by the container
+    pub use_sequence_resolution: bool,
+    /// Number of tile lists decoded per second in large scale tile streams, [`DEFAULT_TILE_LIST_RATE`] if not given
+    pub tile_list_rate: Option<f64>,
+    /// Temporal layer whose OBUs are counted, or every layer if not given
+    pub temporal_id: Option<u8>,
+    /// Spatial layer whose OBUs are counted, or every layer if not given
+    pub spatial_id: Option<u8>,
+    /// Whether the analysis of a truncated stream stops at the first incomplete container frame, instead of failing.
+    /// In raw OBU streams, an incomplete OBU at the end of the file also stops the analysis.
+    pub allow_truncated: bool,
+    /// Whether the container bytes between the frames, like IVF frame headers, count towards the bitrate,
+    /// for comparison with the bitrate of the muxed file
+    pub count_container_overhead: bool,
+    /// Lengths in seconds of the windows over which the peak bitrate is measured, [`DEFAULT_BITRATE_WINDOW`] if empty.
+    /// The level is calculated from the bitrate over the first one.
+    pub bitrate_windows: Vec<f64>,
+    /// Tier that the levels are calculated for, instead of the tier signaled for each operating point
+    pub tier: Option<Tier>,
+    /// Whether the rates of a clip shorter than the measurement window are its totals, instead of being extrapolated
+    /// as if the clip repeated to fill the window
+    pub no_extrapolate: bool,
+    /// Whether the stream is decoded in large scale tile mode, in which its tile lists are decoded from its first coded frames.
+    /// This mode is not signaled in the bitstream, and tile lists are malformed without it.
+    pub large_scale_tile: bool,
+}
+
+/// Maximum number of anchor frames that the tile lists of a large scale tile stream can refer to
+const MAX_ANCHOR_FRAMES: usize = 128;
+
+/// Number of tile lists decoded per second assumed for large scale tile streams, which have no frame rate of their own
+pub const DEFAULT_TILE_LIST_RATE: f64 = 180.0;
+
+/// Length in seconds of the window over which the bitrate is measured by default
+pub const DEFAULT_BITRATE_WINDOW: f64 = 1.0;
+
+/// Highest number of time scale units per second for which a unit is assumed to be the duration of a frame,
+/// as in raw OBU and most IVF input
+const MAX_FRAME_TIME_SCALE: f64 = 240.0;
+
+/// Provides the duration assumed for a temporal unit that is not followed by another one to measure it against:
+/// one unit of a time scale counting frames, and one second otherwise.
+/// A still picture is displayed for one second, since the time scale of its container says nothing about it.
+fn default_tu_duration(time_scale: f64, still_picture: bool) -> u64 {
+    if time_scale <= MAX_FRAME_TIME_SCALE && !still_picture {
+        1
+    } else {
+        time_scale.round() as u64
+    }
+}
+
+impl ContainerMetadata {
+    /// Provides the time base in floating point form
+    pub fn time_scale(&self) -> f64 {
+        f64::from(self.time_scale.0) / f64::from(self.time_scale.1)
+    }
+}
+
+impl Display for ContainerMetadata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Time scale: {:.3} ({}/{})",
+            self.time_scale(),
+            self.time_scale.0,
+            self.time_scale.1
+        )?;
+        writeln!(f, "Resolution: {}x{}", self.resolution.0, self.resolution.1)?;
+
+        if let Some(frame_count) = self.frame_count {
+            writeln!(f, "Frame count: {}", frame_count)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Container-level frame metadata
+struct ContainerFrameMetadata {
+    /// Size of the frame in bytes
+    size: u32,
+    /// Display timestamp of the frame at the time scale of the stream
+    display_timestamp: u64,
+    /// Absolute position of the first byte of the frame in the file
+    position: u64,
+}
+
+/// Source of the frames of a stream in a given container format.
+/// The container metadata is parsed beforehand by `open_stream`, so that a demuxer can be cloned for each pass over the frames.
+trait Demuxer<R: Read + Seek> {
+    /// Reads the next container frame, leaving the reader at its first byte.
+    /// Containers that are read sequentially expect the reader to be at the end of the previous frame.
+    fn next_frame(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError>;
+}
+
+/// Size of the header preceding each frame of an IVF file, whose first field is the frame size
+const IVF_FRAME_HEADER_SIZE: u64 = 12;
+
+/// Frames of an IVF file, which are read sequentially
+#[derive(Clone)]
+struct IvfDemuxer {
+    /// Position of the next frame header
+    next_position: u64,
+}
+
+impl<R: Read + Seek> Demuxer<R> for IvfDemuxer {
+    fn next_frame(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError> {
+        let frame = match av1p::ivf::parse_ivf_frame(&mut *reader) {
+            Ok(frame) => frame,
+            // A partial frame header at the end of the file ends the stream.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        // The position is tracked rather than queried, which would discard the buffer of a buffered reader.
+        let position = self.next_position + IVF_FRAME_HEADER_SIZE;
+        self.next_position = position + u64::from(frame.size);
+
+        Ok(Some(ContainerFrameMetadata {
+            size: frame.size,
+            display_timestamp: frame.pts,
+            position,
+        }))
+    }
+}
+
+impl<R: Read + Seek> Demuxer<R> for std::vec::IntoIter<mp4::Mp4Sample> {
+    fn next_frame(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError> {
+        let sample = match self.next() {
+            Some(sample) => sample,
+            None => return Ok(None),
+        };
+        reader.seek(SeekFrom::Start(sample.offset))?;
+
+        // Composition offsets are not read, so the samples are timed in decode order.
+        Ok(Some(ContainerFrameMetadata {
+            size: sample.size,
+            display_timestamp: sample.dts,
+            position: sample.offset,
+        }))
+    }
+}
+
+impl<R: Read + Seek> Demuxer<R> for std::vec::IntoIter<mkv::MkvBlock> {
+    fn next_frame(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError> {
+        let block = match self.next() {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        reader.seek(SeekFrom::Start(block.offset))?;
+
+        Ok(Some(ContainerFrameMetadata {
+            size: block.size,
+            display_timestamp: block.pts,
+            position: block.offset,
+        }))
+    }
+}
+
+impl<R: Read + Seek> Demuxer<R> for annexb::AnnexBReader {
+    fn next_frame(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError> {
+        Ok(self
+            .next_frame_unit(reader)?
+            .map(|unit| ContainerFrameMetadata {
+                size: unit.size,
+                display_timestamp: unit.temporal_unit,
+                position: unit.offset,
+            }))
+    }
+}
+
+/// Raw OBU stream, split into temporal units
+#[derive(Clone)]
+struct ObuDemuxer {
+    /// Position of the next temporal unit
+    next_position: u64,
+    /// Position of the end of the stream
+    end: u64,
+    /// Number of temporal units read so far
+    count: u64,
+}
+
+impl ObuDemuxer {
+    fn new(end: u64) -> Self {
+        ObuDemuxer {
+            next_position: 0,
+            end,
+            count: 0,
+        }
+    }
+}
+
+impl<R: Read + Seek> Demuxer<R> for ObuDemuxer {
+    fn next_frame(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError> {
+        // Synthesize a container frame from every OBU up to the next temporal delimiter.
+        let start = self.next_position;
+        let mut pos = start;
+
+        while pos < self.end {
+            reader.seek(SeekFrom::Start(pos))?;
+            let sz = (self.end - pos).min(u64::from(std::u32::MAX)) as u32;
+            let obu = read_obu_header(&mut *reader, sz)?;
+
+            if obu.obu_type == av1p::obu::OBU_TEMPORAL_DELIMITER && pos > start {
+                break;
+            }
+
+            // A truncated last OBU is left for the caller to report.
+            pos = (pos + u64::from(obu.header_len) + u64::from(obu.obu_size)).min(self.end);
+        }
+
+        if pos == start {
+            return Ok(None);
+        }
+
+        reader.seek(SeekFrom::Start(start))?;
+        self.next_position = pos;
+        self.count += 1;
+
+        Ok(Some(ContainerFrameMetadata {
+            size: (pos - start) as u32,
+            display_timestamp: self.count - 1,
+            position: start,
+        }))
+    }
+}
+
+/// Container-specific state used to locate frames
+#[derive(Clone)]
+enum Container {
+    Ivf(IvfDemuxer),
+    /// Remaining samples of the AV1 track
+    Mp4(std::vec::IntoIter<mp4::Mp4Sample>),
+    /// Remaining blocks of the AV1 track
+    Mkv(std::vec::IntoIter<mkv::MkvBlock>),
+    /// Frame units of an Annex B stream
+    AnnexB(annexb::AnnexBReader),
+    Obu(ObuDemuxer),
+}
+
+impl Display for ContainerFrameMetadata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Frame @ {}: {} bytes", self.display_timestamp, self.size)
+    }
+}
+
+impl<R: Read + Seek> Demuxer<R> for Container {
+    fn next_frame(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError> {
+        match self {
+            Container::Ivf(frames) => frames.next_frame(reader),
+            Container::Mp4(samples) => samples.next_frame(reader),
+            Container::Mkv(blocks) => blocks.next_frame(reader),
+            Container::AnnexB(units) => units.next_frame(reader),
+            Container::Obu(temporal_units) => temporal_units.next_frame(reader),
+        }
+    }
+}
+
+impl Container {
+    /// Reads the next container frame, checking that it lies within a file of `file_size` bytes.
+    fn next_frame_within<R: Read + Seek>(
+        &mut self,
+        reader: &mut R,
+        file_size: u64,
+    ) -> Result<Option<ContainerFrameMetadata>, ElevatorError> {
+        let frame = self.next_frame(reader)?;
+
+        if let Some(frame) = &frame {
+            if frame.position > file_size || u64::from(frame.size) > file_size - frame.position {
+                return Err(ElevatorError::TruncatedFrame {
+                    position: frame.position,
+                    size: frame.size,
+                });
+            }
+        }
+
+        Ok(frame)
+    }
+}
+
+/// Provides the error for a truncated OBU, whose first `consumed` bytes were just read.
+fn truncated_obu<R: Seek>(reader: &mut R, consumed: u32) -> ElevatorError {
+    match reader.seek(SeekFrom::Current(0)) {
+        Ok(position) => ElevatorError::TruncatedObu {
+            position: position.saturating_sub(u64::from(consumed)),
+        },
+        Err(err) => err.into(),
+    }
+}
+
+/// Reads an OBU header, given the `sz` bytes left for the whole OBU.
+///
+/// The size of OBUs without a size field is deduced from `sz`, which must then hold at least the OBU header.
+fn read_obu_header<R: Read + Seek>(
+    reader: &mut R,
+    sz: u32,
+) -> Result<av1p::obu::Obu, ElevatorError> {
+    let mut first_byte = [0; 1];
+    reader.read_exact(&mut first_byte)?;
+
+    let obu_extension_flag = (first_byte[0] >> 2) & 1;
+    if sz < 1 + u32::from(obu_extension_flag) {
+        return Err(truncated_obu(reader, 1));
+    }
+
+    Ok(av1p::obu::parse_obu_header(
+        &mut (&first_byte[..]).chain(&mut *reader),
+        sz,
+    )?)
+}
+
+/// Reads the next OBU header from a container frame with `sz` bytes remaining, and deducts the OBU from `sz`.
+/// In Annex B streams, each OBU is preceded by its length.
+fn next_obu_header<R: Read + Seek>(
+    reader: &mut R,
+    sz: &mut u32,
+    is_annexb: bool,
+) -> Result<av1p::obu::Obu, ElevatorError> {
+    let obu_length = if is_annexb {
+        let (obu_length, len) = annexb::read_leb128(reader)?;
+        *sz = match sz.checked_sub(len) {
+            Some(sz) => sz,
+            None => return Err(truncated_obu(reader, len)),
+        };
+        obu_length
+    } else {
+        *sz
+    };
+
+    let obu = read_obu_header(reader, obu_length)?;
+    *sz = match obu
+        .header_len
+        .checked_add(obu.obu_size)
+        .and_then(|obu_len| sz.checked_sub(obu_len))
+    {
+        Some(sz) => sz,
+        None => return Err(truncated_obu(reader, obu.header_len)),
+    };
+
+    Ok(obu)
+}
+
+/// Iterator over the OBUs of a stream, yielding each OBU header along with its payload.
+///
+/// The configOBUs of the codec configuration record, if any, come before the OBUs of the first frame.
+pub struct ObuReader<R> {
+    reader: R,
+    container: Container,
+    is_annexb: bool,
+    /// Position and size of the configOBUs, until they are read
+    config_obus: Option<(u64, u32)>,
+    file_size: u64,
+    /// Position of the next container frame, for containers that are read sequentially
+    frame_end: u64,
+    /// Position of the next OBU in the current container frame or in the configOBUs
+    next_position: u64,
+    /// Number of bytes left in the current container frame or in the configOBUs
+    remaining: u32,
+    reading_config: bool,
+    timestamp: Option<u64>,
+    position: u64,
+    failed: bool,
+}
+
+impl<R: Read + Seek> ObuReader<R> {
+    /// Opens a stream in the given file format.
+    ///
+    /// A time scale must be provided in `options` for raw OBU and Annex B input, which carry no timing information.
+    pub fn new(
+        mut reader: R,
+        fmt: FileFormat,
+        options: &AnalysisOptions,
+    ) -> Result<Self, ElevatorError> {
+        let (_, layout) = open_stream(&mut reader, fmt, options)?;
+
+        Ok(ObuReader::with_container(
+            reader,
+            layout.container,
+            layout.config_obus,
+            layout.frames_start,
+            layout.file_size,
+        ))
+    }
+
+    fn with_container(
+        reader: R,
+        container: Container,
+        config_obus: Option<(u64, u32)>,
+        frames_start: u64,
+        file_size: u64,
+    ) -> Self {
+        ObuReader {
+            reader,
+            is_annexb: matches!(container, Container::AnnexB(_)),
+            container,
+            config_obus,
+            file_size,
+            frame_end: frames_start,
+            next_position: 0,
+            remaining: 0,
+            reading_config: false,
+            timestamp: None,
+            position: 0,
+            failed: false,
+        }
+    }
+
+    /// Provides the absolute position of the payload of the last OBU read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Provides the display timestamp of the container frame holding the last OBU read,
+    /// or `None` if it is one of the configOBUs.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    fn next_obu(&mut self) -> Result<Option<(av1p::obu::Obu, Vec<u8>)>, ElevatorError> {
+        while self.remaining == 0 {
+            if let Some((config_pos, config_size)) = self.config_obus.take() {
+                self.next_position = config_pos;
+                self.remaining = config_size;
+                self.reading_config = true;
+                self.timestamp = None;
+                continue;
+            }
+
+            self.reader.seek(SeekFrom::Start(self.frame_end))?;
+            let frame = match self
+                .container
+                .next_frame_within(&mut self.reader, self.file_size)?
+            {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            self.frame_end = frame.position + u64::from(frame.size);
+            self.next_position = frame.position;
+            self.remaining = frame.size;
+            self.reading_config = false;
+            self.timestamp = Some(frame.display_timestamp);
+        }
+
+        self.reader.seek(SeekFrom::Start(self.next_position))?;
+        let obu = next_obu_header(
+            &mut self.reader,
+            &mut self.remaining,
+            self.is_annexb && !self.reading_config,
+        )?;
+        self.position = self.reader.seek(SeekFrom::Current(0))?;
+        self.next_position = self.position + u64::from(obu.obu_size);
+
+        // The payload is read without preallocating it, since its size is not trusted yet.
+        let mut payload = Vec::new();
+        (&mut self.reader)
+            .take(u64::from(obu.obu_size))
+            .read_to_end(&mut payload)?;
+        if payload.len() != obu.obu_size as usize {
+            return Err(ElevatorError::TruncatedObu {
+                position: self.position - u64::from(obu.header_len),
+            });
+        }
+
+        Ok(Some((obu, payload)))
+    }
+}
+
+impl<R: Read + Seek> Iterator for ObuReader<R> {
+    type Item = Result<(av1p::obu::Obu, Vec<u8>), ElevatorError>;
+
+    /// Reads the next OBU. The iteration stops after the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let result = self.next_obu().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+
+        result
+    }
+}
+
+/// Looks ahead for the first sequence header, for input without container metadata.
+fn find_sequence_header<R: io::Read + io::Seek>(
+    reader: &mut R,
+    container: Container,
+    frames_start: u64,
+    file_size: u64,
+) -> Result<Option<av1p::obu::SequenceHeader>, ElevatorError> {
+    let mut seq = av1p::av1::Sequence::new();
+
+    for item in ObuReader::with_container(reader, container, None, frames_start, file_size) {
+        let (obu, payload) = item?;
+
+        if obu.obu_type == av1p::obu::OBU_SEQUENCE_HEADER {
+            obu::process_obu(&mut payload.as_slice(), &mut seq, &obu);
+
+            if seq.sh.is_some() {
+                return Ok(seq.sh);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Parses the configOBUs of an AV1CodecConfigurationRecord, tracking sequence headers for patching.
+fn parse_config_obus<R: io::Read + io::Seek>(
+    reader: &mut R,
+    seq: &mut av1p::av1::Sequence,
+    sequence_headers: &mut Vec<SequenceHeaderLocation>,
+    config_pos: u64,
+    config_size: u32,
+) -> Result<(), ElevatorError> {
+    reader.seek(SeekFrom::Start(config_pos))?;
+    let mut sz = config_size;
+
+    while sz > 0 {
+        let obu = next_obu_header(reader, &mut sz, false)?;
+        let pos = reader.seek(SeekFrom::Current(0))?;
+
+        if obu.obu_type == av1p::obu::OBU_SEQUENCE_HEADER {
+            sequence_headers.push(SequenceHeaderLocation::new(pos, &obu, None));
+            obu::process_obu(reader, seq, &obu);
+        }
+
+        reader.seek(SeekFrom::Start(pos + u64::from(obu.obu_size)))?;
+    }
+
+    Ok(())
+}
+
+/// Location of a sequence header OBU in the file
+#[derive(Clone, Copy)]
+struct SequenceHeaderLocation {
+    /// Absolute position of the OBU payload
+    position: u64,
+    /// Size of the OBU payload in bytes
+    size: u32,
+    /// Size of the OBU header in bytes, including its size field
+    header_len: u32,
+    /// Position and size of the container frame holding the OBU, unless it is stored in the codec configuration record
+    frame: Option<(u64, u32)>,
+}
+
+impl SequenceHeaderLocation {
+    fn new(position: u64, obu: &av1p::obu::Obu, frame: Option<(u64, u32)>) -> Self {
+        SequenceHeaderLocation {
+            position,
+            size: obu.obu_size,
+            header_len: obu.header_len,
+            frame,
+        }
+    }
+}
+
+/// Size fields of the container frames, which must be updated when an OBU grows
+#[derive(Clone, Copy, PartialEq)]
+enum FrameSizeField {
+    /// The frame size in each IVF frame header
+    Ivf,
+    /// Raw OBU streams only have OBU size fields
+    None,
+    /// The frame sizes cannot be updated
+    Unsupported,
+}
+
+/// Results of the analysis of a single operating point
+pub struct OperatingPoint {
+    /// Layers included in the operating point, as signaled by `operating_point_idc`
+    pub idc: u16,
+    /// Maximum parameters relevant to level restrictions encountered in the operating point
+    pub context: SequenceContext,
+    /// Minimum level satisfying the operating point parameters
+    pub level: Level,
+    /// Name of the constraint that rules out the level below `level`, if any
+    pub binding_constraint: Option<&'static str>,
+    /// Minimum level satisfying the operating point parameters in High tier, if it is lower than `level`,
+    /// which happens when only the Main tier bitrate limit holds a Main tier stream back
+    pub high_tier_level: Option<Level>,
+    /// Level signaled in the sequence header
+    pub old_level: Level,
+    /// Minimum level required to satisfy the compressed ratio constraint
+    pub min_cr_level: Level,
+    /// Total number of displayed frames
+    pub total_show_count: u32,
+    /// Maximum header rate, windowed over one second
+    pub max_header_rate: f64,
+    /// Maximum number of shown frames per second, windowed over one second
+    pub max_display_rate: f64,
+    /// Maximum number of decoded frames per second, windowed over one second
+    pub max_decode_rate: f64,
+    /// Minimum frame rate of a single temporal unit
+    pub min_frame_rate: f64,
+    /// Average frame rate over the whole stream
+    pub avg_frame_rate: f64,
+    /// Maximum frame rate of a single temporal unit
+    pub max_frame_rate: f64,
+    /// Maximum bitrate in megabits per second, over the first bitrate window
+    pub max_mbps: f64,
+    /// Timestamps of the temporal units ending the windows over which the maximum rates were measured
+    pub peak_timestamps: PeakTimestamps,
+    /// Peak bitrate over each bitrate window given in the analysis options
+    pub peak_bitrates: Vec<PeakBitrate>,
+    /// Maximum number of tiles in a frame
+    pub max_tiles: u32,
+    /// Maximum number of tile columns in a frame
+    pub max_tile_cols: u32,
+    /// Decoder model parameters signaled in the first sequence header, if any
+    pub operating_parameters: Option<OperatingParameters>,
+    /// Rates of the tile lists, if the stream uses large scale tile decoding
+    pub tile_lists: Option<TileListRates>,
+}
+
+/// Rates at which the tile lists of a large scale tile stream are decoded, at the tile list rate given in the analysis
+/// options. The bitrate counts against the bitrate limit of the level, and the decode rates against half its decode rate.
+/// The tiles of a tile list also count against the tile limits of the level, like those of a frame.
+#[derive(Clone, Copy, Serialize)]
+pub struct TileListRates {
+    /// Number of tile list OBUs in the operating point
+    pub count: u64,
+    /// Maximum bitrate of a tile list, in megabits per second
+    pub mbps: f64,
+    /// Maximum number of frames decoded per second, measuring each tile as a fraction of its anchor frame
+    pub decode_rate: f64,
+    /// Maximum number of luma samples decoded per second, from the tiling of the anchor frames
+    pub decode_sample_rate: f64,
+    /// Maximum number of tiles in a tile list
+    pub max_tiles: u16,
+    /// Maximum width in tiles of the frame output from a tile list
+    pub max_tile_cols: u16,
+    /// Whether the tile lists require the level of the operating point, through its binding constraint
+    pub drives_level: bool,
+}
+
+/// Timestamps at which the maximum rates of an operating point were measured, at the time scale of the stream.
+/// Each one is the presentation timestamp of the temporal unit ending the window with the maximum rate.
+#[derive(Clone, Copy, Default)]
+pub struct PeakTimestamps {
+    pub header_rate: u64,
+    pub display_rate: u64,
+    pub decode_rate: u64,
+    pub mbps: u64,
+}
+
+/// Highest bitrate of a stream over a window of a given length
+#[derive(Clone, Copy, Serialize)]
+pub struct PeakBitrate {
+    /// Length of the window in seconds
+    pub window: f64,
+    /// Bitrate in megabits per second
+    pub mbps: f64,
+}
+
+/// Results of the analysis of an AV1 stream
+pub struct Analysis {
+    /// Container-level stream metadata
+    pub metadata: ContainerMetadata,
+    /// Analysis of each operating point, in the order signaled in the sequence header
+    pub operating_points: Vec<OperatingPoint>,
+    /// Level signaled in the codec configuration record, for MP4 and WebM input
+    pub config_record_level: Option<Level>,
+    /// Tier signaled in the codec configuration record, which unlike the sequence header codes it for every level
+    pub config_record_tier: Option<Tier>,
+    /// Number of container frames read, which can be compared with [`ContainerMetadata::frame_count`]
+    pub frame_count: u64,
+    /// Point at which the analysis stopped, if the stream is truncated and [`AnalysisOptions::allow_truncated`] is set
+    pub truncation: Option<Truncation>,
+    /// Temporal units whose timestamp precedes that of the previous one, which was given the median duration
+    /// of the last temporal units instead
+    pub timestamp_jumps: Vec<TimestampJump>,
+    sequence_header: av1p::obu::SequenceHeader,
+    sequence_headers: Vec<SequenceHeaderLocation>,
+    config_record: Option<u64>,
+    fixed_size_headers: bool,
+    frame_size_field: FrameSizeField,
+}
+
+impl Analysis {
+    /// Provides the last sequence header of the stream. The analysis fails if it changes mid-stream, so it describes every frame.
+    pub fn sequence_header(&self) -> &av1p::obu::SequenceHeader {
+        &self.sequence_header
+    }
+
+    /// Provides the absolute position of the payload of each sequence header OBU, in the order they were found,
+    /// all of which are patched. The analysis fails if any of them declares other levels than the first one.
+    pub fn sequence_header_positions(&self) -> Vec<u64> {
+        self.sequence_headers
+            .iter()
+            .map(|location| location.position)
+            .collect()
+    }
+
+    /// Checks whether the stream already signals the given level of each operating point, and the given tier if any,
+    /// in its sequence headers and codec configuration record, so that patching them would not change anything.
+    /// The tier is compared with the signaled one rather than the tier of the analysis, which may have been forced.
+    pub fn signals_levels(&self, levels: &[Level], tier: Option<Tier>) -> bool {
+        let sh = &self.sequence_header;
+        let signaled_tier = |i: usize| {
+            if sh.op[i].seq_tier == 0 {
+                Tier::Main
+            } else {
+                Tier::High
+            }
+        };
+
+        // The sequence header only codes the tier above level 3.3 (index 7), and the configuration record always does.
+        let sequence_headers_unchanged =
+            self.operating_points
+                .iter()
+                .zip(levels)
+                .enumerate()
+                .all(|(i, (op, level))| {
+                    op.old_level.0 == level.0
+                        && tier
+                            .iter()
+                            .all(|&tier| level.0 <= 7 || signaled_tier(i) == tier)
+                });
+        let config_record_unchanged = match (levels.first(), self.config_record_level) {
+            (Some(level), Some(record_level)) => {
+                let record_tier = match tier.unwrap_or_else(|| signaled_tier(0)) {
+                    Tier::High if level.0 > 7 => Tier::High,
+                    _ => Tier::Main,
+                };
+
+                record_level.0 == level.0 && self.config_record_tier == Some(record_tier)
+            }
+            _ => true,
+        };
+
+        sequence_headers_unchanged && config_record_unchanged
+    }
+}
+
+/// Point at which the analysis of a truncated stream stopped
+#[derive(Clone, Copy, Serialize)]
+pub struct Truncation {
+    /// Absolute position of the incomplete container frame or OBU
+    pub position: u64,
+    /// Number of temporal units analyzed before it, which is zero if the frames were not analyzed
+    pub temporal_units: u64,
+    /// Display timestamp of the last temporal unit analyzed, at the time scale of the stream
+    pub timestamp: u64,
+}
+
+/// Temporal unit whose timestamp goes backwards
+#[derive(Clone, Copy, Serialize)]
+pub struct TimestampJump {
+    /// Absolute position of the container frame starting the temporal unit
+    pub position: u64,
+    /// Display timestamp of the temporal unit, at the time scale of the stream
+    pub timestamp: u64,
+    /// Display timestamp of the previous temporal unit
+    pub previous_timestamp: u64,
+}
+
+/// Extent of the stream covered by a pass over its frames
+struct Coverage {
+    /// Number of container frames read
+    frames: u64,
+    truncation: Option<Truncation>,
+    timestamp_jumps: Vec<TimestampJump>,
+}
+
+/// Statistics of a single temporal unit, reported as the stream is analyzed
+#[derive(Clone, Copy)]
+pub struct TemporalUnitStats {
+    /// Presentation timestamp of the temporal unit, at the time scale of the stream
+    pub pts: u64,
+    /// Duration of the temporal unit, in time scale units
+    pub duration: u64,
+    /// Total size of the OBUs in the temporal unit in bytes, including their headers
+    pub bytes: u32,
+    /// Number of shown frames
+    pub shown_frames: u32,
+    /// Number of decoded frames
+    pub decoded_frames: u32,
+    /// Number of frame headers
+    pub headers: u32,
+    /// Bitrate in megabits per second over the one-second window ending with the temporal unit
+    pub mbps: f64,
+}
+
+/// Location of the frames and configuration OBUs of a stream
+#[derive(Clone)]
+struct StreamLayout {
+    container: Container,
+    /// Position and size of the configOBUs of the codec configuration record, if any
+    config_obus: Option<(u64, u32)>,
+    /// Position of the first frame, for containers that are read sequentially
+    frames_start: u64,
+    /// Position of the codec configuration record, for MP4 and WebM input
+    config_record: Option<u64>,
+    /// Whether sequence headers are stored in elements that cannot be resized
+    fixed_size_headers: bool,
+    frame_size_field: FrameSizeField,
+    /// Size of the file, which no container frame may extend past
+    file_size: u64,
+}
+
+/// Checks whether an OBU belongs to one of the layers included in an operating point.
+fn in_operating_point(idc: u16, obu: &av1p::obu::Obu) -> bool {
+    if idc == 0 || !obu.obu_extension_flag {
+        return true;
+    }
+
+    let in_temporal_layer = (idc >> obu.temporal_id) & 1 != 0;
+    let in_spatial_layer = (idc >> (obu.spatial_id + 8)) & 1 != 0;
+
+    in_temporal_layer && in_spatial_layer
+}
+
+/// Checks whether an OBU belongs to the layer selected in the analysis options.
+/// OBUs without an extension header, like sequence headers and temporal delimiters, apply to every layer.
+fn in_selected_layer(options: &AnalysisOptions, obu: &av1p::obu::Obu) -> bool {
+    if !obu.obu_extension_flag {
+        return true;
+    }
+
+    !matches!(options.temporal_id, Some(id) if id != obu.temporal_id)
+        && !matches!(options.spatial_id, Some(id) if id != obu.spatial_id)
+}
+
+/// Provides the position of the incomplete frame or OBU reported by `err`, if truncated streams are allowed.
+/// Incomplete OBUs are only expected at the end of the file, in the last container frame.
+fn truncated_at(
+    options: &AnalysisOptions,
+    err: &ElevatorError,
+    is_last_frame: bool,
+) -> Option<u64> {
+    match *err {
+        _ if !options.allow_truncated => None,
+        ElevatorError::TruncatedFrame { position, .. } => Some(position),
+        ElevatorError::TruncatedObu { position } if is_last_frame => Some(position),
+        _ => None,
+    }
+}
+
+/// Analyzes an AV1 stream in the given file format to determine its level.
+///
+/// A time scale must be provided in `options` for raw OBU and Annex B input, which carry no timing information.
+pub fn analyze<R: Read + Seek>(
+    reader: R,
+    fmt: FileFormat,
+    options: &AnalysisOptions,
+) -> Result<Analysis, ElevatorError> {
+    analyze_with_tu_stats(reader, fmt, options, |_| {})
+}
+
+/// Parses the container of the stream, locating its frames and configuration OBUs.
+fn open_stream<R: Read + Seek>(
+    mut reader: R,
+    fmt: FileFormat,
+    options: &AnalysisOptions,
+) -> Result<(ContainerMetadata, StreamLayout), ElevatorError> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut config_record = None; // position of the codec configuration record, for MP4 and WebM input
+    let mut config_obus = None; // position and size of the configOBUs, for MP4 and WebM input
+    let mut fixed_size_headers = false; // whether sequence headers are stored in elements that cannot be resized
+
+    let (mut metadata, container) = match fmt {
+        FileFormat::IVF => {
+            let header = ivf::parse_ivf_header(&mut reader)?;
+
+            (
+                ContainerMetadata {
+                    // Note: the `framerate` field name (from av1parser) is inaccurate
+                    time_scale: (header.framerate, header.timescale),
+                    resolution: (header.width, header.height),
+                    frame_count: Some(header.nframes),
+                },
+                Container::Ivf(IvfDemuxer {
+                    next_position: av1p::ivf::IVF_HEADER_SIZE as u64,
+                }),
+            )
+        }
+        FileFormat::MP4 => {
+            let track = mp4::parse_mp4(&mut reader)?;
+            config_record = track.config_record;
+
+            config_obus = track.config_obus;
+
+            (
+                ContainerMetadata {
+                    time_scale: (track.timescale, 1),
+                    resolution: track.resolution,
+                    frame_count: None,
+                },
+                Container::Mp4(track.samples.into_iter()),
+            )
+        }
+        FileFormat::WebM => {
+            let track = mkv::parse_mkv(&mut reader)?;
+            config_record = track.config_record;
+            fixed_size_headers = true;
+
+            config_obus = track.config_obus;
+
+            (
+                ContainerMetadata {
+                    time_scale: track.time_scale,
+                    resolution: track.resolution,
+                    frame_count: None,
+                },
+                Container::Mkv(track.blocks.into_iter()),
+            )
+        }
+        FileFormat::Bitstream => {
+            let time_scale = options.time_scale.ok_or(ElevatorError::MissingTimeScale)?;
+            let is_annexb = annexb::probe(&mut reader)?;
+            let new_container = || {
+                if is_annexb {
+                    Container::AnnexB(annexb::AnnexBReader::new(file_size))
+                } else {
+                    Container::Obu(ObuDemuxer::new(file_size))
+                }
+            };
+
+            // There is no container to provide a resolution, so look ahead for the first sequence header.
+            let sh = find_sequence_header(&mut reader, new_container(), 0, file_size)?
+                .ok_or(ElevatorError::MissingSequenceHeader)?;
+            reader.seek(SeekFrom::Start(0))?;
+
+            (
+                ContainerMetadata {
+                    // Each temporal unit is one unit of time.
+                    time_scale,
+                    resolution: (sh.max_frame_width as u16, sh.max_frame_height as u16),
+                    frame_count: None,
+                },
+                new_container(),
+            )
+        }
+    };
+
+    let layout = StreamLayout {
+        config_obus,
+        frames_start: reader.seek(SeekFrom::Current(0))?,
+        config_record,
+        fixed_size_headers,
+        frame_size_field: match container {
+            Container::Ivf(_) => FrameSizeField::Ivf,
+            Container::Obu(_) => FrameSizeField::None,
+            _ => FrameSizeField::Unsupported,
+        },
+        container,
+        file_size,
+    };
+
+    // Raw OBU and Annex B input already take their resolution from the first sequence header.
+    if options.use_sequence_resolution && !matches!(fmt, FileFormat::Bitstream) {
+        let mut seq = av1p::av1::Sequence::new();
+
+        if let Some((config_pos, config_size)) = layout.config_obus {
+            parse_config_obus(
+                &mut reader,
+                &mut seq,
+                &mut Vec::new(),
+                config_pos,
+                config_size,
+            )?;
+        }
+
+        let sh = match seq.sh {
+            Some(sh) => sh,
+            None => find_sequence_header(
+                &mut reader,
+                layout.container.clone(),
+                layout.frames_start,
+                layout.file_size,
+            )?
+            .ok_or(ElevatorError::MissingSequenceHeader)?,
+        };
+
+        metadata.resolution = (sh.max_frame_width as u16, sh.max_frame_height as u16);
+        reader.seek(SeekFrom::Start(layout.frames_start))?;
+    }
+
+    Ok((metadata, layout))
+}
+
+/// Reads the decoder model parameters of each operating point from the first sequence header.
+fn read_operating_parameters<R: Read + Seek>(
+    mut reader: R,
+    sequence_headers: &[SequenceHeaderLocation],
+    operating_points: &mut [OperatingPoint],
+) -> Result<(), ElevatorError> {
+    let loc = match sequence_headers.first() {
+        Some(loc) => loc,
+        None => return Ok(()),
+    };
+
+    let mut input = vec![0; loc.size as usize];
+    reader.seek(SeekFrom::Start(loc.position))?;
+    reader.read_exact(&mut input)?;
+
+    let fields = obu::parse_level_fields(&input).map_err(|err| match err {
+        ElevatorError::Io(err) => ElevatorError::MalformedBitstream {
+            position: loc.position,
+            reason: err.to_string(),
+        },
+        err => err,
+    })?;
+
+    for (op, field) in operating_points.iter_mut().zip(&fields) {
+        op.operating_parameters = field.operating_parameters;
+    }
+
+    Ok(())
+}
+
+/// Reads the level and tier signaled in the codec configuration record, for MP4 and WebM input.
+fn read_config_record_level<R: Read + Seek>(
+    mut reader: R,
+    layout: &StreamLayout,
+) -> Result<Option<(Level, Tier)>, ElevatorError> {
+    match layout.config_record {
+        Some(record_pos) => {
+            let (record_level, record_tier) = mp4::read_config_level(&mut reader, record_pos)?;
+            let tier = if record_tier == 0 {
+                Tier::Main
+            } else {
+                Tier::High
+            };
+            Ok(Some((LEVELS[usize::from(record_level)], tier)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Analyzes an AV1 stream like [`analyze`], calling `on_temporal_unit` with the statistics of each temporal unit
+/// of the whole stream as it is read.
+pub fn analyze_with_tu_stats<R: Read + Seek, F: FnMut(&TemporalUnitStats)>(
+    mut reader: R,
+    fmt: FileFormat,
+    options: &AnalysisOptions,
+    mut on_temporal_unit: F,
+) -> Result<Analysis, ElevatorError> {
+    let (metadata, layout) = open_stream(&mut reader, fmt, options)?;
+
+    // Analyze the whole stream first, which also locates the sequence headers.
+    let mut sequence_headers = Vec::new();
+    let (stream, sh, coverage) = analyze_operating_point(
+        &mut reader,
+        &layout,
+        &metadata,
+        options,
+        0,
+        0,
+        &mut sequence_headers,
+        &mut on_temporal_unit,
+    )?;
+
+    // Each operating point only includes the OBUs of its own layers.
+    let mut operating_points = if sh.operating_points_cnt > 1 {
+        (0..sh.operating_points_cnt as usize)
+            .map(|i| {
+                analyze_operating_point(
+                    &mut reader,
+                    &layout,
+                    &metadata,
+                    options,
+                    i,
+                    sh.op[i].operating_point_idc,
+                    &mut Vec::new(),
+                    &mut |_| {},
+                )
+                .map(|(op, _, _)| op)
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        vec![stream]
+    };
+
+    read_operating_parameters(&mut reader, &sequence_headers, &mut operating_points)?;
+
+    let config_record = read_config_record_level(&mut reader, &layout)?;
+
+    Ok(Analysis {
+        config_record_level: config_record.map(|(level, _)| level),
+        config_record_tier: config_record.map(|(_, tier)| tier),
+        metadata,
+        operating_points,
+        frame_count: coverage.frames,
+        truncation: coverage.truncation,
+        timestamp_jumps: coverage.timestamp_jumps,
+        sequence_header: sh,
+        sequence_headers,
+        config_record: layout.config_record,
+        fixed_size_headers: layout.fixed_size_headers,
+        frame_size_field: layout.frame_size_field,
+    })
+}
+
+/// Locates the sequence headers of an AV1 stream without analyzing its frames, for setting a level manually.
+///
+/// Only the sequence headers are parsed, so the measured rates are zero, and the level of each operating point
+/// only accounts for the maximum frame size of the sequence header. It is a lower bound of the level computed by
+/// [`analyze`], which measures the rates with the same frame size.
+pub fn analyze_headers<R: Read + Seek>(
+    mut reader: R,
+    fmt: FileFormat,
+    options: &AnalysisOptions,
+) -> Result<Analysis, ElevatorError> {
+    let (metadata, layout) = open_stream(&mut reader, fmt, options)?;
+
+    let mut seq = av1p::av1::Sequence::new();
+    let mut sequence_headers = Vec::new();
+
+    if let Some((config_pos, config_size)) = layout.config_obus {
+        parse_config_obus(
+            &mut reader,
+            &mut seq,
+            &mut sequence_headers,
+            config_pos,
+            config_size,
+        )?;
+    }
+
+    let mut container = layout.container.clone();
+    let is_annexb = matches!(container, Container::AnnexB(_));
+    let mut coverage = Coverage {
+        frames: 0,
+        truncation: None,
+        timestamp_jumps: Vec::new(),
+    };
+    let mut last_timestamp = 0;
+    reader.seek(SeekFrom::Start(layout.frames_start))?;
+
+    // Only the OBU headers are read, skipping over the payloads of everything but sequence headers.
+    'frames: loop {
+        let frame = match container.next_frame_within(&mut reader, layout.file_size) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) => match truncated_at(options, &err, true) {
+                Some(position) => {
+                    coverage.truncation = Some(Truncation {
+                        position,
+                        temporal_units: 0,
+                        timestamp: last_timestamp,
+                    });
+                    break;
+                }
+                None => return Err(err),
+            },
+        };
+        let is_last_frame = frame.position + u64::from(frame.size) == layout.file_size;
+
+        coverage.frames += 1;
+        last_timestamp = frame.display_timestamp;
+        let frame_end = frame.position + u64::from(frame.size);
+        let mut sz = frame.size;
+
+        while sz > 0 {
+            let obu = match next_obu_header(&mut reader, &mut sz, is_annexb) {
+                Ok(obu) => obu,
+                Err(err) => match truncated_at(options, &err, is_last_frame) {
+                    Some(position) => {
+                        coverage.truncation = Some(Truncation {
+                            position,
+                            temporal_units: 0,
+                            timestamp: last_timestamp,
+                        });
+                        break 'frames;
+                    }
+                    None => return Err(err),
+                },
+            };
+            let pos = frame_end - u64::from(sz) - u64::from(obu.obu_size);
+            let mut payload = Read::by_ref(&mut reader).take(u64::from(obu.obu_size));
+
+            if obu.obu_type == av1p::obu::OBU_SEQUENCE_HEADER {
+                sequence_headers.push(SequenceHeaderLocation::new(
+                    pos,
+                    &obu,
+                    Some((frame.position, frame.size)),
+                ));
+
+                let previous = seq.sh;
+                obu::process_obu(&mut payload, &mut seq, &obu);
+                check_sequence_header_change(previous, seq.sh, pos)?;
+            }
+
+            let remaining = payload.limit();
+            skip_payload(&mut reader, remaining)?;
+        }
+    }
+
+    let sh = seq.sh.ok_or(ElevatorError::MissingSequenceHeader)?;
+    let mut operating_points = (0..usize::from(sh.operating_points_cnt.max(1)))
+        .map(|i| {
+            let old_level = LEVELS[usize::from(sh.op[i].seq_level_idx)];
+            let context = SequenceContext {
+                tier: if sh.op[i].seq_tier == 0 {
+                    Tier::Main
+                } else {
+                    Tier::High
+                },
+                pic_size: (sh.max_frame_width as u16, sh.max_frame_height as u16),
+                ..SequenceContext::default()
+            };
+            let (level, binding_constraint) = calculate_level_with_constraint(&context)?;
+
+            Ok(OperatingPoint {
+                idc: sh.op[i].operating_point_idc,
+                context,
+                level,
+                binding_constraint,
+                high_tier_level: None,
+                old_level,
+                min_cr_level: LEVELS[0],
+                total_show_count: 0,
+                max_header_rate: 0.0,
+                max_display_rate: 0.0,
+                max_decode_rate: 0.0,
+                min_frame_rate: 0.0,
+                avg_frame_rate: 0.0,
+                max_frame_rate: 0.0,
+                max_mbps: 0.0,
+                peak_timestamps: PeakTimestamps::default(),
+                peak_bitrates: Vec::new(),
+                max_tiles: 0,
+                max_tile_cols: 0,
+                operating_parameters: None,
+                tile_lists: None,
+            })
+        })
+        .collect::<Result<Vec<_>, ElevatorError>>()?;
+    read_operating_parameters(&mut reader, &sequence_headers, &mut operating_points)?;
+
+    let config_record = read_config_record_level(&mut reader, &layout)?;
+
+    Ok(Analysis {
+        config_record_level: config_record.map(|(level, _)| level),
+        config_record_tier: config_record.map(|(_, tier)| tier),
+        metadata,
+        operating_points,
+        frame_count: coverage.frames,
+        truncation: coverage.truncation,
+        timestamp_jumps: coverage.timestamp_jumps,
+        sequence_header: sh,
+        sequence_headers,
+        config_record: layout.config_record,
+        fixed_size_headers: layout.fixed_size_headers,
+        frame_size_field: layout.frame_size_field,
+    })
+}
+
+/// Provides the name of the first field, besides the levels and tiers, that differs between two sequence headers.
+fn changed_sequence_header_field(
+    previous: &av1p::obu::SequenceHeader,
+    sh: &av1p::obu::SequenceHeader,
+) -> Option<&'static str> {
+    let op_count = usize::from(sh.operating_points_cnt.max(1));
+    let (previous_color, color) = (&previous.color_config, &sh.color_config);
+
+    if previous.seq_profile != sh.seq_profile {
+        Some("profile")
+    } else if (
+        previous.still_picture,
+        previous.reduced_still_picture_header,
+    ) != (sh.still_picture, sh.reduced_still_picture_header)
+    {
+        Some("still picture flags")
+    } else if (previous.max_frame_width, previous.max_frame_height)
+        != (sh.max_frame_width, sh.max_frame_height)
+    {
+        Some("maximum frame size")
+    } else if previous.operating_points_cnt != sh.operating_points_cnt
+        || (0..op_count).any(|i| previous.op[i].operating_point_idc != sh.op[i].operating_point_idc)
+    {
+        Some("operating points")
+    } else if previous.timing_info_present_flag != sh.timing_info_present_flag {
+        Some("timing info")
+    } else if previous_color.BitDepth != color.BitDepth
+        || previous_color.mono_chrome != color.mono_chrome
+        || (previous_color.subsampling_x, previous_color.subsampling_y)
+            != (color.subsampling_x, color.subsampling_y)
+        || (
+            previous_color.color_primaries,
+            previous_color.transfer_characteristics,
+            previous_color.matrix_coefficients,
+        ) != (
+            color.color_primaries,
+            color.transfer_characteristics,
+            color.matrix_coefficients,
+        )
+        || previous_color.color_range != color.color_range
+    {
+        Some("color config")
+    } else {
+        None
+    }
+}
+
+/// Checks that a sequence header keeps the parameters of the previous one which the analysis depends on,
+/// since the stream is analyzed and patched as a single configuration.
+fn check_sequence_header_change(
+    previous: Option<av1p::obu::SequenceHeader>,
+    sh: Option<av1p::obu::SequenceHeader>,
+    position: u64,
+) -> Result<(), ElevatorError> {
+    let (previous, sh) = match (previous, sh) {
+        (Some(previous), Some(sh)) => (previous, sh),
+        _ => return Ok(()),
+    };
+
+    let op_count = usize::from(sh.operating_points_cnt.max(1));
+    let parameter = match changed_sequence_header_field(&previous, &sh) {
+        Some(field) => field,
+        None if (0..op_count).any(|i| {
+            (previous.op[i].seq_level_idx, previous.op[i].seq_tier)
+                != (sh.op[i].seq_level_idx, sh.op[i].seq_tier)
+        }) =>
+        {
+            "level"
+        }
+        None => return Ok(()),
+    };
+
+    Err(ElevatorError::SequenceHeaderChanged {
+        position,
+        parameter,
+    })
+}
+
+/// Provides the number of tiles and tile columns of a frame, which saturate rather than wrap around.
+fn tile_counts(tile_info: &av1p::obu::TileInfo) -> (u16, u16) {
+    let saturate = |count: u32| u16::try_from(count).unwrap_or(std::u16::MAX);
+
+    (
+        saturate(tile_info.tile_cols.saturating_mul(tile_info.tile_rows)),
+        saturate(tile_info.tile_cols),
+    )
+}
+
+/// Provides the compressed ratio of a frame, given its number of luma samples and its compressed size in bytes,
+/// or `None` if it has no compressed data, like frames shown with show_existing_frame.
+fn compressed_ratio(seq_profile: u8, frame_samples: u64, compressed_size: u64) -> Option<f64> {
+    if compressed_size == 0 {
+        return None;
+    }
+
+    Some(uncompressed_size(seq_profile, frame_samples as usize) as f64 / compressed_size as f64)
+}
+
+/// Raises the minimum level index required by the compressed ratio bound in each tier, Main then High,
+/// to the one required by the frames of a temporal unit.
+fn update_min_cr_level_indices(
+    min_cr_level_idx: &mut [usize; 2],
+    display_rate: f64,
+    min_compressed_ratio: f64,
+    still_picture: bool,
+) {
+    for (index, &tier) in min_cr_level_idx.iter_mut().zip(&[Tier::Main, Tier::High]) {
+        *index = (*index).max(min_cr_level_index(
+            tier,
+            display_rate,
+            min_compressed_ratio,
+            still_picture,
+        ));
+    }
+}
+
+/// Provides the number of luma samples of a frame, after super resolution upscaling.
+fn luma_samples(frame_size: &av1p::obu::FrameSize) -> u64 {
+    u64::from(frame_size.superres_params.UpscaledWidth) * u64::from(frame_size.FrameHeight)
+}
+
+/// Skips the unread bytes of an OBU payload, with a relative seek that is only made if there are any.
+fn skip_payload<R: Seek>(reader: &mut R, remaining: u64) -> io::Result<()> {
+    if remaining > 0 {
+        reader.seek(SeekFrom::Current(remaining as i64))?;
+    }
+
+    Ok(())
+}
+
+/// Reads the payload of a frame header OBU, and checks whether it repeats the last frame header of the temporal unit,
+/// remembering it otherwise.
+fn is_repeated_frame_header<R: Read>(
+    payload: &mut R,
+    last_frame_header: &mut Vec<u8>,
+) -> io::Result<bool> {
+    // The payload is bounded by the OBU size, which is checked against the container frame size beforehand.
+    let mut bytes = Vec::new();
+    payload.read_to_end(&mut bytes)?;
+
+    if bytes == *last_frame_header {
+        Ok(true)
+    } else {
+        *last_frame_header = bytes;
+        Ok(false)
+    }
+}
+
+/// Checks whether a frame header OBU only copies the frame header of the current frame, which redundant frame header
+/// OBUs always do, so that it adds to the bitrate without counting as another frame.
+fn is_frame_header_copy<R: Read>(
+    obu: &av1p::obu::Obu,
+    payload: &mut R,
+    last_frame_header: &mut Vec<u8>,
+) -> io::Result<bool> {
+    if obu.obu_type == av1p::obu::OBU_REDUNDANT_FRAME_HEADER {
+        return Ok(true);
+    }
+
+    is_repeated_frame_header(payload, last_frame_header)
+}
+
+/// Raises a maximum to a new measurement, along with the timestamp at which it was measured.
+fn update_peak(max: &mut f64, max_pts: &mut u64, value: f64, pts: u64) {
+    if value > *max {
+        *max = value;
+        *max_pts = pts;
+    }
+}
+
+/// Extrapolates the bitrate of a clip shorter than the bitrate windows, and provides the bitrate over the first one.
+fn extrapolate_bitrate_windows(windows: &mut [BitrateWindow]) -> Option<f64> {
+    let mut first = None;
+
+    for window in windows {
+        let mbps = window.extrapolate();
+        first.get_or_insert(mbps);
+    }
+
+    first.flatten()
+}
+
+/// Adds a temporal unit to every bitrate window, and provides the bitrate over the first one.
+fn push_bitrate_windows(windows: &mut [BitrateWindow], duration: u64, bytes: u32) -> f64 {
+    let mut first = None;
+
+    for window in windows {
+        let mbps = window.push(duration, bytes);
+        first.get_or_insert(mbps);
+    }
+
+    first.unwrap_or(0.0)
+}
+
+/// Counts a frame header in the temporal unit holding it, and provides the number of luma samples of its frame.
+///
+/// A frame shown with show_existing_frame is displayed at the time of the temporal unit holding this header,
+/// rather than the one that decoded it, with the size of the reference frame it shows.
+/// Showing an existing key frame also refreshes every reference frame with it, without decoding anything.
+fn count_frame_header(
+    fh: &av1p::obu::FrameHeader,
+    counts: &mut TemporalUnitCounts,
+    ref_frame_samples: &mut [u64; 8],
+) -> u64 {
+    let samples = if fh.show_existing_frame {
+        ref_frame_samples[usize::from(fh.frame_to_show_map_idx) & 7]
+    } else {
+        luma_samples(&fh.frame_size)
+    };
+
+    if fh.show_frame || fh.show_existing_frame {
+        counts.shown_frames += 1;
+        counts.shown_samples += samples;
+    }
+
+    let refresh_frame_flags = if !fh.show_existing_frame {
+        counts.headers += 1;
+        counts.decoded_frames += 1;
+        counts.decoded_samples += samples;
+        fh.refresh_frame_flags
+    } else if fh.frame_type == av1p::obu::KEY_FRAME {
+        0xFF
+    } else {
+        0
+    };
+
+    for (i, slot) in ref_frame_samples.iter_mut().enumerate() {
+        if refresh_frame_flags >> i & 1 != 0 {
+            *slot = samples;
+        }
+    }
+
+    samples
+}
+
+/// Provides the number of bytes that an OBU adds to the bitrate, which is defined over the whole coded bitstream,
+/// so its header is included along with its payload whatever its type.
+fn coded_obu_size(obu: &av1p::obu::Obu) -> u32 {
+    obu.header_len + obu.obu_size
+}
+
+/// Analyzes the OBUs of the layers included in an operating point, and provides the last sequence header
+/// along with the extent of the stream that was read.
+// TODO: split this function into smaller parts
+#[allow(clippy::cognitive_complexity, clippy::too_many_arguments)]
+fn analyze_operating_point<R: Read + Seek>(
+    mut reader: R,
+    layout: &StreamLayout,
+    metadata: &ContainerMetadata,
+    options: &AnalysisOptions,
+    op_index: usize,
+    op_idc: u16,
+    sequence_headers: &mut Vec<SequenceHeaderLocation>,
+    on_temporal_unit: &mut dyn FnMut(&TemporalUnitStats),
+) -> Result<(OperatingPoint, av1p::obu::SequenceHeader, Coverage), ElevatorError> {
+    let mut seq = av1p::av1::Sequence::new();
+
+    // The codec configuration record may be the only place where the sequence header is stored.
+    if let Some((config_pos, config_size)) = layout.config_obus {
+        parse_config_obus(
+            &mut reader,
+            &mut seq,
+            sequence_headers,
+            config_pos,
+            config_size,
+        )?;
+    }
+
+    let mut container = layout.container.clone();
+    reader.seek(SeekFrom::Start(layout.frames_start))?;
+
+    let (mut max_tile_cols, mut max_tiles) = (0, 0); // the maximum tile parameters
+    let mut max_display_rate = 0_f64; // max number of shown frames per second (i.e. number of frame headers with show_frame or show_existing_frame)
+    let mut max_decode_rate = 0_f64; // max number of decoded frames per second (i.e. number of frame headers without show_existing_frame)
+    let mut max_header_rate = 0_f64; // max number of frame and frame header (excluding show_existing_frame) OBUs per second
+    let mut max_mbps = 0_f64; // max bitrate in megabits per second, over the first bitrate window
+    let mut peak_timestamps = PeakTimestamps::default(); // timestamps at which the maxima were measured
+    let mut min_cr_level_idx = [0; 2]; // minimum level index required to support the compressed ratio bound, per tier
+    let mut max_tile_list_mbps = 0_f64; // max bitrate for tile lists, in megabits per second
+    let mut max_tile_decode_rate = 0_f64; // max decode rate for tile lists, in frames per second
+    let mut max_display_sample_rate = 0_f64; // max number of luma samples of the shown frames per second
+    let mut max_decode_sample_rate = 0_f64; // max number of luma samples of the decoded frames per second
+    let mut max_tile_decode_sample_rate = 0_f64; // max number of luma samples decoded per second for tile lists
+    let mut tile_list_count = 0; // number of tile lists, which only large scale tile streams have
+    let (mut max_tile_list_tiles, mut max_tile_list_cols) = (0, 0); // the maximum tile parameters of the tile lists
+
+    let time_scale = metadata.time_scale();
+    let tile_list_rate = options.tile_list_rate.unwrap_or(DEFAULT_TILE_LIST_RATE);
+
+    let mut counts = TemporalUnitCounts::default(); // frame counts of the current temporal unit
+    let mut last_tu_duration = None; // duration of the last complete temporal unit
+    let mut clock = TimestampClock::new(default_tu_duration(time_scale, false)); // durations of the temporal units
+    let mut timestamp_jumps = Vec::new(); // temporal units whose timestamp goes backwards
+    let mut cur_tu_time = 0; // timestamp for the first frame of the current temporal unit
+    let mut compressed_size = 0_u64; // compressed size of the current frame, excluding its uncompressed header
+    let mut tu_size = 0; // total size of the OBUs in the current temporal unit, including their headers
+    let mut previous_frame_end = layout.frames_start; // end of the last container frame, for the container overhead
+    let mut window = RateWindow::new(metadata.time_scale); // one-second buffer of the counts per temporal unit
+    let mut bitrate_windows = if options.bitrate_windows.is_empty() {
+        vec![BitrateWindow::new(
+            metadata.time_scale,
+            DEFAULT_BITRATE_WINDOW,
+        )]
+    } else {
+        options
+            .bitrate_windows
+            .iter()
+            .map(|&seconds| BitrateWindow::new(metadata.time_scale, seconds))
+            .collect()
+    };
+    let mut seen_frame_header = false; // refreshed with each temporal unit
+    let mut measuring = false; // whether a frame header was found, after which every temporal unit is measured
+    let mut last_frame_header = Vec::new(); // payload of the last frame header OBU in the current temporal unit
+    let mut min_compressed_ratio = std::f64::MAX; // min compression ratio for a single frame
+    let mut anchor_frames = Vec::new(); // number of tiles and luma samples of the first coded frames, for large scale tile
+    let mut pending_anchor = None; // tiles and luma samples of the last frame header, an anchor frame once its tiles follow
+    let mut frame_samples = 0; // luma samples of the last decoded frame
+    let mut ref_frame_samples = [0; 8]; // luma samples of the frame in each reference slot, shown by show_existing_frame
+
+    let mut total_show_count = 0; // total number of displayed frames
+    let mut container_frames = 0; // total number of container frames
+    let mut temporal_units = 0; // total number of temporal units measured
+    let mut truncated_at_position = None; // position of the incomplete frame or OBU that ends a truncated stream
+
+    let is_annexb = matches!(container, Container::AnnexB(_));
+
+    // Read one frame from the container at a time.
+    'frames: loop {
+        let frame = match container.next_frame_within(&mut reader, layout.file_size) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(err) => match truncated_at(options, &err, true) {
+                Some(position) => {
+                    truncated_at_position = Some(position);
+                    break;
+                }
+                None => return Err(err),
+            },
+        };
+        let is_last_frame = frame.position + u64::from(frame.size) == layout.file_size;
+
+        container_frames += 1;
+        let frame_end = frame.position + u64::from(frame.size);
+        let mut sz = frame.size;
+        let pts = frame.display_timestamp;
+
+        // The container bytes preceding a frame are counted with its first OBU, in the temporal unit it belongs to.
+        // Frames stored out of order, as MP4 samples can be, have no overhead.
+        let mut container_overhead = if options.count_container_overhead {
+            frame.position.saturating_sub(previous_frame_end) as u32
+        } else {
+            0
+        };
+        previous_frame_end = frame_end;
+
+        // Read all AV1 OBUs in the container frame.
+        while sz > 0 {
+            let obu = match next_obu_header(&mut reader, &mut sz, is_annexb) {
+                Ok(obu) => obu,
+                Err(err) => match truncated_at(options, &err, is_last_frame) {
+                    // The OBUs read so far in the last temporal unit are still measured.
+                    Some(position) => {
+                        truncated_at_position = Some(position);
+                        break 'frames;
+                    }
+                    None => return Err(err),
+                },
+            };
+
+            // The position is tracked rather than queried, and unread payload bytes are skipped with a single relative seek,
+            // since both discard the buffer of a buffered reader.
+            let pos = frame_end - u64::from(sz) - u64::from(obu.obu_size);
+            let mut payload = Read::by_ref(&mut reader).take(u64::from(obu.obu_size));
+
+            // Skip the OBUs of layers that are not decoded for the operating point, or not selected for the analysis.
+            if !in_operating_point(op_idc, &obu) || !in_selected_layer(options, &obu) {
+                skip_payload(&mut reader, u64::from(obu.obu_size))?;
+                continue;
+            }
+
+            match obu.obu_type {
+                // Temporal units before the first frame, like the one before the first temporal delimiter, are not measured.
+                // Later ones without frames, such as those of temporal layers outside the operating point, still take up time.
+                // A temporal unit at the same timestamp as the current one is counted as part of it,
+                // along with its temporal delimiter.
+                av1p::obu::OBU_TEMPORAL_DELIMITER if !measuring || pts == cur_tu_time => {}
+                av1p::obu::OBU_TEMPORAL_DELIMITER => {
+                    let (duration, renormalized) = clock.duration(cur_tu_time, pts);
+                    if renormalized {
+                        timestamp_jumps.push(TimestampJump {
+                            position: frame.position,
+                            timestamp: pts,
+                            previous_timestamp: cur_tu_time,
+                        });
+                    }
+                    let display_rate = window.sample_rate(counts.shown_samples, duration);
+
+                    // Calculate the rates and bitrate, windowed over one second (sampled every temporal unit).
+                    // We assume that header rate is computed over one-second windows.
+                    // This is not clear in the specification, but seems implied.
+                    let rates = window.push(TemporalUnitCounts {
+                        duration,
+                        bytes: tu_size,
+                        ..counts
+                    });
+                    let mbps = push_bitrate_windows(&mut bitrate_windows, duration, tu_size);
+                    update_peak(
+                        &mut max_display_rate,
+                        &mut peak_timestamps.display_rate,
+                        rates.display_rate,
+                        cur_tu_time,
+                    );
+                    update_peak(
+                        &mut max_decode_rate,
+                        &mut peak_timestamps.decode_rate,
+                        rates.decode_rate,
+                        cur_tu_time,
+                    );
+                    update_peak(
+                        &mut max_header_rate,
+                        &mut peak_timestamps.header_rate,
+                        rates.header_rate,
+                        cur_tu_time,
+                    );
+                    update_peak(&mut max_mbps, &mut peak_timestamps.mbps, mbps, cur_tu_time);
+                    max_display_sample_rate =
+                        max_display_sample_rate.max(rates.display_sample_rate);
+                    max_decode_sample_rate = max_decode_sample_rate.max(rates.decode_sample_rate);
+                    last_tu_duration = Some(duration);
+                    temporal_units += 1;
+
+                    on_temporal_unit(&TemporalUnitStats {
+                        pts: cur_tu_time,
+                        duration,
+                        bytes: tu_size,
+                        shown_frames: counts.shown_frames,
+                        decoded_frames: counts.decoded_frames,
+                        headers: counts.headers,
+                        mbps: rates.mbps,
+                    });
+
+                    if let Some(sh) = seq.sh {
+                        // The last frame of the temporal unit ends with it.
+                        if let Some(ratio) =
+                            compressed_ratio(sh.seq_profile, frame_samples, compressed_size)
+                        {
+                            min_compressed_ratio = min_compressed_ratio.min(ratio);
+                        }
+                        compressed_size = 0;
+
+                        update_min_cr_level_indices(
+                            &mut min_cr_level_idx,
+                            display_rate,
+                            min_compressed_ratio,
+                            sh.still_picture,
+                        );
+                    }
+
+                    total_show_count += counts.shown_frames;
+
+                    counts = TemporalUnitCounts::default();
+                    tu_size = 0;
+                    min_compressed_ratio = std::f64::MAX;
+                    seen_frame_header = false;
+                    cur_tu_time = pts;
+                    last_frame_header.clear();
+
+                    obu::process_obu(&mut payload, &mut seq, &obu);
+                }
+                // Redundant and repeated frame headers belong to the frame of the original one, so only their size is counted.
+                // Redundant frame headers are not part of the compressed data either.
+                av1p::obu::OBU_FRAME_HEADER | av1p::obu::OBU_REDUNDANT_FRAME_HEADER
+                    if is_frame_header_copy(&obu, &mut payload, &mut last_frame_header)? => {}
+                av1p::obu::OBU_FRAME_HEADER | av1p::obu::OBU_FRAME => {
+                    if let Some(sh) = seq.sh {
+                        // A new frame header ends the previous frame.
+                        if let Some(ratio) =
+                            compressed_ratio(sh.seq_profile, frame_samples, compressed_size)
+                        {
+                            min_compressed_ratio = min_compressed_ratio.min(ratio);
+                        }
+                        compressed_size = 0;
+
+                        // The payload of a frame header OBU was already read to compare it with the last one.
+                        // In a frame OBU, the tile group following the frame header is the compressed data.
+                        let fh = if obu.obu_type == av1p::obu::OBU_FRAME_HEADER {
+                            av1p::obu::parse_frame_header(
+                                &mut last_frame_header.as_slice(),
+                                &sh,
+                                &mut seq.rfman,
+                            )
+                        } else {
+                            let fh =
+                                av1p::obu::parse_frame_header(&mut payload, &sh, &mut seq.rfman);
+                            compressed_size += payload.limit();
+                            fh
+                        };
+
+                        if let Some(fh) = fh {
+                            if !seen_frame_header {
+                                cur_tu_time = pts;
+                            }
+                            seen_frame_header = true;
+                            measuring = true;
+
+                            frame_samples =
+                                count_frame_header(&fh, &mut counts, &mut ref_frame_samples);
+
+                            if fh.show_frame || fh.show_existing_frame {
+                                seq.rfman.output_process(&fh);
+                            }
+
+                            // Showing an existing key frame refreshes the reference frames with it.
+                            if !fh.show_existing_frame || fh.frame_type == av1p::obu::KEY_FRAME {
+                                seq.rfman.update_process(&fh);
+                            }
+
+                            let (tiles, tile_cols) = tile_counts(&fh.tile_info);
+                            max_tile_cols = max_tile_cols.max(tile_cols);
+                            max_tiles = max_tiles.max(tiles);
+
+                            // The frame header preceding the tile lists has no tiles, so it is not an anchor frame.
+                            pending_anchor = None;
+                            if options.large_scale_tile
+                                && !fh.show_existing_frame
+                                && anchor_frames.len() < MAX_ANCHOR_FRAMES
+                            {
+                                pending_anchor = Some((tiles.max(1), frame_samples));
+                            }
+                            if obu.obu_type == av1p::obu::OBU_FRAME {
+                                anchor_frames.extend(pending_anchor.take());
+                            }
+                        }
+                    } else {
+                        return Err(ElevatorError::MissingSequenceHeader);
+                    }
+                }
+                av1p::obu::OBU_TILE_GROUP => {
+                    compressed_size += u64::from(obu.obu_size);
+                    anchor_frames.extend(pending_anchor.take());
+                }
+                av1p::obu::OBU_METADATA => {}
+                // Tile lists are decoded at the tile list rate, so their bitrate counts against the level's bitrate,
+                // and their tiles, measured as a fraction of the anchor frame each one is decoded from, against its decode rate.
+                // Large scale tile decoding is not signaled in the sequence header, so it has to be requested.
+                av1p::obu::OBU_TILE_LIST if !options.large_scale_tile => {
+                    return Err(ElevatorError::MalformedBitstream {
+                        position: pos,
+                        reason: String::from(
+                            "tile list OBUs are only decoded in large scale tile mode",
+                        ),
+                    });
+                }
+                av1p::obu::OBU_TILE_LIST => {
+                    if let Some(tile_list) = av1p::obu::parse_tile_list(&mut payload) {
+                        tile_list_count += 1;
+                        let (mut bytes, mut frames, mut samples) = (0, 0_f64, 0_f64);
+                        for entry in &tile_list.tile_list_entries {
+                            let &(tiles, anchor_samples) = anchor_frames
+                                .get(usize::from(entry.anchor_frame_idx))
+                                .ok_or_else(|| ElevatorError::MalformedBitstream {
+                                    position: pos,
+                                    reason: format!(
+                                        "tile list entry refers to anchor frame {}, but only {} were decoded",
+                                        entry.anchor_frame_idx,
+                                        anchor_frames.len()
+                                    ),
+                                })?;
+                            bytes += u64::from(entry.tile_data_size_minus_1) + 1;
+                            frames += 1.0 / f64::from(tiles);
+                            samples += anchor_samples as f64 / f64::from(tiles);
+                        }
+
+                        max_tile_list_mbps = max_tile_list_mbps
+                            .max(bytes as f64 * 8.0 * tile_list_rate / 1_000_000.0);
+                        max_tile_decode_rate = max_tile_decode_rate.max(frames * tile_list_rate);
+                        max_tile_decode_sample_rate =
+                            max_tile_decode_sample_rate.max(samples * tile_list_rate);
+                        let tiles = tile_list.tile_count_minus_1 + 1;
+                        max_tile_list_tiles =
+                            max_tile_list_tiles.max(u16::try_from(tiles).unwrap_or(std::u16::MAX));
+                        max_tile_list_cols = max_tile_list_cols
+                            .max(u16::from(tile_list.output_frame_width_in_tiles_minus_1) + 1);
+                    }
+                }
+                av1p::obu::OBU_SEQUENCE_HEADER => {
+                    // Track the start location and size of the sequence header OBU for patching.
+                    sequence_headers.push(SequenceHeaderLocation::new(
+                        pos,
+                        &obu,
+                        Some((frame.position, frame.size)),
+                    ));
+
+                    let previous = seq.sh;
+                    obu::process_obu(&mut payload, &mut seq, &obu);
+                    check_sequence_header_change(previous, seq.sh, pos)?;
+                }
+                _ => {
+                    obu::process_obu(&mut payload, &mut seq, &obu);
+                }
+            }
+
+            // Every OBU is counted once the previous temporal unit may have ended, so a temporal delimiter counts towards the one it starts.
+            tu_size += coded_obu_size(&obu) + container_overhead;
+            container_overhead = 0;
+
+            let remaining = payload.limit();
+            skip_payload(&mut reader, remaining)?;
+        }
+    }
+
+    // Do the final updates for header/display/show rates.
+
+    // The duration of the last temporal unit is unknown, so it is assumed to last as long as the previous one.
+    // Clips with a single temporal unit have no frame rate, so their frames are assumed to be shown over one second.
+    let mut display_rate = 0_f64;
+    if measuring {
+        let still_picture = matches!(seq.sh, Some(sh) if sh.still_picture);
+        let duration =
+            last_tu_duration.unwrap_or_else(|| default_tu_duration(time_scale, still_picture));
+        display_rate = window.sample_rate(counts.shown_samples, duration);
+
+        let rates = window.push(TemporalUnitCounts {
+            duration,
+            bytes: tu_size,
+            ..counts
+        });
+        // The last temporal unit has the same timestamp as when it was still the current one.
+        let mbps = push_bitrate_windows(&mut bitrate_windows, duration, tu_size);
+        update_peak(
+            &mut max_display_rate,
+            &mut peak_timestamps.display_rate,
+            rates.display_rate,
+            cur_tu_time,
+        );
+        update_peak(
+            &mut max_decode_rate,
+            &mut peak_timestamps.decode_rate,
+            rates.decode_rate,
+            cur_tu_time,
+        );
+        update_peak(
+            &mut max_header_rate,
+            &mut peak_timestamps.header_rate,
+            rates.header_rate,
+            cur_tu_time,
+        );
+        update_peak(&mut max_mbps, &mut peak_timestamps.mbps, mbps, cur_tu_time);
+        max_display_sample_rate = max_display_sample_rate.max(rates.display_sample_rate);
+        max_decode_sample_rate = max_decode_sample_rate.max(rates.decode_sample_rate);
+        temporal_units += 1;
+
+        on_temporal_unit(&TemporalUnitStats {
+            pts: cur_tu_time,
+            duration,
+            bytes: tu_size,
+            shown_frames: counts.shown_frames,
+            decoded_frames: counts.decoded_frames,
+            headers: counts.headers,
+            mbps: rates.mbps,
+        });
+    }
+
+    // A clip shorter than the measurement window is measured as if it repeated to fill the window, as a decoder
+    // has to sustain the rates of its frames for a whole second.
+    if !options.no_extrapolate {
+        if let Some(rates) = window.extrapolate() {
+            update_peak(
+                &mut max_display_rate,
+                &mut peak_timestamps.display_rate,
+                rates.display_rate,
+                cur_tu_time,
+            );
+            update_peak(
+                &mut max_decode_rate,
+                &mut peak_timestamps.decode_rate,
+                rates.decode_rate,
+                cur_tu_time,
+            );
+            update_peak(
+                &mut max_header_rate,
+                &mut peak_timestamps.header_rate,
+                rates.header_rate,
+                cur_tu_time,
+            );
+            max_display_sample_rate = max_display_sample_rate.max(rates.display_sample_rate);
+            max_decode_sample_rate = max_decode_sample_rate.max(rates.decode_sample_rate);
+        }
+        if let Some(mbps) = extrapolate_bitrate_windows(&mut bitrate_windows) {
+            update_peak(&mut max_mbps, &mut peak_timestamps.mbps, mbps, cur_tu_time);
+        }
+    }
+
+    // Tile decode rate is restricted to the level's maximum decode rate halved, so double the input to achieve that effect.
+    max_decode_rate = max_decode_rate.max(max_tile_decode_rate * 2.0);
+    max_decode_sample_rate = max_decode_sample_rate.max(max_tile_decode_sample_rate * 2.0);
+    max_mbps = max_mbps.max(max_tile_list_mbps);
+
+    let sh = seq.sh.ok_or(ElevatorError::MissingSequenceHeader)?; // sequence header
+    let tier = options.tier.unwrap_or(if sh.op[op_index].seq_tier == 0 {
+        Tier::Main
+    } else {
+        Tier::High
+    });
+
+    // The last frame of the stream ends with it.
+    if let Some(ratio) = compressed_ratio(sh.seq_profile, frame_samples, compressed_size) {
+        min_compressed_ratio = min_compressed_ratio.min(ratio);
+    }
+    update_min_cr_level_indices(
+        &mut min_cr_level_idx,
+        display_rate,
+        min_compressed_ratio,
+        sh.still_picture,
+    );
+
+    total_show_count += counts.shown_frames;
+    let (min_frame_rate, avg_frame_rate, max_frame_rate) = window.frame_rates();
+
+    // Generate a SequenceContext using the parsed data.
+    // The picture size is the maximum frame size, but the rates count the luma samples of each frame.
+    let context = SequenceContext {
+        tier,
+        pic_size: (sh.max_frame_width as u16, sh.max_frame_height as u16), // (width, height)
+        display_rate: max_display_sample_rate.ceil() as u64,
+        decode_rate: max_decode_sample_rate.ceil() as u64,
+        header_rate: max_header_rate.ceil() as u16,
+        mbps: max_mbps,
+        tiles: max_tiles.max(max_tile_list_tiles),
+        tile_cols: max_tile_cols.max(max_tile_list_cols),
+    };
+
+    let level_in_tier = |tier| {
+        let min_cr_level_idx = min_cr_level_idx[tier as usize];
+
+        calculate_level_with_constraint(&SequenceContext { tier, ..context }).map(|result| {
+            match result {
+                (level, _) if min_cr_level_idx > usize::from(level.0) => {
+                    (LEVELS[min_cr_level_idx], Some("min_compressed_ratio"))
+                }
+                result => result,
+            }
+        })
+    };
+    let (level, binding_constraint) = level_in_tier(tier)?;
+
+    // A Main tier stream that only exceeds the Main tier bitrate may fit a lower level in High tier.
+    let high_tier_level = match level_in_tier(Tier::High)? {
+        (high_tier_level, _) if high_tier_level.0 < level.0 => Some(high_tier_level),
+        _ => None,
+    };
+
+    // The tile lists count towards the bitrate, decode rate and tile limits along with the frames.
+    let tile_lists = if tile_list_count > 0 {
+        Some(TileListRates {
+            count: tile_list_count,
+            mbps: max_tile_list_mbps,
+            decode_rate: max_tile_decode_rate,
+            decode_sample_rate: max_tile_decode_sample_rate,
+            max_tiles: max_tile_list_tiles,
+            max_tile_cols: max_tile_list_cols,
+            drives_level: match binding_constraint {
+                Some("max_mbps") => max_tile_list_mbps >= context.mbps,
+                Some("max_decode_rate") => {
+                    (max_tile_decode_sample_rate * 2.0).ceil() as u64 >= context.decode_rate
+                }
+                Some("max_tiles") => max_tile_list_tiles >= context.tiles,
+                Some("max_tile_cols") => max_tile_list_cols >= context.tile_cols,
+                _ => false,
+            },
+        })
+    } else {
+        None
+    };
+
+    Ok((
+        OperatingPoint {
+            idc: op_idc,
+            context,
+            level,
+            binding_constraint,
+            high_tier_level,
+            old_level: LEVELS[usize::from(sh.op[op_index].seq_level_idx)],
+            min_cr_level: LEVELS[min_cr_level_idx[tier as usize]],
+            total_show_count,
+            max_header_rate,
+            max_display_rate,
+            max_decode_rate,
+            min_frame_rate,
+            avg_frame_rate,
+            max_frame_rate,
+            max_mbps,
+            peak_timestamps,
+            peak_bitrates: bitrate_windows
+                .iter()
+                .zip(&options.bitrate_windows)
+                .map(|(bitrate_window, &window)| PeakBitrate {
+                    window,
+                    mbps: bitrate_window.peak_mbps(),
+                })
+                .collect(),
+            max_tiles: u32::from(max_tiles),
+            max_tile_cols: u32::from(max_tile_cols),
+            operating_parameters: None,
+            tile_lists,
+        },
+        sh,
+        Coverage {
+            frames: container_frames,
+            truncation: truncated_at_position.map(|position| Truncation {
+                position,
+                temporal_units,
+                timestamp: cur_tu_time,
+            }),
+            timestamp_jumps,
+        },
+    ))
+}
+
+/// Part of the stream modified by a patch
+pub enum PatchTarget {
+    /// A sequence header OBU, with the bit offset of the level of each of its operating points
+    SequenceHeader {
+        index: usize,
+        level_bit_offsets: Vec<usize>,
+    },
+    /// The codec configuration record of an MP4 or WebM file
+    ConfigRecord,
+    /// The size of an IVF frame holding a sequence header that grew or shrank
+    FrameSize,
+}
+
+/// Bytes to substitute in the input stream to change its level
+pub struct Patch {
+    pub target: PatchTarget,
+    /// Absolute position of the first patched byte
+    pub position: u64,
+    /// Number of bytes of the input stream replaced by the patch, which differs from the number of
+    /// patched bytes when a sequence header grows or shrinks
+    pub replaced_len: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// Prepares the patches setting the level of each operating point of the stream analyzed in `analysis`,
+/// and the tier of every operating point if `tier` is given.
+/// If `in_place` is set, the patches keep the size of the stream, so that they can be applied with [`apply_patches`].
+/// The patches are sorted by position and do not overlap.
+pub fn prepare_patches<R: Read + Seek>(
+    mut reader: R,
+    analysis: &Analysis,
+    levels: &[Level],
+    tier: Option<Tier>,
+    in_place: bool,
+) -> Result<Vec<Patch>, ElevatorError> {
+    let sh = &analysis.sequence_header;
+    let levels = levels.iter().map(|level| level.0).collect::<Vec<_>>();
+
+    assert_eq!(
+        analysis.operating_points.len(),
+        levels.len(),
+        "one level must be given per operating point"
+    );
+
+    for (op, &level) in analysis.operating_points.iter().zip(&levels) {
+        // Adding or removing the tier bit shifts the rest of the sequence header by one bit,
+        // which cannot be absorbed safely when the enclosing element size is fixed.
+        if analysis.fixed_size_headers
+            && !sh.reduced_still_picture_header
+            && (op.old_level.0 > 7) != (level > 7)
+        {
+            return Err(ElevatorError::Unpatchable(format!(
+                "level {} cannot be changed to {} in place, since the size of the sequence header would change",
+                op.old_level, LEVELS[usize::from(level)]
+            )));
+        }
+    }
+
+    let mut patches = Vec::new();
+    let mut frame_growth = BTreeMap::<u64, (u32, i64)>::new(); // size and growth of container frames, by position
+    let mut first_input = None; // payload of the first sequence header, which the others must repeat
+
+    for (i, loc) in analysis.sequence_headers.iter().enumerate() {
+        let mut input = vec![0; loc.size as usize];
+        reader.seek(SeekFrom::Start(loc.position))?;
+        reader.read_exact(&mut input)?;
+
+        // Patching sequence headers that differ could mix up their configurations.
+        match &first_input {
+            None => first_input = Some((loc.position, input.clone())),
+            Some((first_pos, first)) if *first != input => {
+                return Err(ElevatorError::Unpatchable(format!(
+                    "the sequence headers at bytes {} and {} differ",
+                    first_pos, loc.position
+                )))
+            }
+            Some(_) => {}
+        }
+
+        // Locate the level of each operating point by parsing the sequence header up to it.
+        let fields = obu::parse_level_fields(&input).map_err(|err| match err {
+            ElevatorError::Io(err) => ElevatorError::MalformedBitstream {
+                position: loc.position,
+                reason: err.to_string(),
+            },
+            err => err,
+        })?;
+
+        // The operating points must match the ones analyzed, which were read from the last sequence header.
+        let same_operating_points = fields.len() == levels.len()
+            && (fields.len() == 1
+                || fields
+                    .iter()
+                    .zip(&analysis.operating_points)
+                    .all(|(field, op)| field.idc == op.idc));
+
+        if !same_operating_points {
+            return Err(ElevatorError::Unpatchable(String::from(
+                "the sequence headers signal different operating points",
+            )));
+        }
+
+        for (field, op) in fields.iter().zip(&analysis.operating_points) {
+            // Ensure that the bits read from the input file correspond to the level parsed earlier.
+            if field.level != op.old_level.0 {
+                return Err(ElevatorError::LevelMismatch {
+                    position: loc.position,
+                    expected: op.old_level.0,
+                    found: field.level,
+                });
+            }
+        }
+
+        let mut output = obu::rewrite_levels(
+            &input,
+            &fields,
+            &levels,
+            tier.map(|tier| tier == Tier::High),
+            sh.reduced_still_picture_header,
+        )?;
+
+        let resizable = !in_place
+            && analysis.frame_size_field != FrameSizeField::Unsupported
+            && loc.frame.is_some();
+
+        // A payload that shrank can keep its size with zero padding, which is valid in the trailing bits.
+        if output.len() < input.len() && !resizable {
+            output.resize(input.len(), 0);
+        }
+
+        let target = PatchTarget::SequenceHeader {
+            index: i,
+            level_bit_offsets: fields.iter().map(|field| field.bit_offset).collect(),
+        };
+
+        if output.len() == input.len() {
+            patches.push(Patch {
+                target,
+                position: loc.position,
+                replaced_len: input.len(),
+                bytes: output,
+            });
+
+            continue;
+        }
+
+        // The payload size changed, so the OBU size field and the enclosing frame size must be updated as well.
+        let frame = match loc.frame {
+            Some(frame) if resizable => frame,
+            _ if in_place => {
+                return Err(ElevatorError::Unpatchable(format!(
+                    "the sequence header at byte {} would grow, so the stream cannot be patched in place",
+                    loc.position
+                )))
+            }
+            _ => {
+                return Err(ElevatorError::Unpatchable(format!(
+                    "the sequence header at byte {} would grow, which is only supported in IVF and raw OBU streams",
+                    loc.position
+                )))
+            }
+        };
+
+        let header_pos = loc.position - u64::from(loc.header_len);
+        let mut header = vec![0; loc.header_len as usize];
+        reader.seek(SeekFrom::Start(header_pos))?;
+        reader.read_exact(&mut header)?;
+
+        // An OBU without a size field extends to the end of its frame, whose size alone then changes.
+        let mut bytes = if header[0] & 0b0000_0010 != 0 {
+            obu::resize_obu_header(&header, output.len() as u32)?
+        } else {
+            header
+        };
+        bytes.extend(output);
+        let replaced_len = (loc.header_len + loc.size) as usize;
+
+        if analysis.frame_size_field == FrameSizeField::Ivf {
+            frame_growth.entry(frame.0).or_insert((frame.1, 0)).1 +=
+                bytes.len() as i64 - replaced_len as i64;
+        }
+
+        patches.push(Patch {
+            target,
+            position: header_pos,
+            replaced_len,
+            bytes,
+        });
+    }
+
+    for (frame_pos, (frame_size, growth)) in frame_growth {
+        let frame_size = u32::try_from(i64::from(frame_size) + growth).map_err(|_| {
+            ElevatorError::Unpatchable(String::from("the IVF frame size would overflow"))
+        })?;
+
+        patches.push(Patch {
+            target: PatchTarget::FrameSize,
+            position: frame_pos - IVF_FRAME_HEADER_SIZE,
+            replaced_len: 4,
+            bytes: frame_size.to_le_bytes().to_vec(),
+        });
+    }
+
+    if let Some(record_pos) = analysis.config_record {
+        // High tier can only be signaled for levels 4.0 and above.
+        let high_tier = match tier {
+            Some(tier) => tier == Tier::High,
+            None => sh.op[0].seq_tier != 0,
+        };
+        let tier = if levels[0] > 7 && high_tier { 1 } else { 0 };
+
+        patches.push(Patch {
+            target: PatchTarget::ConfigRecord,
+            position: record_pos + 1,
+            replaced_len: 2,
+            bytes: mp4::patch_config_level(&mut reader, record_pos, levels[0], tier)?.to_vec(),
+        });
+    }
+
+    patches.sort_by_key(|patch| patch.position);
+
+    Ok(patches)
+}
+
+/// Writes the patches over a copy of the input stream.
+/// This fails if a patch changes the size of the stream.
+pub fn apply_patches<W: Write + Seek>(mut writer: W, patches: &[Patch]) -> io::Result<()> {
+    if patches
+        .iter()
+        .any(|patch| patch.bytes.len() != patch.replaced_len)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the size of the stream changes, so it cannot be patched in place",
+        ));
+    }
+
+    for patch in patches {
+        writer.seek(SeekFrom::Start(patch.position))?;
+        writer.write_all(&patch.bytes)?;
+    }
+
+    writer.flush()
+}
+
+/// Checks that a stream patched with [`prepare_patches`] signals the given levels and tier, and that the other fields of
+/// its sequence header are unchanged, by parsing its sequence headers (and codec configuration record, if any) again.
+pub fn verify_patches<R: Read + Seek>(
+    mut reader: R,
+    analysis: &Analysis,
+    levels: &[Level],
+    tier: Option<Tier>,
+) -> Result<(), ElevatorError> {
+    let sh = &analysis.sequence_header;
+
+    // The tier is only coded for levels 4.0 and above.
+    let expected_tier = |i: usize, level: u8| {
+        if level > 7 && !sh.reduced_still_picture_header {
+            Some(match tier {
+                Some(tier) => tier == Tier::High,
+                None => sh.op[i].seq_tier != 0,
+            })
+        } else {
+            None
+        }
+    };
+
+    // Locate the sequence headers again, since they move when one of them grows.
+    reader.seek(SeekFrom::Start(0))?;
+    let fmt = probe_fileformat(&mut reader)?;
+    let options = AnalysisOptions {
+        time_scale: Some(analysis.metadata.time_scale),
+        allow_truncated: analysis.truncation.is_some(),
+        ..AnalysisOptions::default()
+    };
+    let patched = analyze_headers(&mut reader, fmt, &options)?;
+
+    if patched.sequence_headers.len() != analysis.sequence_headers.len() {
+        return Err(ElevatorError::VerificationFailed {
+            position: 0,
+            reason: format!(
+                "found {} sequence headers instead of {}",
+                patched.sequence_headers.len(),
+                analysis.sequence_headers.len()
+            ),
+        });
+    }
+
+    for loc in &patched.sequence_headers {
+        let mut input = vec![0; loc.size as usize];
+        reader.seek(SeekFrom::Start(loc.position))?;
+        reader.read_exact(&mut input)?;
+
+        let fields =
+            obu::parse_level_fields(&input).map_err(|err| ElevatorError::VerificationFailed {
+                position: loc.position,
+                reason: err.to_string(),
+            })?;
+
+        if fields.len() != levels.len() {
+            return Err(ElevatorError::VerificationFailed {
+                position: loc.position,
+                reason: format!(
+                    "found {} operating points instead of {}",
+                    fields.len(),
+                    levels.len()
+                ),
+            });
+        }
+
+        for (i, (field, level)) in fields.iter().zip(levels).enumerate() {
+            if field.level != level.0 || field.tier != expected_tier(i, level.0) {
+                return Err(ElevatorError::VerificationFailed {
+                    position: loc.position,
+                    reason: format!(
+                        "operating point {} signals level {} instead of {}",
+                        i,
+                        LEVELS[usize::from(field.level)],
+                        level
+                    ),
+                });
+            }
+        }
+    }
+
+    // Only the levels and tiers may differ from the original sequence header.
+    if let Some(field) = changed_sequence_header_field(sh, &patched.sequence_header) {
+        return Err(ElevatorError::VerificationFailed {
+            position: patched
+                .sequence_headers
+                .last()
+                .map_or(0, |loc| loc.position),
+            reason: format!("the {} of the sequence header changed", field),
+        });
+    }
+
+    if let Some(record_pos) = patched.config_record {
+        let (record_level, record_tier) = mp4::read_config_level(&mut reader, record_pos)?;
+        let expected_record_tier = expected_tier(0, levels[0].0).unwrap_or(false);
+
+        if record_level != levels[0].0 || (record_tier != 0) != expected_record_tier {
+            return Err(ElevatorError::VerificationFailed {
+                position: record_pos,
+                reason: format!(
+                    "the codec configuration record declares level {} instead of {}",
+                    LEVELS[usize::from(record_level)],
+                    levels[0]
+                ),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Sets the level of every operating point of the stream analyzed in `analysis`, patching `file` in place.
+pub fn patch_level<F: Read + Write + Seek>(
+    mut file: F,
+    analysis: &Analysis,
+    target: Level,
+) -> Result<(), ElevatorError> {
+    let levels = vec![target; analysis.operating_points.len()];
+    let patches = prepare_patches(&mut file, analysis, &levels, None, true)?;
+    apply_patches(&mut file, &patches)?;
+
+    verify_patches(&mut file, analysis, &levels, None)
+}
+
+/// Copies the input stream to `writer`, substituting the patched bytes on the way.
+pub fn stream_patches<R: Read + Seek, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    patches: &[Patch],
+) -> io::Result<()> {
+    let mut position = 0;
+    reader.seek(SeekFrom::Start(0))?;
+
+    for patch in patches {
+        io::copy(
+            &mut Read::by_ref(&mut reader).take(patch.position - position),
+            &mut writer,
+        )?;
+        writer.write_all(&patch.bytes)?;
+
+        position = patch.position + patch.replaced_len as u64;
+        reader.seek(SeekFrom::Start(position))?;
+    }
+
+    io::copy(&mut reader, &mut writer)?;
+    writer.flush()
+}
+
+/// Rewrites the OBUs of an IVF frame starting at `position`, setting the levels, and the tier if given, of its
+/// sequence headers and recalculating their OBU sizes. Other OBUs are copied unchanged.
+fn remux_frame(
+    frame: &[u8],
+    position: u64,
+    levels: &[u8],
+    tier: Option<bool>,
+    reduced_still_picture_header: bool,
+) -> Result<Vec<u8>, ElevatorError> {
+    let mut output = Vec::with_capacity(frame.len() + 1);
+    let mut offset = 0;
+
+    while offset < frame.len() {
+        let remaining = &frame[offset..];
+        let obu_pos = position + offset as u64;
+        let obu =
+            av1p::obu::parse_obu_header(&mut io::Cursor::new(remaining), remaining.len() as u32)
+                .map_err(|_| ElevatorError::TruncatedObu { position: obu_pos })?;
+        let header_len = obu.header_len as usize;
+        let obu_end = header_len + obu.obu_size as usize;
+
+        if obu_end > remaining.len() {
+            return Err(ElevatorError::TruncatedObu { position: obu_pos });
+        }
+
+        let (header, payload) = (&remaining[..header_len], &remaining[header_len..obu_end]);
+
+        if obu.obu_type == av1p::obu::OBU_SEQUENCE_HEADER {
+            let fields = obu::parse_level_fields(payload).map_err(|err| match err {
+                ElevatorError::Io(err) => ElevatorError::MalformedBitstream {
+                    position: obu_pos + header_len as u64,
+                    reason: err.to_string(),
+                },
+                err => err,
+            })?;
+
+            if fields.len() != levels.len() {
+                return Err(ElevatorError::Unpatchable(String::from(
+                    "the sequence headers signal different operating points",
+                )));
+            }
+
+            let payload =
+                obu::rewrite_levels(payload, &fields, levels, tier, reduced_still_picture_header)?;
+
+            // The last OBU of a frame may omit its size field, since the frame size delimits it.
+            if obu.obu_has_size_field {
+                output.extend(obu::resize_obu_header(header, payload.len() as u32)?);
+            } else {
+                output.extend_from_slice(header);
+            }
+            output.extend(payload);
+        } else {
+            output.extend_from_slice(&remaining[..obu_end]);
+        }
+
+        offset += obu_end;
+    }
+
+    Ok(output)
+}
+
+/// Writes the IVF stream analyzed in `analysis` to `writer` as a new IVF file, setting the level of each operating
+/// point, and the tier of every operating point if `tier` is given.
+///
+/// Unlike [`stream_patches`], which substitutes bytes in a copy of the input, every frame is rebuilt from its OBUs
+/// with its size recalculated, so the sequence headers can grow or shrink regardless of how their sizes are coded.
+/// A frame cut short by the end of a truncated file is copied unchanged.
+pub fn remux_ivf<R: Read + Seek, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    analysis: &Analysis,
+    levels: &[Level],
+    tier: Option<Tier>,
+) -> Result<(), ElevatorError> {
+    if analysis.frame_size_field != FrameSizeField::Ivf {
+        return Err(ElevatorError::UnsupportedFormat(String::from(
+            "only IVF input can be rewritten as a new IVF file",
+        )));
+    }
+
+    assert_eq!(
+        analysis.operating_points.len(),
+        levels.len(),
+        "one level must be given per operating point"
+    );
+
+    let levels = levels.iter().map(|level| level.0).collect::<Vec<_>>();
+    let tier = tier.map(|tier| tier == Tier::High);
+    let reduced_still_picture_header = analysis.sequence_header.reduced_still_picture_header;
+
+    // The file header is unchanged, since the frames keep their count and timestamps.
+    let mut file_header = [0; av1p::ivf::IVF_HEADER_SIZE];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut file_header)?;
+    writer.write_all(&file_header)?;
+
+    let mut position = av1p::ivf::IVF_HEADER_SIZE as u64;
+
+    loop {
+        let mut frame_header = Vec::with_capacity(IVF_FRAME_HEADER_SIZE as usize);
+        Read::by_ref(&mut reader)
+            .take(IVF_FRAME_HEADER_SIZE)
+            .read_to_end(&mut frame_header)?;
+
+        if frame_header.len() < IVF_FRAME_HEADER_SIZE as usize {
+            writer.write_all(&frame_header)?;
+            break;
+        }
+
+        let size = u32::from_le_bytes([
+            frame_header[0],
+            frame_header[1],
+            frame_header[2],
+            frame_header[3],
+        ]);
+        // The frame is read without preallocating it, since its size is not trusted yet.
+        let mut frame = Vec::new();
+        Read::by_ref(&mut reader)
+            .take(u64::from(size))
+            .read_to_end(&mut frame)?;
+
+        if frame.len() < size as usize {
+            writer.write_all(&frame_header)?;
+            writer.write_all(&frame)?;
+            break;
+        }
+
+        position += IVF_FRAME_HEADER_SIZE;
+        let output = remux_frame(
+            &frame,
+            position,
+            &levels,
+            tier,
+            reduced_still_picture_header,
+        )?;
+        position += u64::from(size);
+
+        let output_size = u32::try_from(output.len()).map_err(|_| {
+            ElevatorError::Unpatchable(String::from("the IVF frame size would overflow"))
+        })?;
+        frame_header[..4].copy_from_slice(&output_size.to_le_bytes());
+        writer.write_all(&frame_header)?;
+        writer.write_all(&output)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Packs a string of bits into bytes, ignoring spaces and padding the last byte with zero bits.
+    fn pack_bits(bits: &str) -> Vec<u8> {
+        let bits = bits.replace(' ', "");
+        let mut data = vec![0; bits.len().div_ceil(8)];
+        for (i, bit) in bits.chars().enumerate() {
+            if bit == '1' {
+                data[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        data
+    }
+
+    /// Formats a syntax element of `n` bits.
+    fn bits(value: u32, n: usize) -> String {
+        format!("{:01$b}", value, n)
+    }
+
+    /// Builds an OBU with a size field, and an extension header if it belongs to a (temporal, spatial) layer.
+    fn obu(obu_type: u8, layer: Option<(u8, u8)>, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![obu_type << 3 | u8::from(layer.is_some()) << 2 | 0b10];
+        data.extend(layer.map(|(temporal_id, spatial_id)| temporal_id << 5 | spatial_id << 3));
+
+        let mut size = payload.len();
+        while size >= 0x80 {
+            data.push((size & 0x7F) as u8 | 0x80);
+            size >>= 7;
+        }
+        data.push(size as u8);
+
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// Frame of a test stream, coded with the tools that `TestStream` disables in its sequence header
+    #[derive(Clone, Copy)]
+    struct TestFrame {
+        frame_type: u8,
+        show_frame: bool,
+        refresh_frame_flags: u8,
+        /// Size coded with frame_size_override_flag, instead of the maximum frame size
+        size: Option<(u32, u32)>,
+        /// Base 2 logarithms of the numbers of tile columns and rows, in uniform tile spacing
+        tiles_log2: (u32, u32),
+    }
+
+    impl TestFrame {
+        /// A key frame shown as it is decoded, which refreshes every reference frame
+        fn key() -> Self {
+            TestFrame {
+                frame_type: av1p::obu::KEY_FRAME,
+                show_frame: true,
+                refresh_frame_flags: 0xFF,
+                size: None,
+                tiles_log2: (0, 0),
+            }
+        }
+
+        fn inter(show_frame: bool, refresh_frame_flags: u8) -> Self {
+            TestFrame {
+                frame_type: av1p::obu::INTER_FRAME,
+                show_frame,
+                refresh_frame_flags,
+                ..TestFrame::key()
+            }
+        }
+
+        /// Provides the bits of the uncompressed header of the frame, in a sequence with the given maximum frame size.
+        fn header_bits(&self, max_size: (u32, u32)) -> String {
+            let is_key = self.frame_type == av1p::obu::KEY_FRAME;
+            let shown_key = is_key && self.show_frame;
+            let mut header = vec![
+                String::from("0"), // show_existing_frame
+                bits(u32::from(self.frame_type), 2),
+                bits(u32::from(self.show_frame), 1),
+            ];
+            if !self.show_frame {
+                header.push(String::from("1")); // showable_frame
+            }
+            if !shown_key {
+                header.push(String::from("0")); // error_resilient_mode
+            }
+            header.push(String::from("0")); // disable_cdf_update
+            header.push(bits(u32::from(self.size.is_some()), 1)); // frame_size_override_flag
+            if !is_key {
+                header.push(String::from("111")); // primary_ref_frame: none
+            }
+            if !shown_key {
+                header.push(bits(u32::from(self.refresh_frame_flags), 8));
+            }
+            if !is_key {
+                header.push("000".repeat(7)); // ref_frame_idx
+                if self.size.is_some() {
+                    header.push("0".repeat(7)); // found_ref
+                }
+            }
+            let (width, height) = self.size.unwrap_or(max_size);
+            if let Some((width, height)) = self.size {
+                header.push(bits(width - 1, 16));
+                header.push(bits(height - 1, 16));
+            }
+            header.push(String::from("0")); // render_and_frame_size_different
+            if !is_key {
+                // allow_high_precision_mv, is_filter_switchable, is_motion_mode_switchable
+                header.push(String::from("0 1 0"));
+            }
+            header.push(String::from("0")); // disable_frame_end_update_cdf
+
+            // tile_info() with 64x64 superblocks, as in the specification
+            let tile_log2 =
+                |block: u32, target: u32| (0_u32..).find(|k| block << k >= target).unwrap();
+            let sb_cols = (2 * ((width + 7) >> 3) + 15) >> 4;
+            let sb_rows = (2 * ((height + 7) >> 3) + 15) >> 4;
+            let min_log2_tile_cols = tile_log2(64, sb_cols);
+            let max_log2_tile_cols = tile_log2(1, sb_cols.min(64));
+            let max_log2_tile_rows = tile_log2(1, sb_rows.min(64));
+            let min_log2_tiles = min_log2_tile_cols.max(tile_log2(2304, sb_rows * sb_cols));
+            let (cols_log2, rows_log2) = self.tiles_log2;
+            let min_log2_tile_rows = min_log2_tiles.saturating_sub(cols_log2);
+            header.push(String::from("1")); // uniform_tile_spacing_flag
+            header.push("1".repeat((cols_log2 - min_log2_tile_cols) as usize));
+            if cols_log2 < max_log2_tile_cols {
+                header.push(String::from("0"));
+            }
+            header.push("1".repeat((rows_log2 - min_log2_tile_rows) as usize));
+            if rows_log2 < max_log2_tile_rows {
+                header.push(String::from("0"));
+            }
+            if cols_log2 > 0 || rows_log2 > 0 {
+                header.push(bits(0, (cols_log2 + rows_log2) as usize)); // context_update_tile_id
+                header.push(String::from("11")); // tile_size_bytes_minus_1
+            }
+
+            // base_q_idx, no delta quantizers, quantizer matrices, segmentation, or delta q
+            header.push(String::from("00100000 0 0 0 0 0 0"));
+            // loop_filter_params(), with a null filter level
+            header.push(String::from("000000 000000 000 0"));
+            header.push(String::from("0")); // tx_mode_select
+            if !is_key {
+                header.push(String::from("0")); // reference_select
+            }
+            header.push(String::from("0")); // reduced_tx_set
+            if !is_key {
+                header.push("0".repeat(7)); // is_global
+            }
+            header.concat()
+        }
+    }
+
+    /// Raw OBU stream under construction, whose sequence header signals profile 0 with 8-bit 4:2:0 samples,
+    /// and disables the tools that `TestFrame` does not code, like order hints, CDEF and loop restoration
+    struct TestStream {
+        data: Vec<u8>,
+        max_size: (u32, u32),
+        sequence_header: Option<Vec<u8>>,
+    }
+
+    impl TestStream {
+        /// Starts a stream with a sequence header signaling the layers and level index of each operating point,
+        /// in Main tier, which is written at the start of the first temporal unit.
+        fn new(operating_points: &[(u16, u8)], max_size: (u32, u32), still_picture: bool) -> Self {
+            let mut header = vec![
+                String::from("000"), // seq_profile
+                bits(u32::from(still_picture), 1),
+                String::from("0 0 0"), // reduced_still_picture_header, timing info, initial display delays
+                bits(operating_points.len() as u32 - 1, 5),
+            ];
+            for &(idc, level) in operating_points {
+                header.push(bits(u32::from(idc), 12));
+                header.push(bits(u32::from(level), 5));
+                if level > 7 {
+                    header.push(String::from("0")); // seq_tier
+                }
+            }
+            header.push(String::from("1111 1111")); // frame width and height bits
+            header.push(bits(max_size.0 - 1, 16));
+            header.push(bits(max_size.1 - 1, 16));
+            // frame ids, 128x128 superblocks, intra and inter tools, order hints, screen content tools, superres,
+            // CDEF and loop restoration are all disabled
+            header.push("0".repeat(14));
+            // color_config(): 8 bits, no color description, limited range, chroma sample position, separate_uv_delta_q
+            header.push(String::from("0 0 0 0 00 0"));
+            header.push(String::from("0 1")); // film_grain_params_present, trailing one bit
+
+            TestStream {
+                data: Vec::new(),
+                max_size,
+                sequence_header: Some(obu(
+                    av1p::obu::OBU_SEQUENCE_HEADER,
+                    None,
+                    &pack_bits(&header.concat()),
+                )),
+            }
+        }
+
+        /// Starts a temporal unit with a temporal delimiter.
+        fn temporal_unit(&mut self) -> &mut Self {
+            self.data
+                .extend(obu(av1p::obu::OBU_TEMPORAL_DELIMITER, None, &[]));
+            self.data
+                .extend(self.sequence_header.take().unwrap_or_default());
+            self
+        }
+
+        /// Adds a frame OBU whose tile group holds `tile_bytes` bytes of tile data.
+        fn frame(
+            &mut self,
+            frame: TestFrame,
+            layer: Option<(u8, u8)>,
+            tile_bytes: usize,
+        ) -> &mut Self {
+            let mut payload = pack_bits(&frame.header_bits(self.max_size));
+            if frame.tiles_log2 != (0, 0) {
+                payload.push(0); // tile_start_and_end_present_flag
+            }
+            payload.resize(payload.len() + tile_bytes, 0);
+            self.data.extend(obu(av1p::obu::OBU_FRAME, layer, &payload));
+            self
+        }
+
+        /// Adds a frame header OBU, or a redundant copy of it.
+        fn frame_header(&mut self, obu_type: u8, frame: TestFrame) -> &mut Self {
+            let header = frame.header_bits(self.max_size) + "1"; // trailing one bit
+            self.data.extend(obu(obu_type, None, &pack_bits(&header)));
+            self
+        }
+
+        /// Adds a frame header OBU showing the frame of a reference slot.
+        fn show_existing_frame(&mut self, frame_to_show_map_idx: u32) -> &mut Self {
+            let header = format!("1 {} 1", bits(frame_to_show_map_idx, 3));
+            self.data
+                .extend(obu(av1p::obu::OBU_FRAME_HEADER, None, &pack_bits(&header)));
+            self
+        }
+
+        /// Adds a tile group OBU of `bytes` bytes, holding the single tile of its frame.
+        fn tile_group(&mut self, bytes: usize) -> &mut Self {
+            self.data
+                .extend(obu(av1p::obu::OBU_TILE_GROUP, None, &vec![0; bytes]));
+            self
+        }
+
+        /// Adds a tile list OBU outputting a frame `output_width_in_tiles` tiles wide, with an entry per
+        /// (anchor frame index, tile data size in bytes) pair.
+        fn tile_list(&mut self, output_width_in_tiles: u32, entries: &[(u32, usize)]) -> &mut Self {
+            let header = format!(
+                "{} {} {}",
+                bits(output_width_in_tiles - 1, 8),
+                bits(0, 8),
+                bits(entries.len() as u32 - 1, 16)
+            );
+            let mut payload = pack_bits(&header);
+            for &(anchor_frame_idx, bytes) in entries {
+                let entry = format!(
+                    "{} {} {}",
+                    bits(anchor_frame_idx, 8),
+                    bits(0, 16), // anchor_tile_row, anchor_tile_col
+                    bits(bytes as u32 - 1, 16)
+                );
+                payload.extend(pack_bits(&entry));
+                payload.resize(payload.len() + bytes, 0);
+            }
+            self.data
+                .extend(obu(av1p::obu::OBU_TILE_LIST, None, &payload));
+            self
+        }
+
+        /// Analyzes the stream as raw OBUs, at 1 temporal unit per unit of `time_scale`.
+        fn analyze(&self, time_scale: u32, options: &AnalysisOptions) -> Analysis {
+            let options = AnalysisOptions {
+                time_scale: Some((time_scale, 1)),
+                ..options.clone()
+            };
+            analyze(Cursor::new(&self.data), FileFormat::Bitstream, &options).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_remux_frame_resizes_sequence_header() {
+        // A temporal delimiter, a sequence header at level 3.0, and a padding OBU
+        let sequence_header = [
+            0x00, 0x00, 0x00, 0x25, 0x57, 0x7F, 0x86, 0xEF, 0xFE, 0xE6, 0x01,
+        ];
+        let mut frame = vec![0x12, 0x00, 0x0A, 0x0B];
+        frame.extend_from_slice(&sequence_header);
+        frame.extend_from_slice(&[0x7A, 0x02, 0xAA, 0xBB]);
+
+        // Level 4.1 adds a tier bit, which moves the trailing one bit to a new byte.
+        let output = remux_frame(&frame, 44, &[9], None, false).unwrap();
+        let mut expected = vec![0x12, 0x00, 0x0A, 0x0C];
+        expected.extend_from_slice(&[
+            0x00, 0x00, 0x00, 0x4A, 0xAB, 0xBF, 0xC3, 0x77, 0xFF, 0x73, 0x00, 0x80,
+        ]);
+        expected.extend_from_slice(&[0x7A, 0x02, 0xAA, 0xBB]);
+        assert_eq!(expected, output);
+
+        // Without a size field, the sequence header can still grow as the last OBU of the frame.
+        let mut frame = vec![0x12, 0x00, 0x08];
+        frame.extend_from_slice(&sequence_header);
+        let output = remux_frame(&frame, 44, &[9], None, false).unwrap();
+        assert_eq!(&[0x12, 0x00, 0x08], &output[..3]);
+        assert_eq!(&expected[4..16], &output[3..]);
+
+        // A padding OBU declaring more bytes than the frame holds
+        assert!(matches!(
+            remux_frame(&[0x12, 0x00, 0x7A, 0x05, 0xAA], 44, &[9], None, false),
+            Err(ElevatorError::TruncatedObu { position: 46 })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_obu() {
+        // A temporal delimiter OBU declaring a 5-byte payload, in a 3-byte container frame
+        let mut sz = 3;
+        let result = next_obu_header(&mut Cursor::new(vec![0x12, 0x05, 0xAA]), &mut sz, false);
+        assert!(matches!(
+            result,
+            Err(ElevatorError::TruncatedObu { position: 0 })
+        ));
+
+        // The same OBU with a clobbered size field
+        let mut sz = 8;
+        let data = vec![0x12, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0xAA, 0xAA];
+        assert!(next_obu_header(&mut Cursor::new(data), &mut sz, false).is_err());
+
+        // A padding OBU with an extension header but no size field, in a 1-byte container frame
+        let mut sz = 1;
+        let result = next_obu_header(&mut Cursor::new(vec![0x7C, 0x00]), &mut sz, false);
+        assert!(matches!(
+            result,
+            Err(ElevatorError::TruncatedObu { position: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_obu_demuxer() {
+        // A temporal unit holding a padding OBU, followed by an empty temporal unit
+        let mut reader = Cursor::new(vec![0x12, 0x00, 0x7A, 0x01, 0xAB, 0x12, 0x00]);
+        let mut demuxer = ObuDemuxer::new(7);
+
+        let frame = demuxer.next_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            (frame.position, frame.size, frame.display_timestamp),
+            (0, 5, 0)
+        );
+
+        let frame = demuxer.next_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            (frame.position, frame.size, frame.display_timestamp),
+            (5, 2, 1)
+        );
+
+        assert!(demuxer.next_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_obu_reader() {
+        // Two temporal units, each holding a padding OBU, the last one being truncated
+        let data = vec![
+            0x12, 0x00, 0x7A, 0x02, 0xAB, 0xCD, 0x12, 0x00, 0x7A, 0x03, 0xEF,
+        ];
+        let container = Container::Obu(ObuDemuxer::new(data.len() as u64));
+        let size = data.len() as u64;
+        let mut reader = ObuReader::with_container(Cursor::new(data), container, None, 0, size);
+
+        let mut next = || {
+            let (obu, payload) = reader.next().unwrap().unwrap();
+            (obu.obu_type, payload, reader.position(), reader.timestamp())
+        };
+        assert_eq!(
+            next(),
+            (av1p::obu::OBU_TEMPORAL_DELIMITER, vec![], 2, Some(0))
+        );
+        assert_eq!(
+            next(),
+            (av1p::obu::OBU_PADDING, vec![0xAB, 0xCD], 4, Some(0))
+        );
+        assert_eq!(
+            next(),
+            (av1p::obu::OBU_TEMPORAL_DELIMITER, vec![], 8, Some(1))
+        );
+
+        assert!(matches!(
+            reader.next(),
+            Some(Err(ElevatorError::TruncatedObu { position: 8 }))
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_obus_without_size_field() {
+        // The same two temporal units, with and without a size field in their last OBU,
+        // which then extends to the end of its container frame
+        let sized = vec![
+            0x12, 0x00, 0x7A, 0x02, 0xAB, 0xCD, 0x12, 0x00, 0x7A, 0x01, 0xEF,
+        ];
+        let stripped = vec![0x12, 0x00, 0x78, 0xAB, 0xCD, 0x12, 0x00, 0x78, 0xEF];
+
+        let read_obus = |data: Vec<u8>, sizes: [u32; 2]| {
+            let samples = vec![
+                mp4::Mp4Sample {
+                    offset: 0,
+                    size: sizes[0],
+                    dts: 0,
+                },
+                mp4::Mp4Sample {
+                    offset: u64::from(sizes[0]),
+                    size: sizes[1],
+                    dts: 1,
+                },
+            ];
+            let size = data.len() as u64;
+            let mut reader = ObuReader::with_container(
+                Cursor::new(data),
+                Container::Mp4(samples.into_iter()),
+                None,
+                0,
+                size,
+            );
+            let mut obus = Vec::new();
+            while let Some(result) = reader.next() {
+                let (obu, payload) = result.unwrap();
+                obus.push((
+                    obu.obu_type,
+                    payload,
+                    reader.timestamp(),
+                    reader.position(),
+                    coded_obu_size(&obu),
+                ));
+            }
+            obus
+        };
+
+        let sized_obus = read_obus(sized, [6, 5]);
+        let stripped_obus = read_obus(stripped, [5, 4]);
+        assert_eq!(4, stripped_obus.len());
+
+        // Only the positions and the coded sizes differ, by the missing size fields.
+        for (sized_obu, stripped_obu) in sized_obus.iter().zip(&stripped_obus) {
+            assert_eq!(
+                (sized_obu.0, &sized_obu.1, sized_obu.2),
+                (stripped_obu.0, &stripped_obu.1, stripped_obu.2)
+            );
+        }
+        assert_eq!(
+            vec![(2, 2), (3, 3), (7, 2), (8, 2)],
+            stripped_obus
+                .iter()
+                .map(|obu| (obu.3, obu.4))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_truncation_position() {
+        let options = AnalysisOptions {
+            allow_truncated: true,
+            ..AnalysisOptions::default()
+        };
+        let frame = ElevatorError::TruncatedFrame {
+            position: 10,
+            size: 100,
+        };
+        let obu = ElevatorError::TruncatedObu { position: 20 };
+
+        assert_eq!(truncated_at(&options, &frame, false), Some(10));
+        assert_eq!(truncated_at(&options, &obu, true), Some(20));
+        // An incomplete OBU before the last frame is malformed rather than truncated.
+        assert_eq!(truncated_at(&options, &obu, false), None);
+        assert_eq!(
+            truncated_at(&AnalysisOptions::default(), &frame, true),
+            None
+        );
+        assert_eq!(
+            truncated_at(&options, &ElevatorError::MissingSequenceHeader, true),
+            None
+        );
+    }
+
+    #[test]
+    fn test_selected_layer() {
+        let obu = |obu_extension_flag, temporal_id, spatial_id| av1p::obu::Obu {
+            obu_type: av1p::obu::OBU_FRAME,
+            obu_extension_flag,
+            obu_has_size_field: true,
+            temporal_id,
+            spatial_id,
+            obu_size: 0,
+            header_len: 3,
+        };
+        let options = AnalysisOptions {
+            temporal_id: Some(0),
+            ..AnalysisOptions::default()
+        };
+
+        assert!(in_selected_layer(&options, &obu(true, 0, 1)));
+        assert!(!in_selected_layer(&options, &obu(true, 1, 0)));
+        assert!(in_selected_layer(&options, &obu(false, 0, 0)));
+
+        let options = AnalysisOptions {
+            spatial_id: Some(1),
+            ..options
+        };
+        assert!(in_selected_layer(&options, &obu(true, 0, 1)));
+        assert!(!in_selected_layer(&options, &obu(true, 0, 0)));
+        assert!(in_selected_layer(
+            &AnalysisOptions::default(),
+            &obu(true, 2, 3)
+        ));
+    }
+
+    #[test]
+    fn test_tile_counts() {
+        let tile_info = av1p::obu::TileInfo {
+            tile_cols: 16,
+            tile_rows: 16,
+        };
+        let (tiles, tile_cols) = tile_counts(&tile_info);
+        assert_eq!((256, 16), (tiles, tile_cols));
+
+        // No level allows 256 tiles, so they must not wrap around to a count that passes every level.
+        let context = SequenceContext {
+            pic_size: (1920, 1080),
+            tiles,
+            tile_cols,
+            ..SequenceContext::default()
+        };
+        let (level, constraint) = calculate_level_with_constraint(&context).unwrap();
+        assert_eq!((31, Some("max_tiles")), (level.0, constraint));
+    }
+
+    #[test]
+    fn test_tile_lists() {
+        // Two anchor frames, the second one split into 2x2 tiles, followed by the tile lists decoded from them.
+        let mut stream = TestStream::new(&[(0, 0)], (1920, 1080), false);
+        stream
+            .temporal_unit()
+            .frame(TestFrame::key(), None, 1000)
+            .temporal_unit()
+            .frame(
+                TestFrame {
+                    tiles_log2: (1, 1),
+                    ..TestFrame::inter(true, 0b10)
+                },
+                None,
+                1000,
+            )
+            .temporal_unit()
+            .frame_header(av1p::obu::OBU_FRAME_HEADER, TestFrame::inter(false, 0))
+            .tile_list(2, &[(1, 100); 4])
+            .tile_list(2, &[(0, 100), (0, 100)]);
+
+        // Tile lists are malformed outside of large scale tile mode.
+        let options = AnalysisOptions {
+            time_scale: Some((30, 1)),
+            ..AnalysisOptions::default()
+        };
+        let result = analyze(Cursor::new(&stream.data), FileFormat::Bitstream, &options);
+        assert!(matches!(
+            result,
+            Err(ElevatorError::MalformedBitstream { .. })
+        ));
+
+        // The four tiles of the second anchor frame make up one frame, and the two whole frames of the first one two.
+        let options = AnalysisOptions {
+            large_scale_tile: true,
+            tile_list_rate: Some(30.0),
+            ..AnalysisOptions::default()
+        };
+        let op = &stream.analyze(30, &options).operating_points[0];
+        let tile_lists = op.tile_lists.unwrap();
+        assert_eq!(2, tile_lists.count);
+        assert_eq!(60.0, tile_lists.decode_rate);
+        assert_eq!(60.0 * 1920.0 * 1080.0, tile_lists.decode_sample_rate);
+        assert_eq!((4, 2), (tile_lists.max_tiles, tile_lists.max_tile_cols));
+        assert_eq!(4.0 * 100.0 * 8.0 * 30.0 / 1_000_000.0, tile_lists.mbps);
+
+        // The tiles count against half the decode rate, which requires level 5.0 rather than the 4.0 of the frames.
+        assert_eq!(120 * 1920 * 1080, op.context.decode_rate);
+        assert_eq!(
+            (12, Some("max_decode_rate")),
+            (op.level.0, op.binding_constraint)
+        );
+        assert!(tile_lists.drives_level);
+
+        // A tile list of 65 tiles exceeds the tile limit of level 5.x.
+        let mut stream = TestStream::new(&[(0, 0)], (1920, 1080), false);
+        stream
+            .temporal_unit()
+            .frame(TestFrame::key(), None, 1000)
+            .temporal_unit()
+            .frame_header(av1p::obu::OBU_FRAME_HEADER, TestFrame::inter(false, 0))
+            .tile_list(8, &[(0, 1); 65]);
+        let options = AnalysisOptions {
+            large_scale_tile: true,
+            tile_list_rate: Some(1.0),
+            ..AnalysisOptions::default()
+        };
+        let op = &stream.analyze(30, &options).operating_points[0];
+        assert_eq!((65, 8), (op.context.tiles, op.context.tile_cols));
+        assert_eq!((16, Some("max_tiles")), (op.level.0, op.binding_constraint));
+        assert!(op.tile_lists.unwrap().drives_level);
+
+        // Tile lists can only refer to the anchor frames decoded before them.
+        stream.tile_list(1, &[(1, 1)]);
+        let options = AnalysisOptions {
+            time_scale: Some((30, 1)),
+            ..options
+        };
+        let result = analyze(Cursor::new(&stream.data), FileFormat::Bitstream, &options);
+        assert!(matches!(
+            result,
+            Err(ElevatorError::MalformedBitstream { .. })
+        ));
+    }
+
+    #[test]
+    fn test_multiple_operating_points() {
+        // One second of a 30 fps stream with two spatial layers, whose first operating point decodes both,
+        // and whose second one only decodes the base layer, in 540p.
+        let base = |frame| TestFrame {
+            size: Some((960, 540)),
+            ..frame
+        };
+        let mut stream = TestStream::new(&[(0x301, 9), (0x101, 8)], (1920, 1080), false);
+        stream
+            .temporal_unit()
+            .frame(base(TestFrame::key()), Some((0, 0)), 1000)
+            .frame(TestFrame::inter(true, 0b10), Some((0, 1)), 1000);
+        for _ in 1..30 {
+            stream
+                .temporal_unit()
+                .frame(base(TestFrame::inter(true, 0b01)), Some((0, 0)), 1000)
+                .frame(TestFrame::inter(true, 0b10), Some((0, 1)), 1000);
+        }
+        let analysis = stream.analyze(30, &AnalysisOptions::default());
+        let ops = &analysis.operating_points;
+        assert_eq!(2, ops.len());
+        assert_eq!((0x301, 0x101), (ops[0].idc, ops[1].idc));
+        assert_eq!((9, 8), (ops[0].old_level.0, ops[1].old_level.0));
+
+        // The 1080p frames of the enhancement layer only count in the first operating point, which requires level 4.1.
+        assert_eq!(
+            (60.0, 30.0),
+            (ops[0].max_display_rate, ops[1].max_display_rate)
+        );
+        assert_eq!(30 * (1920 * 1080 + 960 * 540), ops[0].context.decode_rate);
+        assert_eq!(30 * 960 * 540, ops[1].context.decode_rate);
+        assert_eq!((9, 8), (ops[0].level.0, ops[1].level.0));
+
+        // Patching rewrites the level of each operating point.
+        let mut data = Cursor::new(stream.data.clone());
+        let patches =
+            prepare_patches(&mut data, &analysis, &[LEVELS[12], LEVELS[9]], None, true).unwrap();
+        apply_patches(&mut data, &patches).unwrap();
+        stream.data = data.into_inner();
+        let ops = stream
+            .analyze(30, &AnalysisOptions::default())
+            .operating_points;
+        assert_eq!((12, 9), (ops[0].old_level.0, ops[1].old_level.0));
+    }
+
+    #[test]
+    fn test_temporal_layers() {
+        // Two seconds of a 1080p60 stream alternating between two temporal layers of 30 fps each,
+        // whose second operating point only decodes the base layer.
+        let mut stream = TestStream::new(&[(0x103, 9), (0x101, 8)], (1920, 1080), false);
+        stream
+            .temporal_unit()
+            .frame(TestFrame::key(), Some((0, 0)), 1000);
+        for i in 1..120 {
+            let (temporal_id, refresh_frame_flags) = if i % 2 == 0 { (0, 0b01) } else { (1, 0b10) };
+            stream.temporal_unit().frame(
+                TestFrame::inter(true, refresh_frame_flags),
+                Some((temporal_id, 0)),
+                1000,
+            );
+        }
+        let ops = stream
+            .analyze(60, &AnalysisOptions::default())
+            .operating_points;
+
+        // The temporal units without frames of the base layer still take up time, so it is decoded at 30 fps.
+        assert_eq!(
+            (60.0, 60.0, 60.0),
+            (
+                ops[0].max_display_rate,
+                ops[0].max_decode_rate,
+                ops[0].max_header_rate
+            )
+        );
+        assert_eq!(
+            (30.0, 30.0, 30.0),
+            (
+                ops[1].max_display_rate,
+                ops[1].max_decode_rate,
+                ops[1].max_header_rate
+            )
+        );
+        assert_eq!(
+            (60, 120),
+            (ops[1].total_show_count, ops[0].total_show_count)
+        );
+        assert_eq!(
+            (30.0, 30.0, 30.0),
+            (
+                ops[1].min_frame_rate,
+                ops[1].avg_frame_rate,
+                ops[1].max_frame_rate
+            )
+        );
+        assert_eq!(1920 * 1080 * 30, ops[1].context.decode_rate);
+        assert_eq!((9, 8), (ops[0].level.0, ops[1].level.0));
+
+        // Selecting the base layer measures it the same way in every operating point.
+        let options = AnalysisOptions {
+            temporal_id: Some(0),
+            ..AnalysisOptions::default()
+        };
+        let ops = stream.analyze(60, &options).operating_points;
+        assert_eq!(
+            (30.0, 30.0),
+            (ops[0].max_display_rate, ops[1].max_display_rate)
+        );
+        assert_eq!((8, 8), (ops[0].level.0, ops[1].level.0));
+    }
+
+    #[test]
+    fn test_show_existing_frame() {
+        let (hd, sd) = (1920 * 1080, 1280 * 720);
+        let hidden = |frame_type| TestFrame {
+            frame_type,
+            show_frame: false,
+            refresh_frame_flags: 0b10,
+            size: Some((1280, 720)),
+            ..TestFrame::key()
+        };
+        let shown = TestFrame::inter(true, 0b01);
+
+        // One second at 60 fps of groups of four temporal units, the first of which also decodes a hidden 720p frame
+        // that the last one shows with show_existing_frame. The first hidden frame is a key frame.
+        let mut stream = TestStream::new(&[(0, 9)], (1920, 1080), false);
+        for group in 0..15 {
+            let frame_type = if group == 0 {
+                av1p::obu::KEY_FRAME
+            } else {
+                av1p::obu::INTER_FRAME
+            };
+            stream
+                .temporal_unit()
+                .frame(hidden(frame_type), None, 1000)
+                .frame(shown, None, 1000);
+            stream.temporal_unit().frame(shown, None, 1000);
+            stream.temporal_unit().frame(shown, None, 1000);
+            stream.temporal_unit().show_existing_frame(1);
+        }
+        let op = &stream
+            .analyze(60, &AnalysisOptions::default())
+            .operating_points[0];
+
+        // Each temporal unit shows a single frame, and the hidden frames are decoded once.
+        assert_eq!(60, op.total_show_count);
+        assert_eq!(
+            (60.0, 60.0, 60.0),
+            (op.max_display_rate, op.max_decode_rate, op.max_header_rate)
+        );
+        assert_eq!(15 * (3 * hd + sd), op.context.display_rate);
+        assert_eq!(op.context.display_rate, op.context.decode_rate);
+
+        // Showing an existing key frame refreshes every reference frame with it, without decoding it again,
+        // so the 1080p frame is no longer there to be shown.
+        let mut stream = TestStream::new(&[(0, 9)], (1920, 1080), false);
+        stream
+            .temporal_unit()
+            .frame(hidden(av1p::obu::KEY_FRAME), None, 1000)
+            .frame(shown, None, 1000);
+        stream.temporal_unit().show_existing_frame(1);
+        stream.temporal_unit().show_existing_frame(0);
+        let options = AnalysisOptions {
+            no_extrapolate: true,
+            ..AnalysisOptions::default()
+        };
+        let op = &stream.analyze(60, &options).operating_points[0];
+
+        assert_eq!(3, op.total_show_count);
+        assert_eq!((2.0, 2.0), (op.max_decode_rate, op.max_header_rate));
+        assert_eq!(hd + 2 * sd, op.context.display_rate);
+        assert_eq!(hd + sd, op.context.decode_rate);
+    }
+
+    #[test]
+    fn test_per_frame_picture_size() {
+        let (uhd, hd) = (3840 * 2160, 1920 * 1080);
+
+        // One second at 60 fps of a 4K stream, in which every fourth frame is 4K and the others are 1080p.
+        let mut stream = TestStream::new(&[(0, 12)], (3840, 2160), false);
+        stream.temporal_unit().frame(TestFrame::key(), None, 1000);
+        for i in 1..60 {
+            let frame = TestFrame {
+                size: if i % 4 == 0 { None } else { Some((1920, 1080)) },
+                ..TestFrame::inter(true, 0b01)
+            };
+            stream.temporal_unit().frame(frame, None, 1000);
+        }
+        let op = &stream
+            .analyze(60, &AnalysisOptions::default())
+            .operating_points[0];
+
+        assert_eq!((3840, 2160), op.context.pic_size);
+        assert_eq!(60.0, op.max_display_rate);
+        assert_eq!(15 * uhd + 45 * hd, op.context.display_rate);
+        assert_eq!(op.context.display_rate, op.context.decode_rate);
+        assert_eq!(12, op.level.0);
+
+        // Assuming that every frame has the maximum frame size requires level 5.1.
+        let naive = SequenceContext {
+            display_rate: 60 * uhd,
+            decode_rate: 60 * uhd,
+            ..op.context
+        };
+        assert_eq!(13, calculate_level(&naive).unwrap().0);
+    }
+
+    #[test]
+    fn test_short_clips() {
+        // A 1080p60 clip of the given number of frames, lasting one time scale unit each.
+        let level = |frames: usize, extrapolate: bool| {
+            let mut stream = TestStream::new(&[(0, 9)], (1920, 1080), false);
+            stream.temporal_unit().frame(TestFrame::key(), None, 1000);
+            for _ in 1..frames {
+                stream
+                    .temporal_unit()
+                    .frame(TestFrame::inter(true, 0b01), None, 1000);
+            }
+            let options = AnalysisOptions {
+                no_extrapolate: !extrapolate,
+                ..AnalysisOptions::default()
+            };
+            stream.analyze(60, &options).operating_points[0].level.0
+        };
+
+        // Every clip has to be decoded at 60 fps, which requires level 4.1.
+        assert_eq!(9, level(1, true));
+        assert_eq!(9, level(10, true));
+        assert_eq!(9, level(61, true));
+
+        // Without extrapolation, the clips shorter than one second only require the level of their picture size.
+        assert_eq!(8, level(1, false));
+        assert_eq!(8, level(10, false));
+        assert_eq!(9, level(61, false));
+    }
+
+    #[test]
+    fn test_still_picture() {
+        // A single 4K frame in a container with a time scale of 60 units per second.
+        let level = |still_picture| {
+            let mut stream = TestStream::new(&[(0, 12)], (3840, 2160), still_picture);
+            stream.temporal_unit().frame(TestFrame::key(), None, 1000);
+            stream
+                .analyze(60, &AnalysisOptions::default())
+                .operating_points[0]
+                .level
+                .0
+        };
+
+        // A still picture is displayed for one second, so only its size matters, which requires level 5.0.
+        assert_eq!(12, level(true));
+
+        // A single frame of video lasts one time scale unit, and is decoded at 60 fps, which requires level 5.1.
+        assert_eq!(13, level(false));
+    }
+
+    #[test]
+    fn test_high_tier_level() {
+        // A 1080p30 stream at 25 Mbps exceeds the Main tier bitrate of level 4.1, but not the High tier one of 4.0.
+        let context = SequenceContext {
+            pic_size: (1920, 1080),
+            display_rate: 1920 * 1080 * 30,
+            decode_rate: 1920 * 1080 * 30,
+            header_rate: 30,
+            mbps: 25.0,
+            ..SequenceContext::default()
+        };
+        assert_eq!(12, calculate_level(&context).unwrap().0);
+
+        let high_tier = SequenceContext {
+            tier: Tier::High,
+            ..context
+        };
+        assert_eq!(8, calculate_level(&high_tier).unwrap().0);
+
+        // The compressed ratio bound is looser in High tier: 4K60 frames compressed five times require level 5.2
+        // in Main tier, but only 5.1 in High tier.
+        let mut min_cr_level_idx = [0; 2];
+        update_min_cr_level_indices(&mut min_cr_level_idx, 3840.0 * 2160.0 * 60.0, 5.0, false);
+        assert_eq!([14, 13], min_cr_level_idx);
+    }
+
+    #[test]
+    fn test_patch_forced_tier_in_place() {
+        // A 1080p30 stream signaling level 4.0 (index 8) in Main tier, which is also the level it requires.
+        let mut stream = TestStream::new(&[(0, 8)], (1920, 1080), false);
+        stream.temporal_unit().frame(TestFrame::key(), None, 1000);
+        for _ in 1..30 {
+            stream
+                .temporal_unit()
+                .frame(TestFrame::inter(true, 0b01), None, 1000);
+        }
+        let options = AnalysisOptions {
+            tier: Some(Tier::High),
+            ..AnalysisOptions::default()
+        };
+        let analysis = stream.analyze(30, &options);
+        let levels = [analysis.operating_points[0].level];
+        assert_eq!(8, levels[0].0);
+        assert_eq!(Tier::High, analysis.operating_points[0].context.tier);
+
+        // The level is unchanged, but forcing High tier still has to patch the tier bit.
+        assert!(analysis.signals_levels(&levels, None));
+        assert!(analysis.signals_levels(&levels, Some(Tier::Main)));
+        assert!(!analysis.signals_levels(&levels, Some(Tier::High)));
+
+        let mut data = Cursor::new(stream.data.clone());
+        let patches =
+            prepare_patches(&mut data, &analysis, &levels, Some(Tier::High), true).unwrap();
+        assert_eq!(1, patches.len());
+        apply_patches(&mut data, &patches).unwrap();
+
+        stream.data = data.into_inner();
+        let patched = stream.analyze(30, &options);
+        assert_eq!(1, patched.sequence_header().op[0].seq_tier);
+        assert_eq!(8, patched.operating_points[0].old_level.0);
+        assert!(patched.signals_levels(&levels, Some(Tier::High)));
+        assert!(!patched.signals_levels(&levels, Some(Tier::Main)));
+    }
+
+    #[test]
+    fn test_bitrate_includes_obu_headers() {
+        // One second of a 1080p30 stream, whose temporal units hold a temporal delimiter and a frame OBU
+        // with an 83333-byte payload and a 3-byte size field.
+        let payload = 83_333;
+        let mut stream = TestStream::new(&[(0, 9)], (1920, 1080), false);
+        for i in 0..30 {
+            let frame = if i == 0 {
+                TestFrame::key()
+            } else {
+                TestFrame::inter(true, 0b01)
+            };
+            let header_len = pack_bits(&frame.header_bits((1920, 1080))).len();
+            stream
+                .temporal_unit()
+                .frame(frame, None, payload - header_len);
+        }
+        let op = &stream
+            .analyze(30, &AnalysisOptions::default())
+            .operating_points[0];
+
+        // Every byte of the stream is counted, including the sequence header.
+        assert_eq!(stream.data.len() as f64 * 8.0 / 1_000_000.0, op.max_mbps);
+        assert_eq!(op.max_mbps, op.context.mbps);
+
+        // The frame payloads alone stay within the 20 Mbps of level 4.1, but the OBU headers and temporal delimiters
+        // push it over the limit.
+        assert_eq!(12, op.level.0);
+        assert_eq!(Some("max_mbps"), op.binding_constraint);
+        let payloads = SequenceContext {
+            mbps: (30 * payload * 8) as f64 / 1_000_000.0,
+            ..op.context
+        };
+        assert_eq!(9, calculate_level(&payloads).unwrap().0);
+    }
+
+    #[test]
+    fn test_compressed_ratio_ignores_color_config() {
+        let color = |bit_depth, mono_chrome, subsampling| av1p::obu::ColorConfig {
+            BitDepth: bit_depth,
+            mono_chrome,
+            subsampling_x: subsampling,
+            subsampling_y: subsampling,
+            ..Default::default()
+        };
+        // The bit depths and chroma formats each profile allows, monochrome being 4:2:0 in the sequence header.
+        let formats = [
+            vec![
+                color(8, false, true),
+                color(10, false, true),
+                color(8, true, true),
+                color(10, true, true),
+            ],
+            vec![color(8, false, false), color(10, false, false)],
+            vec![
+                color(8, false, true),
+                color(10, false, true),
+                color(12, false, true),
+                color(12, false, false),
+                color(12, true, true),
+            ],
+        ];
+        let (samples, compressed_size) = (1920 * 1080, 1_000_000);
+        let sizes = [3_888_000, 7_776_000, 9_331_200];
+
+        for (seq_profile, (colors, &size)) in formats.iter().zip(&sizes).enumerate() {
+            for &color_config in colors {
+                let sh = av1p::obu::SequenceHeader {
+                    seq_profile: seq_profile as u8,
+                    color_config,
+                    ..Default::default()
+                };
+
+                // The uncompressed size is the largest format of the profile, even for 8-bit or monochrome frames.
+                assert_eq!(
+                    size,
+                    uncompressed_size(sh.seq_profile, samples as usize),
+                    "profile {}, {:?}",
+                    seq_profile,
+                    color_config
+                );
+                assert_eq!(
+                    Some(size as f64 / compressed_size as f64),
+                    compressed_ratio(sh.seq_profile, samples, compressed_size),
+                    "profile {}, {:?}",
+                    seq_profile,
+                    color_config
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compressed_ratio_excludes_frame_header() {
+        // Two temporal units of a 1080p60 stream of key frames, each holding 1105980 bytes of tile data.
+        let samples = 1920 * 1080;
+        let tile_bytes = 1_105_980;
+        let mut stream = TestStream::new(&[(0, 9)], (1920, 1080), false);
+        for _ in 0..2 {
+            stream
+                .temporal_unit()
+                .frame(TestFrame::key(), None, tile_bytes);
+        }
+        let op = &stream
+            .analyze(60, &AnalysisOptions::default())
+            .operating_points[0];
+
+        // The compressed ratio of the tile data falls just short of the minimum of level 4.1.
+        assert_eq!(samples * 60, op.context.display_rate);
+        assert_eq!(12, op.min_cr_level.0);
+
+        // Subtracting a fixed 128-byte frame header size from the frame OBU instead of the actual one hides it.
+        let header_len = pack_bits(&TestFrame::key().header_bits((1920, 1080))).len();
+        let fudged = compressed_ratio(0, samples, (header_len + tile_bytes - 128) as u64).unwrap();
+        assert_eq!(
+            9,
+            min_cr_level_index(Tier::Main, (samples * 60) as f64, fudged, false)
+        );
+
+        // Frames without compressed data have no compressed ratio.
+        assert!(compressed_ratio(0, samples, 0).is_none());
+    }
+
+    #[test]
+    fn test_repeated_frame_headers() {
+        let mut last_frame_header = Vec::new();
+
+        assert!(!is_repeated_frame_header(&mut &[0x10, 0x20][..], &mut last_frame_header).unwrap());
+        assert_eq!(vec![0x10, 0x20], last_frame_header);
+
+        assert!(is_repeated_frame_header(&mut &[0x10, 0x20][..], &mut last_frame_header).unwrap());
+        assert!(!is_repeated_frame_header(&mut &[0x10, 0x30][..], &mut last_frame_header).unwrap());
+        assert_eq!(vec![0x10, 0x30], last_frame_header);
+    }
+
+    #[test]
+    fn test_frame_header_copies() {
+        // One second of a 1080p30 stream, whose temporal units hold a temporal delimiter, a frame header OBU and
+        // a tile group OBU of 40000 bytes, optionally followed by a redundant and a repeated copy of the frame header.
+        let stream = |copies: bool| {
+            let mut stream = TestStream::new(&[(0, 8)], (1920, 1080), false);
+            for i in 0..30 {
+                let frame = if i == 0 {
+                    TestFrame::key()
+                } else {
+                    TestFrame::inter(true, 0b01)
+                };
+                stream
+                    .temporal_unit()
+                    .frame_header(av1p::obu::OBU_FRAME_HEADER, frame)
+                    .tile_group(40_000);
+                if copies {
+                    stream
+                        .frame_header(av1p::obu::OBU_REDUNDANT_FRAME_HEADER, frame)
+                        .frame_header(av1p::obu::OBU_FRAME_HEADER, frame);
+                }
+            }
+            stream
+        };
+        let (plain, copied) = (stream(false), stream(true));
+        let op = &plain
+            .analyze(30, &AnalysisOptions::default())
+            .operating_points[0];
+        let copied_op = &copied
+            .analyze(30, &AnalysisOptions::default())
+            .operating_points[0];
+
+        // The copies do not count as frames, so only the bitrate grows.
+        assert_eq!(30, copied_op.total_show_count);
+        assert_eq!(30.0, copied_op.max_header_rate);
+        assert_eq!(
+            (op.max_display_rate, op.max_decode_rate, op.max_header_rate),
+            (
+                copied_op.max_display_rate,
+                copied_op.max_decode_rate,
+                copied_op.max_header_rate
+            )
+        );
+        assert_eq!(
+            (op.context.display_rate, op.context.decode_rate),
+            (
+                copied_op.context.display_rate,
+                copied_op.context.decode_rate
+            )
+        );
+        assert_eq!(plain.data.len() as f64 * 8.0 / 1_000_000.0, op.max_mbps);
+        assert_eq!(
+            copied.data.len() as f64 * 8.0 / 1_000_000.0,
+            copied_op.max_mbps
+        );
+
+        // Both fit the 12 Mbps of level 4.0.
+        assert_eq!(8, op.level.0);
+        assert_eq!(op.level.0, copied_op.level.0);
+    }
+}
@@ -1,82 +1,419 @@
 extern crate av1parser;
 extern crate clap;
-
-mod ivf;
-mod level;
-mod obu;
-
-use av1parser as av1p;
-use clap::{App, Arg};
-use level::*;
-use std::collections::VecDeque;
-use std::fmt;
-use std::fmt::{Display, Formatter};
+extern crate elevator;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+extern crate serde;
+extern crate serde_json;
+
+use clap::{App, AppSettings, Arg, SubCommand};
+use elevator::level::*;
+use elevator::PatchTarget;
+use elevator::{
+    AnalysisOptions, ContainerMetadata, ElevatorError, OperatingParameters, PeakBitrate,
+    PeakTimestamps, TemporalUnitStats, TileListRates, TimestampJump, Truncation,
+};
+use serde::Serialize;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::vec::Vec;
 
 #[derive(PartialEq)]
 enum Output<'a> {
     InPlace,
     File(&'a str),
+    /// The stream is written to a new IVF file, rebuilding each frame from its OBUs
+    Ivf(&'a str),
+    /// The patched stream is written to stdout
+    Stdout,
     CommandLine,
 }
 
+/// Format of the results written to stdout
+#[derive(PartialEq)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// Format of the timeline of the temporal units
+#[derive(PartialEq)]
+enum TimelineFormat {
+    Text,
+    Csv,
+}
+
+/// Column names of the statistics of each temporal unit, in CSV form
+const TU_STATS_HEADER: &str = "pts,duration,bytes,shown_frames,decoded_frames,headers,mbps";
+
+/// Prints a line of human-readable output, unless in quiet mode. It goes to stderr when stdout is reserved
+/// for the patched stream or the JSON results.
+macro_rules! report {
+    ($config: expr) => {
+        report!($config, "")
+    };
+    ($config: expr, $($arg: tt)*) => {
+        if !$config.quiet {
+            if $config.output == Output::Stdout || $config.format == Format::Json {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+
+/// Suffix appended to the input name to form the name of its backup file, if none is given
+const DEFAULT_BACKUP_SUFFIX: &str = ".elevator-bak";
+
+/// Number of sequence headers up to which the verbose output lists the position of each one
+const MAX_LISTED_SEQUENCE_HEADERS: usize = 8;
+
+/// Level that the stream is checked against with `--check`
+#[derive(Clone, Copy)]
+enum CheckLevel {
+    /// The level declared by each operating point
+    Declared,
+    Level(Level),
+}
+
 /// Configuration parameters received via CLI
 struct AppConfig<'a> {
     verbose: bool,
-    input: &'a str,
+    /// Whether the human-readable output is suppressed, leaving only errors and JSON results
+    quiet: bool,
+    /// Whether patches are only prepared and reported, without writing any file
+    dry_run: bool,
+    inputs: Vec<&'a str>,
     output: Output<'a>,
+    format: Format,
+    /// Whether the inputs are renditions of the same title, reported together in a ladder table
+    ladder: bool,
     forced_level: Option<Level>,
+    /// Whether a forced level lower than the computed one is an error rather than a warning
+    strict: bool,
+    /// Whether the frames are analyzed even though the level is forced
+    analyze: bool,
+    /// Whether a stream exceeding every defined level is patched to signal the maximum parameters
+    allow_max_params: bool,
+    forced_tier: Option<Tier>,
+    /// Index of the only operating point to analyze and patch, if any
+    operating_point: Option<usize>,
+    /// Whether the patched output is parsed again to check its levels
+    verify: bool,
+    force: bool,
+    /// Parameters of the analysis, including the time scale for input without container timing
+    analysis_options: AnalysisOptions,
+    /// CSV file receiving the statistics of each temporal unit
+    tu_stats: Option<&'a str>,
+    /// Format of the timeline of the temporal units printed after the analysis, if one is printed
+    timeline: Option<TimelineFormat>,
+    /// JSON file receiving the sequence context and level of each operating point
+    dump_context: Option<&'a str>,
+    /// Level that the stream is checked against, instead of being patched
+    check_level: Option<CheckLevel>,
+    /// Level that the computed level must not exceed for the input to be patched
+    assert_max_level: Option<Level>,
+    /// Utilization percentage above which a limit of the output level is flagged in the headroom report, if any
+    headroom: Option<f64>,
+    /// Suffix of the backup file saving the bytes replaced by in-place patches, if one is written
+    backup: Option<&'a str>,
+    /// Whether the inputs are restored from their backup files instead of being analyzed
+    restore: bool,
 }
 
-/// Container-level stream metadata
-struct ContainerMetadata {
-    /// Temporal resolution, such that `time_scale` units represent one second of real time
-    /// Represented as a rational (numerator, denominator)
-    time_scale: (u32, u32),
-    /// Frame width and height in pixels
-    resolution: (u16, u16),
+/// Provides the exit status for an error, which depends on its category.
+fn exit_code(err: &ElevatorError) -> i32 {
+    match err {
+        ElevatorError::Io(_) => 2,
+        ElevatorError::UnsupportedFormat(_)
+        | ElevatorError::UnsupportedFeature(_)
+        | ElevatorError::SequenceHeaderChanged { .. } => 3,
+        ElevatorError::MissingSequenceHeader
+        | ElevatorError::TruncatedObu { .. }
+        | ElevatorError::TruncatedFrame { .. }
+        | ElevatorError::MalformedBitstream { .. } => 4,
+        ElevatorError::ConfigRecordMismatch { .. } | ElevatorError::VerificationFailed { .. } => 5,
+        ElevatorError::Unpatchable(_) => 6,
+        ElevatorError::MissingTimeScale | ElevatorError::InvalidOperatingPoint { .. } => 7,
+        ElevatorError::LevelMismatch { .. } => 9,
+        ElevatorError::InvalidBackup(_) => 10,
+        ElevatorError::NoConformingLevel { .. } => 13,
+    }
 }
 
-impl ContainerMetadata {
-    /// Provides the time base in floating point form
-    fn time_scale(&self) -> f64 {
-        f64::from(self.time_scale.0) / f64::from(self.time_scale.1)
+/// Parses a rational number written as `numerator[/denominator]`.
+fn parse_rational(value: &str) -> Option<(u32, u32)> {
+    let mut parts = value.splitn(2, '/');
+    let num = parts.next()?.trim().parse::<u32>().ok()?;
+    let den = match parts.next() {
+        Some(den) => den.trim().parse::<u32>().ok()?,
+        None => 1,
+    };
+
+    if num == 0 || den == 0 {
+        None
+    } else {
+        Some((num, den))
     }
 }
 
-impl Display for ContainerMetadata {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        writeln!(
-            f,
-            "Time scale: {:.3} ({}/{})",
-            self.time_scale(),
-            self.time_scale.0,
-            self.time_scale.1
-        )?;
-        writeln!(f, "Resolution: {}x{}", self.resolution.0, self.resolution.1)?;
+/// Checks that a level argument names a defined level, either in dotted form (e.g. 4.0) or by index (e.g. 8).
+fn validate_level(value: String) -> Result<(), String> {
+    value.parse::<Level>().map(|_| ()).map_err(|err| {
+        format!(
+            "{}, use the list-levels subcommand to show the defined levels",
+            err
+        )
+    })
+}
 
-        Ok(())
-    }
+/// Defines the input files argument, which is required by the subcommands reading them.
+fn input_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("input")
+        .short("i")
+        .long("input")
+        .value_name("INPUT_FILE")
+        .help("Input filename(s)")
+        .multiple(true)
+        .index(1)
 }
 
-/// Container-level frame metadata
-struct ContainerFrameMetadata {
-    /// Size of the frame in bytes
-    size: u32,
-    /// Display timestamp of the frame at the time scale of the stream
-    display_timestamp: u64,
+/// Defines the output file argument of the patch subcommand.
+fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .short("o")
+        .long("output")
+        .value_name("OUTPUT_FILE")
+        .help("Output filename, or - to write to stdout")
+        .conflicts_with("inplace")
 }
 
-impl Display for ContainerFrameMetadata {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        writeln!(f, "Frame @ {}: {} bytes", self.display_timestamp, self.size)
+/// Defines the arguments of the analysis, which are shared by the analyze and patch subcommands.
+fn shared_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Format of the results written to stdout")
+            .possible_values(&["text", "json"])
+            .default_value("text"),
+        Arg::with_name("json")
+            .long("json")
+            .help("Write the results to stdout in JSON format, same as --format json"),
+        Arg::with_name("assert_max_level")
+            .long("assert-max-level")
+            .visible_alias("max-level")
+            .value_name("LEVEL")
+            .help("Fail without patching if the computed level exceeds the given one, reporting the violated constraints")
+            .validator(validate_level),
+        Arg::with_name("headroom")
+            .long("headroom")
+            .value_name("PERCENT")
+            .help("Report how much of each limit of the output level the stream uses, flagging the limits used above the given percentage [default: 90]")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .validator(|v| {
+                v.parse::<f64>()
+                    .ok()
+                    .filter(|percent| *percent >= 0.0)
+                    .map(|_| ())
+                    .ok_or_else(|| String::from("expected a percentage"))
+            }),
+        Arg::with_name("op")
+            .long("op")
+            .value_name("INDEX")
+            .help("Only analyze and patch the operating point with the given index")
+            .validator(|v| {
+                v.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| String::from("expected an operating point index"))
+            }),
+        Arg::with_name("timescale")
+            .long("timescale")
+            .value_name("TIME_SCALE")
+            .help("Frame rate of raw OBU or Annex B input, e.g. 30 or 30000/1001")
+            .validator(|v| {
+                parse_rational(&v)
+                    .map(|_| ())
+                    .ok_or_else(|| String::from("expected a positive rational number"))
+            }),
+        Arg::with_name("large_scale_tile")
+            .long("large-scale-tile")
+            .help("Decode the tile lists of the stream from its first frames, as anchor frames in large scale tile mode"),
+        Arg::with_name("tile_rate")
+            .long("tile-rate")
+            .value_name("RATE")
+            .help("Number of tile lists decoded per second in large scale tile streams [default: 180]")
+            .requires("large_scale_tile")
+            .validator(|v| {
+                v.parse::<f64>()
+                    .ok()
+                    .filter(|rate| *rate > 0.0)
+                    .map(|_| ())
+                    .ok_or_else(|| String::from("expected a positive number"))
+            }),
+        Arg::with_name("temporal_id")
+            .long("temporal-id")
+            .value_name("ID")
+            .help("Only count the OBUs of the temporal layer with the given ID")
+            .validator(|v| {
+                v.parse::<u8>()
+                    .ok()
+                    .filter(|id| *id < 8)
+                    .map(|_| ())
+                    .ok_or_else(|| String::from("expected a temporal layer ID from 0 to 7"))
+            }),
+        Arg::with_name("spatial_id")
+            .long("spatial-id")
+            .value_name("ID")
+            .help("Only count the OBUs of the spatial layer with the given ID")
+            .validator(|v| {
+                v.parse::<u8>()
+                    .ok()
+                    .filter(|id| *id < 4)
+                    .map(|_| ())
+                    .ok_or_else(|| String::from("expected a spatial layer ID from 0 to 3"))
+            }),
+        Arg::with_name("allow_truncated")
+            .long("allow-truncated")
+            .help("Analyze truncated files up to their first incomplete frame, reporting partial results"),
+        Arg::with_name("container_overhead")
+            .long("container-overhead")
+            .help("Count the container bytes between the frames, like IVF frame headers, towards the bitrate"),
+        Arg::with_name("no_extrapolate")
+            .long("no-extrapolate")
+            .help("Measure clips shorter than one second over their own duration, instead of as if they repeated"),
+        Arg::with_name("use_seq_resolution")
+            .long("use-seq-resolution")
+            .help("Report the maximum frame size of the sequence header as the resolution instead of the container resolution"),
+        Arg::with_name("bitrate_window")
+            .long("bitrate-window")
+            .value_name("SECONDS")
+            .help("Length of the window over which the peak bitrate is measured, which can be given multiple times to report each one, the first one being used for the level [default: 1]")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|v| {
+                v.parse::<f64>()
+                    .ok()
+                    .filter(|seconds| *seconds > 0.0)
+                    .map(|_| ())
+                    .ok_or_else(|| String::from("expected a positive number of seconds"))
+            }),
+        Arg::with_name("tu_stats")
+            .long("tu-stats")
+            .value_name("CSV_FILE")
+            .help("Write the statistics of each temporal unit to a CSV file"),
+        Arg::with_name("timeline")
+            .long("timeline")
+            .value_name("FORMAT")
+            .help("Print the statistics and windowed bitrate of each temporal unit, as a table or in CSV form [default: text]")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["text", "csv"]),
+        Arg::with_name("dump_context")
+            .long("dump-context")
+            .value_name("JSON_FILE")
+            .help("Write the sequence context and level of each operating point to a JSON file"),
+        Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Display verbose output, which may be helpful for debugging"),
+        Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .help("Do not display any output besides errors and JSON results")
+            .conflicts_with("verbose"),
+    ]
+}
+
+/// Defines the arguments that only apply to the analyze subcommand, which never writes the inputs.
+/// The deprecated flat form of the command line also accepts the patch arguments, so with `flat`, the arguments
+/// conflict with those that would write the inputs.
+fn analyze_args<'a, 'b>(flat: bool) -> Vec<Arg<'a, 'b>> {
+    let mut check = Arg::with_name("check")
+        .long("check")
+        .value_name("LEVEL")
+        .help("Check whether the stream conforms to a level, or to its declared level by default, reporting each constraint")
+        .takes_value(true)
+        .min_values(0)
+        .require_equals(true)
+        .validator(validate_level);
+    let mut ladder = Arg::with_name("ladder")
+        .long("ladder")
+        .help("Treat the inputs as renditions of the same title and report them in a single table");
+
+    if flat {
+        check = check.conflicts_with_all(&["output", "inplace", "forcedlevel", "restore"]);
+        ladder = ladder.conflicts_with("restore");
     }
+
+    vec![check, ladder]
 }
 
-fn main() -> io::Result<()> {
+/// Defines the arguments that only apply to the patch subcommand, besides its output file.
+fn patch_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("inplace")
+            .long("inplace")
+            .help("Patch file in place"),
+        Arg::with_name("output_ivf")
+            .long("output-ivf")
+            .value_name("OUTPUT_FILE")
+            .help("Write the stream to a new IVF file, rebuilding every frame instead of patching a copy of the IVF input")
+            .conflicts_with_all(&["output", "inplace"]),
+        Arg::with_name("forcedlevel")
+            .short("f")
+            .long("forcedlevel")
+            .value_name("FORCED_LEVEL")
+            .help("Force a level instead of calculating it, e.g. 4.0 or 8")
+            .validator(validate_level)
+            .conflicts_with("assert_max_level"),
+        Arg::with_name("tier")
+            .long("tier")
+            .value_name("TIER")
+            .help("Force the tier of the output stream")
+            .possible_values(&["main", "high"])
+            .case_insensitive(true),
+        Arg::with_name("allow_max_params")
+            .long("allow-max-params")
+            .help("Patch a stream exceeding the limits of every defined level to signal the maximum parameters, level index 31")
+            .conflicts_with("forcedlevel"),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("Fail without patching if the forced level is lower than the computed one")
+            .requires("forcedlevel"),
+        Arg::with_name("analyze")
+            .long("analyze")
+            .help("Analyze the frames even though the level is forced, to check it against the computed one")
+            .requires("forcedlevel"),
+        Arg::with_name("no_verify")
+            .long("no-verify")
+            .help("Do not parse the patched output again to check its levels"),
+        Arg::with_name("force")
+            .long("force")
+            .help("Patch even if the levels in the container and the bitstream disagree"),
+        Arg::with_name("dry_run")
+            .long("dry-run")
+            .help("Report the level and the patches to apply without writing any file"),
+        Arg::with_name("backup")
+            .long("backup")
+            .value_name("SUFFIX")
+            .help("Save the bytes replaced by in-place patches to a file named after the input, with the given suffix [default: .elevator-bak]")
+            .takes_value(true)
+            .min_values(0)
+            .require_equals(true),
+        Arg::with_name("restore")
+            .long("restore")
+            .help("Write the bytes saved with --backup back into the inputs, instead of analyzing them")
+            .conflicts_with_all(&["output", "output_ivf", "inplace", "forcedlevel", "tier", "op", "dry_run"]),
+    ]
+}
+
+/// Defines the command line interface.
+fn app<'a, 'b>() -> App<'a, 'b> {
     /// Shortcut for fetching a Cargo environment variable.
     macro_rules! cargo_env {
         ($name: expr) => {
@@ -84,663 +421,1681 @@ fn main() -> io::Result<()> {
         };
     }
 
-    // Generate a list of valid levels to validate the `forcedlevel` argument.
-    let level_strings = LEVELS
-        .iter()
-        .filter(|&l| l.is_valid())
-        .map(|&l| l.0.to_string())
-        .collect::<Vec<_>>();
-
-    // Define the command line interface.
-    let matches = App::new(cargo_env!("NAME"))
+    App::new(cargo_env!("NAME"))
         .version(cargo_env!("VERSION"))
         .author(cargo_env!("AUTHORS"))
         .about(cargo_env!("DESCRIPTION"))
-        .arg(
-            Arg::with_name("input")
-                .short("i")
-                .long("input")
-                .value_name("INPUT_FILE")
-                .help("Input filename")
-                .required(true)
-                .index(1),
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Calculate and report the level of the inputs, without modifying them")
+                .arg(input_arg().required(true))
+                .args(&shared_args())
+                .args(&analyze_args(false)),
         )
-        .arg(
-            Arg::with_name("output")
-                .short("o")
-                .long("output")
-                .value_name("OUTPUT_FILE")
-                .help("Output filename"),
+        .subcommand(
+            SubCommand::with_name("patch")
+                .about("Calculate the level of the inputs and patch it into them")
+                .arg(input_arg().required(true))
+                .arg(output_arg().required_unless_one(&["inplace", "output_ivf", "restore"]))
+                .args(&shared_args())
+                .args(&patch_args()),
         )
-        .arg(
-            Arg::with_name("inplace")
-                .long("inplace")
-                .help("Patch file in place"),
-        )
-        .arg(
-            Arg::with_name("forcedlevel")
-                .short("f")
-                .long("forcedlevel")
-                .value_name("FORCED_LEVEL")
-                .help("Force a level instead of calculating it")
-                .possible_values(&level_strings.iter().map(|l| &**l).collect::<Vec<_>>()),
+        .subcommand(
+            SubCommand::with_name("list-levels").about("Print the limits of every defined level"),
         )
+        // The flat form of the command line, without a subcommand, is deprecated.
+        .arg(input_arg().required_unless("list_levels"))
+        .args(&shared_args())
+        .args(&analyze_args(true))
+        .arg(output_arg())
+        .args(&patch_args())
         .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .long("verbose")
-                .help("Display verbose output, which may be helpful for debugging"),
+            Arg::with_name("list_levels")
+                .long("list-levels")
+                .help("Print the limits of every defined level and exit"),
         )
-        .get_matches();
+}
 
-    // Parse command line input.
-    if matches.is_present("output") && matches.is_present("inplace") {
-        panic!("cannot specify an output file and in place at the same time");
-    }
+fn main() {
+    let matches = app().get_matches();
 
+    let matches = match matches.subcommand() {
+        ("list-levels", _) => {
+            print_levels();
+            return;
+        }
+        ("analyze", Some(matches)) | ("patch", Some(matches)) => matches,
+        _ => {
+            if matches.is_present("list_levels") {
+                eprintln!(
+                    "warning: --list-levels is deprecated, use the list-levels subcommand instead"
+                );
+                print_levels();
+                return;
+            }
+
+            if !matches.is_present("quiet") {
+                eprintln!(
+                    "warning: running without a subcommand is deprecated and will stop working in the next release, use the analyze or patch subcommand instead"
+                );
+            }
+
+            &matches
+        }
+    };
+
+    // Parse command line input.
     let config = AppConfig {
         verbose: matches.is_present("verbose"),
-        input: matches.value_of("input").unwrap(),
-        output: if matches.is_present("output") {
+        quiet: matches.is_present("quiet"),
+        dry_run: matches.is_present("dry_run"),
+        inputs: matches.values_of("input").unwrap().collect(),
+        output: if matches.value_of("output") == Some("-") {
+            Output::Stdout
+        } else if matches.is_present("output") {
             Output::File(matches.value_of("output").unwrap())
+        } else if let Some(output_fname) = matches.value_of("output_ivf") {
+            Output::Ivf(output_fname)
         } else if matches.is_present("inplace") {
             Output::InPlace
         } else {
             Output::CommandLine
         },
-        forced_level: if matches.is_present("forcedlevel") {
+        format: if matches.is_present("json") || matches.value_of("format") == Some("json") {
+            Format::Json
+        } else {
+            Format::Text
+        },
+        forced_level: matches
+            .value_of("forcedlevel")
+            .map(|level| level.parse().unwrap()),
+        operating_point: matches.value_of("op").map(|op| op.parse().unwrap()),
+        forced_tier: matches.value_of("tier").map(|tier| tier.parse().unwrap()),
+        verify: !matches.is_present("no_verify"),
+        ladder: matches.is_present("ladder"),
+        force: matches.is_present("force"),
+        strict: matches.is_present("strict"),
+        analyze: matches.is_present("analyze"),
+        allow_max_params: matches.is_present("allow_max_params"),
+        analysis_options: AnalysisOptions {
+            time_scale: matches.value_of("timescale").and_then(parse_rational),
+            use_sequence_resolution: matches.is_present("use_seq_resolution"),
+            tile_list_rate: matches
+                .value_of("tile_rate")
+                .map(|rate| rate.parse().unwrap()),
+            large_scale_tile: matches.is_present("large_scale_tile"),
+            temporal_id: matches
+                .value_of("temporal_id")
+                .map(|id| id.parse().unwrap()),
+            spatial_id: matches.value_of("spatial_id").map(|id| id.parse().unwrap()),
+            allow_truncated: matches.is_present("allow_truncated"),
+            count_container_overhead: matches.is_present("container_overhead"),
+            bitrate_windows: matches
+                .values_of("bitrate_window")
+                .map_or_else(Vec::new, |windows| {
+                    windows.map(|window| window.parse().unwrap()).collect()
+                }),
+            tier: matches.value_of("tier").map(|tier| tier.parse().unwrap()),
+            no_extrapolate: matches.is_present("no_extrapolate"),
+        },
+        tu_stats: matches.value_of("tu_stats"),
+        timeline: if matches.is_present("timeline") {
+            Some(match matches.value_of("timeline") {
+                Some("csv") => TimelineFormat::Csv,
+                _ => TimelineFormat::Text,
+            })
+        } else {
+            None
+        },
+        dump_context: matches.value_of("dump_context"),
+        check_level: if matches.is_present("check") {
+            Some(match matches.value_of("check") {
+                Some(level) => CheckLevel::Level(level.parse().unwrap()),
+                None => CheckLevel::Declared,
+            })
+        } else {
+            None
+        },
+        assert_max_level: matches
+            .value_of("assert_max_level")
+            .map(|level| level.parse().unwrap()),
+        headroom: if matches.is_present("headroom") {
             Some(
-                LEVELS[matches
-                    .value_of("forcedlevel")
-                    .unwrap()
-                    .parse::<usize>()
-                    .unwrap()],
+                matches
+                    .value_of("headroom")
+                    .map_or(90.0, |percent| percent.parse().unwrap()),
             )
         } else {
             None
         },
+        backup: if matches.is_present("backup") {
+            Some(matches.value_of("backup").unwrap_or(DEFAULT_BACKUP_SUFFIX))
+        } else {
+            None
+        },
+        restore: matches.is_present("restore"),
     };
 
-    process_input(&config)?;
+    if config.inputs.len() > 1 && (matches.is_present("output") || matches.is_present("output_ivf"))
+    {
+        eprintln!("error: an output file cannot be specified with multiple inputs");
+        std::process::exit(1);
+    }
 
-    Ok(())
+    if config.inputs.len() > 1 && config.tu_stats.is_some() {
+        eprintln!("error: temporal unit statistics cannot be written for multiple inputs");
+        std::process::exit(1);
+    }
+
+    if config.inputs.len() > 1 && config.dump_context.is_some() {
+        eprintln!("error: sequence contexts cannot be written for multiple inputs");
+        std::process::exit(1);
+    }
+
+    if config.output == Output::Stdout && config.format == Format::Json {
+        eprintln!("error: JSON results cannot be written to stdout along with the output stream");
+        std::process::exit(1);
+    }
+
+    if config.backup.is_some() && config.output != Output::InPlace && !config.restore {
+        eprintln!("error: a backup can only be saved when patching in place");
+        std::process::exit(1);
+    }
+
+    if config.restore {
+        restore_inputs(&config);
+        return;
+    }
+
+    // A failed input does not prevent the others from being processed.
+    let mut summaries = Vec::new();
+    let mut failed_inputs = Vec::new();
+    let mut status = 0; // exit status of the first failed input
+    let mut json_reports = Vec::new();
+
+    for &input in &config.inputs {
+        match process_input(&config, input) {
+            Ok(mut summary) => {
+                json_reports.extend(summary.json.take());
+
+                if !summary.conforms && status == 0 {
+                    status = 8;
+                }
+
+                if !summary.within_max_level && status == 0 {
+                    status = 11;
+                }
+
+                if !summary.forced_level_conforms && status == 0 {
+                    status = 12;
+                }
+
+                if !summary.max_params_allowed && status == 0 {
+                    status = 14;
+                }
+
+                summaries.push((input, summary));
+            }
+            Err(err) => {
+                eprintln!("error: {}: {}", input, err);
+
+                if config.format == Format::Json && !config.ladder {
+                    let failure = JsonFailure {
+                        input,
+                        error: err.to_string(),
+                    };
+                    json_reports.push(serde_json::to_value(&failure).unwrap());
+                }
+
+                match err {
+                    ElevatorError::ConfigRecordMismatch { .. } => {
+                        eprintln!("use --force to patch anyway")
+                    }
+                    ElevatorError::LevelMismatch { .. } => eprintln!(
+                        "the sequence header may contain fields that are not supported yet, so no file was modified"
+                    ),
+                    _ => {}
+                }
+
+                if status == 0 {
+                    status = exit_code(&err);
+                }
+
+                failed_inputs.push(input);
+            }
+        }
+    }
+
+    if config.ladder {
+        print_ladder(&config, &summaries);
+    } else if config.inputs.len() > 1 {
+        print_summary_table(&config, &summaries);
+    }
+
+    // A single input is reported as an object, and multiple inputs as an array of objects.
+    if config.format == Format::Json && !config.ladder {
+        let json = if config.inputs.len() > 1 {
+            Some(serde_json::to_string_pretty(&json_reports))
+        } else {
+            json_reports.first().map(serde_json::to_string_pretty)
+        };
+
+        match json {
+            Some(Ok(json)) => println!("{}", json),
+            Some(Err(err)) => {
+                eprintln!("error: {}", err);
+                std::process::exit(2);
+            }
+            None => {}
+        }
+    }
+
+    if !failed_inputs.is_empty() && config.inputs.len() > 1 {
+        eprintln!("error: failed to process {}", failed_inputs.join(", "));
+    }
+
+    if status != 0 {
+        std::process::exit(status);
+    }
 }
 
-// TODO: split this function into smaller parts
-#[allow(clippy::cognitive_complexity)]
-fn process_input(config: &AppConfig) -> io::Result<()> {
-    // Open the specified input file using a buffered reader.
-    let input_file = OpenOptions::new()
-        .read(true)
-        .write(config.output == Output::InPlace)
-        .open(config.input)
-        .expect("could not open the specified input file");
-    let output_file: File;
+/// Formats the statistics of a temporal unit as a CSV row, with the columns of [`TU_STATS_HEADER`].
+fn tu_stats_row(tu: &TemporalUnitStats) -> String {
+    format!(
+        "{},{},{},{},{},{},{:.6}",
+        tu.pts, tu.duration, tu.bytes, tu.shown_frames, tu.decoded_frames, tu.headers, tu.mbps
+    )
+}
 
-    let mut reader = BufReader::new(input_file);
-    let mut writer: BufWriter<File>;
+/// Prints the statistics of each temporal unit, flagging the ones at which the windowed bitrate peaks.
+fn print_timeline(
+    config: &AppConfig,
+    format: &TimelineFormat,
+    timeline: &[TemporalUnitStats],
+    metadata: &ContainerMetadata,
+) {
+    if *format == TimelineFormat::Csv {
+        report!(config, "{}", TU_STATS_HEADER);
+
+        for tu in timeline {
+            report!(config, "{}", tu_stats_row(tu));
+        }
+        return;
+    }
 
-    let fmt = av1p::probe_fileformat(&mut reader).expect("could not probe the input file format");
-    reader.seek(SeekFrom::Start(0))?;
+    let peak_mbps = timeline.iter().map(|tu| tu.mbps).fold(0.0, f64::max);
+    let seconds = |units: u64| units as f64 / metadata.time_scale();
+
+    report!(
+        config,
+        "{:>10}  {:>8}  {:>9}  {:>5}  {:>7}  {:>7}  {:>8}",
+        "Time",
+        "Duration",
+        "Bytes",
+        "Shown",
+        "Decoded",
+        "Headers",
+        "Mbps"
+    );
+
+    for tu in timeline {
+        report!(
+            config,
+            "{:>10.3}  {:>8.3}  {:>9}  {:>5}  {:>7}  {:>7}  {:>8.3}{}",
+            seconds(tu.pts),
+            seconds(tu.duration),
+            tu.bytes,
+            tu.shown_frames,
+            tu.decoded_frames,
+            tu.headers,
+            tu.mbps,
+            if tu.mbps == peak_mbps {
+                "  <- peak"
+            } else {
+                ""
+            }
+        );
+    }
+}
 
-    let mut seq = av1p::av1::Sequence::new();
-    let mut seq_positions = Vec::new();
-    let mut seq_sizes = Vec::new();
-
-    let (mut max_tile_cols, mut max_tiles) = (0, 0); // the maximum tile parameters
-    let mut max_display_rate = 0_f64; // max number of shown frames in a temporal unit (i.e. number of frame headers with show_frame or show_existing_frame)
-    let mut max_decode_rate = 0_f64; // max number of decoded frames in a temporal unit (i.e. number of frame headers without show_existing_frame)
-    let mut max_header_rate = 0_f64; // max number of frame and frame header (excluding show_existing_frame) OBUs in a temporal unit
-    let mut min_cr_level_idx = 0; // minimum level index required to support the compressed ratio bound
-    let mut max_mbps = 0_f64; // max bitrate in megabits per second
-    let mut max_tile_list_bitrate = 0; // max bitrate for tile lists
-    let mut max_tile_decode_rate = 0_f64; // max decode rate for tile lists
-
-    let metadata = match fmt {
-        av1p::FileFormat::IVF => {
-            let header = ivf::parse_ivf_header(&mut reader, config.input)?;
-
-            ContainerMetadata {
-                // Note: the `framerate` field name (from av1parser) is inaccurate
-                time_scale: (header.framerate, header.timescale),
-                resolution: (header.width, header.height),
+/// Writes the bytes saved in the backup file of each input back into it, then exits with the status of the first failure.
+fn restore_inputs(config: &AppConfig) {
+    let suffix = config.backup.unwrap_or(DEFAULT_BACKUP_SUFFIX);
+    let mut status = 0;
+
+    for &input in &config.inputs {
+        let backup_fname = format!("{}{}", input, suffix);
+        let result = File::open(&backup_fname)
+            .map_err(ElevatorError::from)
+            .and_then(|backup_file| elevator::Backup::read(BufReader::new(backup_file)))
+            .and_then(|backup| {
+                let file = OpenOptions::new().read(true).write(true).open(input)?;
+                backup.restore(file)
+            });
+
+        match result {
+            Ok(()) => report!(config, "{}: restored from {}", input, backup_fname),
+            Err(err) => {
+                eprintln!("error: {}: {}", input, err);
+
+                if status == 0 {
+                    status = exit_code(&err);
+                }
             }
         }
-        _ => unimplemented!("non-IVF input not currently supported"),
-    };
+    }
 
-    let time_scale = metadata.time_scale();
-    let picture_size = usize::from(metadata.resolution.0) * usize::from(metadata.resolution.1);
+    if status != 0 {
+        std::process::exit(status);
+    }
+}
 
-    if config.verbose {
-        println!("Container metadata:");
-        println!("{}", metadata);
-    }
-
-    // TODO: do not parse the whole stream if setting a level manually
-    let mut show_count = 0; // shown frame count for the current temporal unit
-    let mut frame_count = 0; // decoded frame count for the current temporal unit
-    let mut header_count = 0; // header count for the current temporal unit
-    let mut last_tu_time = 0; // timestamp for the first frame of the last temporal unit
-    let mut cur_tu_time = 0; // timestamp for the first frame of the current temporal unit
-    let mut frame_size = 0_i64; // total compressed size for the current frame (includes frame, frame header, metadata, and tile group OBUs)
-    let mut tu_size = 0; // total size of frames in the current temporal unit
-    let mut tu_sizes = VecDeque::<u32>::new(); // one-second buffer for bitrate calculation per temporal unit
-    let mut tu_times = VecDeque::<u64>::new(); // one-second buffer for time scale units taken per temporal unit
-    let mut header_counts = VecDeque::<u32>::new(); // one-second buffer for number of headers per temporal unit
-    let mut seen_frame_header = false; // refreshed with each temporal unit
-    let mut min_compressed_ratio = std::f64::MAX; // min compression ratio for a single frame
-    let mut tile_info = av1p::obu::TileInfo::default(); // last seen tile information
-
-    let mut total_show_count = 0; // total number of displayed frames
-
-    fn get_container_frame<R: io::Read>(
-        reader: &mut R,
-        fmt: &av1p::FileFormat,
-    ) -> Option<ContainerFrameMetadata> {
-        match fmt {
-            av1p::FileFormat::IVF => {
-                if let Ok(frame) = av1p::ivf::parse_ivf_frame(reader) {
-                    ContainerFrameMetadata {
-                        size: frame.size,
-                        display_timestamp: frame.pts,
-                    }
-                    .into()
+/// Prints a table of the limits of every defined level.
+fn print_levels() {
+    println!(
+        "{:<5}  {:>10}  {:>6}  {:>6}  {:>13}  {:>13}  {:>6}  {:>9}  {:>9}  {:>7}  {:>7}  {:>5}  {:>9}",
+        "Level",
+        "Pic size",
+        "Width",
+        "Height",
+        "Display rate",
+        "Decode rate",
+        "Header",
+        "Main Mbps",
+        "High Mbps",
+        "Main CR",
+        "High CR",
+        "Tiles",
+        "Tile cols"
+    );
+
+    for level in LEVELS.iter().filter(|level| level.0 < 31) {
+        if let Some(limits) = level.limits() {
+            // Only Main tier exists for low levels.
+            let high_tier = |value: String| {
+                if level.0 > 7 {
+                    value
                 } else {
-                    None
+                    String::from("-")
                 }
-            }
-            _ => {
-                unreachable!();
-            }
+            };
+
+            println!(
+                "{:<5}  {:>10}  {:>6}  {:>6}  {:>13}  {:>13}  {:>6}  {:>9.1}  {:>9}  {:>7}  {:>7}  {:>5}  {:>9}",
+                level.name(),
+                limits.max_pic_size,
+                limits.max_h_size,
+                limits.max_v_size,
+                limits.max_display_rate,
+                limits.max_decode_rate,
+                limits.max_header_rate,
+                limits.main_mbps,
+                high_tier(format!("{:.1}", limits.high_mbps)),
+                limits.main_cr,
+                high_tier(limits.high_cr.to_string()),
+                limits.max_tiles,
+                limits.max_tile_cols
+            );
         }
     }
 
-    // Read one frame from the container at a time.
-    while let Some(frame) = get_container_frame(&mut reader, &fmt) {
-        let mut sz = frame.size;
-        let pts = frame.display_timestamp;
+    println!();
+    println!("Level index 31 ({}) has no limits.", LEVELS[31].name());
+}
 
-        let pos = reader.seek(SeekFrom::Current(0))?;
+/// Prints a table summarizing the results for each input.
+fn print_summary_table(config: &AppConfig, summaries: &[(&str, Summary)]) {
+    let name_width = summaries
+        .iter()
+        .map(|(input, _)| input.len())
+        .chain(std::iter::once("Input".len()))
+        .max()
+        .unwrap_or(0);
+
+    report!(config);
+    report!(
+        config,
+        "{:<name_width$}  {:<18}  {:<18}  {:>9}  Resolution",
+        "Input",
+        "Old level",
+        "New level",
+        "Max Mbps",
+        name_width = name_width
+    );
+
+    for (input, summary) in summaries {
+        report!(
+            config,
+            "{:<name_width$}  {:<18}  {:<18}  {:>9.3}  {}x{}",
+            input,
+            summary.old_level.to_string(),
+            summary.level.to_string(),
+            summary.max_mbps,
+            summary.resolution.0,
+            summary.resolution.1,
+            name_width = name_width
+        );
+    }
+}
 
-        // Read all AV1 OBUs in the container frame.
-        while sz > 0 {
-            let obu = av1p::obu::parse_obu_header(&mut reader, sz)?;
+/// A rendition of a title, reported in the ladder table
+#[derive(Serialize)]
+struct Rendition<'a> {
+    input: &'a str,
+    resolution: (u16, u16),
+    frame_rate: f64,
+    max_mbps: f64,
+    level: Level,
+    declared_level: Level,
+    /// Whether the declared level is lower than the computed one
+    underdeclared: bool,
+}
 
-            sz -= obu.header_len + obu.obu_size;
-            let pos = reader.seek(SeekFrom::Current(0))?;
+/// Prints the renditions sorted by picture size, flagging the ones that declare a level that is too low.
+fn print_ladder(config: &AppConfig, summaries: &[(&str, Summary)]) {
+    let mut renditions = summaries
+        .iter()
+        .map(|&(input, ref summary)| Rendition {
+            input,
+            resolution: summary.resolution,
+            frame_rate: summary.frame_rate,
+            max_mbps: summary.max_mbps,
+            level: summary.computed_level,
+            declared_level: summary.old_level,
+            underdeclared: summary.old_level.0 < summary.computed_level.0,
+        })
+        .collect::<Vec<_>>();
 
-            match obu.obu_type {
-                av1p::obu::OBU_TEMPORAL_DELIMITER => {
-                    if pts == cur_tu_time {
-                        // duplicate temporal delimiter?
-                        continue;
-                    }
+    renditions.sort_by_key(|r| u32::from(r.resolution.0) * u32::from(r.resolution.1));
 
-                    let delta_time = (pts - cur_tu_time) as f64 / time_scale;
+    if config.format == Format::Json {
+        match serde_json::to_string_pretty(&renditions) {
+            Ok(json) => println!("{}", json),
+            Err(err) => eprintln!("error: {}", err),
+        }
 
-                    let display_rate = f64::from(show_count) / delta_time;
-                    max_display_rate = max_display_rate.max(display_rate);
-                    max_decode_rate = max_decode_rate.max(f64::from(frame_count) / delta_time);
-                    //max_header_rate = max_header_rate.max(header_count as f64 / delta_time);
+        return;
+    }
 
-                    // Calculate bitrate and header rate, windowed over one second (sampled every frame).
-                    // We assume that header rate is computed over one-second windows.
-                    // This is not clear in the specification, but seems implied.
-                    header_counts.push_back(header_count);
-                    tu_sizes.push_back(tu_size);
-                    tu_times.push_back(pts - cur_tu_time);
+    let name_width = renditions
+        .iter()
+        .map(|r| r.input.len())
+        .chain(std::iter::once("Input".len()))
+        .max()
+        .unwrap_or(0);
+
+    report!(config);
+    report!(
+        config,
+        "{:<name_width$}  {:<11}  {:>10}  {:>9}  {:<18}  Declared level",
+        "Input",
+        "Resolution",
+        "Frame rate",
+        "Max Mbps",
+        "Level",
+        name_width = name_width
+    );
+
+    for r in &renditions {
+        report!(
+            config,
+            "{:<name_width$}  {:<11}  {:>10.3}  {:>9.3}  {:<18}  {}{}",
+            r.input,
+            format!("{}x{}", r.resolution.0, r.resolution.1),
+            r.frame_rate,
+            r.max_mbps,
+            r.level.to_string(),
+            r.declared_level,
+            if r.underdeclared { " (too low)" } else { "" },
+            name_width = name_width
+        );
+    }
+}
 
-                    let mut tu_times_sum = tu_times.iter().sum::<u64>() as f64;
+/// Results for a single input, reported in the summary and ladder tables
+struct Summary {
+    old_level: Level,
+    level: Level,
+    /// Level computed for the input, regardless of any forced level
+    computed_level: Level,
+    /// Average number of shown frames per second
+    frame_rate: f64,
+    max_mbps: f64,
+    /// Maximum frame size signaled in the sequence header
+    resolution: (u16, u16),
+    /// Results written to stdout in JSON format, unless in ladder mode
+    json: Option<serde_json::Value>,
+    /// Whether the input conforms to the level given with `--check`, if any
+    conforms: bool,
+    /// Whether the computed level is within the one given with `--assert-max-level`, if any
+    within_max_level: bool,
+    /// Whether the forced level, if any, is at least the computed level, or `--strict` is not given
+    forced_level_conforms: bool,
+    /// Whether the computed level is a defined one, or `--allow-max-params` is given
+    max_params_allowed: bool,
+}
 
-                    if tu_times_sum >= time_scale.round() {
-                        while tu_times_sum > time_scale.round() {
-                            header_counts.pop_front();
-                            tu_sizes.pop_front();
-                            tu_times.pop_front();
+/// An input that could not be processed, written to stdout in JSON format
+#[derive(Serialize)]
+struct JsonFailure<'a> {
+    input: &'a str,
+    error: String,
+}
 
-                            tu_times_sum = tu_times.iter().sum::<u64>() as f64
-                        }
+/// Results for a single input, written to stdout in JSON format
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    input: &'a str,
+    metadata: &'a ContainerMetadata,
+    operating_points: Vec<JsonOperatingPoint<'a>>,
+    /// Whether a patched stream was written
+    patched: bool,
+    /// Point at which the analysis of a truncated stream stopped, if any
+    truncation: Option<Truncation>,
+    /// Temporal units whose timestamp goes backwards
+    timestamp_jumps: &'a [TimestampJump],
+}
 
-                        let factor = time_scale / tu_times_sum; // adjustment to measure rates per second
+/// Sequence context of a single operating point, written to the file given with `--dump-context`
+#[derive(Serialize)]
+struct JsonContext<'a> {
+    index: usize,
+    context: &'a SequenceContext,
+    /// Output level of the operating point
+    level: Level,
+}
 
-                        let header_rate = f64::from(header_counts.iter().sum::<u32>()) * factor;
-                        max_header_rate = max_header_rate.max(header_rate);
+/// Results for a single operating point, written to stdout in JSON format
+#[derive(Serialize)]
+struct JsonOperatingPoint<'a> {
+    index: usize,
+    idc: u16,
+    context: &'a SequenceContext,
+    min_cr_level_idx: u8,
+    old_level: Level,
+    new_level: Level,
+    /// Constraint that rules out the level below the computed one
+    binding_constraint: Option<&'static str>,
+    /// Lower level that the operating point fits in High tier, if any
+    high_tier_level: Option<Level>,
+    total_show_count: u32,
+    max_header_rate: f64,
+    max_display_rate: f64,
+    max_decode_rate: f64,
+    min_frame_rate: f64,
+    avg_frame_rate: f64,
+    max_frame_rate: f64,
+    max_mbps: f64,
+    /// Timestamps of the temporal units ending the windows over which the maximum rates were measured
+    peak_timestamps: JsonPeakTimestamps,
+    /// Peak bitrate over each window given with --bitrate-window
+    peak_bitrates: &'a [PeakBitrate],
+    max_tiles: u32,
+    max_tile_cols: u32,
+    /// Decoder model parameters, if the sequence header signals them
+    operating_parameters: Option<OperatingParameters>,
+    /// Rates of the tile lists of a large scale tile stream
+    tile_lists: Option<TileListRates>,
+}
 
-                        let mbps =
-                            f64::from(tu_sizes.iter().sum::<u32>()) * factor * 8.0 / 1_000_000.0;
-                        max_mbps = max_mbps.max(mbps);
-                    }
+/// Timestamp of a temporal unit, both in time scale units and in hh:mm:ss.mmm form
+#[derive(Serialize)]
+struct JsonTimestamp {
+    pts: u64,
+    time: String,
+}
 
-                    if let Some(sh) = seq.sh {
-                        let tier = if sh.op[0].seq_tier == 0 {
-                            Tier::Main
-                        } else {
-                            Tier::High
-                        };
-                        let min_pic_compressed_ratio =
-                            calculate_min_pic_compress_ratio(tier, display_rate);
-
-                        for (level_idx, compressed_ratio) in
-                            min_pic_compressed_ratio.iter().enumerate()
-                        {
-                            if min_compressed_ratio >= *compressed_ratio {
-                                min_cr_level_idx = min_cr_level_idx.max(level_idx);
-                                break;
-                            }
-                        }
-                    }
+#[derive(Serialize)]
+struct JsonPeakTimestamps {
+    header_rate: JsonTimestamp,
+    display_rate: JsonTimestamp,
+    decode_rate: JsonTimestamp,
+    mbps: JsonTimestamp,
+}
 
-                    total_show_count += show_count;
+impl JsonPeakTimestamps {
+    fn new(timestamps: &PeakTimestamps, metadata: &ContainerMetadata) -> Self {
+        let timestamp = |pts| JsonTimestamp {
+            pts,
+            time: format_timestamp(pts, metadata),
+        };
 
-                    show_count = 0;
-                    frame_count = 0;
-                    header_count = 0;
-                    tu_size = 0;
-                    min_compressed_ratio = std::f64::MAX;
-                    seen_frame_header = false;
+        JsonPeakTimestamps {
+            header_rate: timestamp(timestamps.header_rate),
+            display_rate: timestamp(timestamps.display_rate),
+            decode_rate: timestamp(timestamps.decode_rate),
+            mbps: timestamp(timestamps.mbps),
+        }
+    }
+}
 
-                    obu::process_obu(&mut reader, &mut seq, &obu);
-                }
-                av1p::obu::OBU_FRAME_HEADER | av1p::obu::OBU_FRAME => {
-                    if let Some(sh) = seq.sh {
-                        if obu.obu_type == av1p::obu::OBU_FRAME_HEADER {
-                            if frame_size > 0 {
-                                let profile_factor = match sh.seq_profile {
-                                    0 => 15,
-                                    1 => 30,
-                                    _ => 36,
-                                };
-                                let uncompressed_size = (picture_size * profile_factor) >> 3; // this assumes a fixed picture size}
-                                min_compressed_ratio = min_compressed_ratio
-                                    .min(uncompressed_size as f64 / frame_size as f64);
-                            }
-
-                            frame_size = i64::from(obu.obu_size) - 128; // this assumes one frame header per frame, coming before other OBUs for this frame
-                            tu_size += obu.obu_size;
-                        } else {
-                            frame_size += i64::from(obu.obu_size);
-                            tu_size += obu.obu_size;
-                        }
-
-                        if let Some(fh) = av1p::obu::parse_frame_header(
-                            &mut reader,
-                            seq.sh.as_ref().unwrap(),
-                            &mut seq.rfman,
-                        ) {
-                            if !seen_frame_header {
-                                last_tu_time = cur_tu_time;
-                                cur_tu_time = pts;
-                            }
-                            seen_frame_header = true;
-
-                            if fh.show_frame || fh.show_existing_frame {
-                                show_count += 1;
-
-                                seq.rfman.output_process(&fh);
-                            }
-
-                            if !fh.show_existing_frame {
-                                header_count += 1; // TODO: detect and do not count duplicate frame headers
-                                frame_count += 1;
-                                seq.rfman.update_process(&fh);
-                            }
-
-                            tile_info = fh.tile_info;
-                            max_tile_cols = max_tile_cols.max(fh.tile_info.tile_cols);
-                            max_tiles =
-                                max_tiles.max(fh.tile_info.tile_cols * fh.tile_info.tile_rows);
-                        }
-                    } else {
-                        panic!("frame header found before sequence header");
-                    }
-                }
-                av1p::obu::OBU_METADATA | av1p::obu::OBU_TILE_GROUP => {
-                    frame_size += i64::from(obu.obu_size);
-                    tu_size += obu.obu_size;
-                }
-                av1p::obu::OBU_TILE_LIST => {
-                    if let Some(tile_list) = av1p::obu::parse_tile_list(&mut reader) {
-                        let mut bytes_per_tile_list = 0;
-
-                        for entry in tile_list.tile_list_entries {
-                            bytes_per_tile_list += entry.tile_data_size_minus_1 + 1;
-                        }
-
-                        max_tile_list_bitrate =
-                            max_tile_list_bitrate.max(bytes_per_tile_list * 8 * 180);
-                        max_tile_decode_rate = max_tile_decode_rate.max(
-                            f64::from(metadata.resolution.0) / f64::from(tile_info.tile_cols)
-                                * f64::from(metadata.resolution.1)
-                                / f64::from(tile_info.tile_rows)
-                                * f64::from(tile_list.tile_count_minus_1 + 1)
-                                * 180.0,
-                        );
-                    }
-                }
-                av1p::obu::OBU_SEQUENCE_HEADER => {
-                    // Track the start location and size of the sequence header OBU for patching.
-                    seq_positions.push(pos);
-                    obu::process_obu(&mut reader, &mut seq, &obu);
-                    seq_sizes.push(obu.obu_size);
-                }
-                _ => {
-                    obu::process_obu(&mut reader, &mut seq, &obu);
-                }
-            }
+/// Formats a timestamp in time scale units as hh:mm:ss.mmm.
+fn format_timestamp(pts: u64, metadata: &ContainerMetadata) -> String {
+    let millis = (pts as f64 / metadata.time_scale() * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
 
-            reader.seek(SeekFrom::Start(pos + u64::from(obu.obu_size)))?;
+/// Describes the bit depth, chroma subsampling, and CICP parameters of a color config, e.g.
+/// "10-bit 4:2:0, color primaries 9, transfer characteristics 16, matrix coefficients 9, limited range".
+fn describe_color_config(color: &av1parser::obu::ColorConfig) -> String {
+    let subsampling = if color.mono_chrome {
+        "4:0:0"
+    } else {
+        match (u8::from(color.subsampling_x), u8::from(color.subsampling_y)) {
+            (1, 1) => "4:2:0",
+            (1, 0) => "4:2:2",
+            _ => "4:4:4",
         }
+    };
 
-        reader.seek(SeekFrom::Start(pos + u64::from(frame.size)))?;
+    format!(
+        "{}-bit {}, color primaries {}, transfer characteristics {}, matrix coefficients {}, {} range",
+        color.BitDepth,
+        subsampling,
+        color.color_primaries,
+        color.transfer_characteristics,
+        color.matrix_coefficients,
+        if u8::from(color.color_range) == 1 {
+            "full"
+        } else {
+            "limited"
+        }
+    )
+}
+
+/// Formats a parameter value for the constraint check report, without decimals for integers.
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 {
+        format!("{:.0}", value)
+    } else {
+        format!("{:.3}", value)
     }
+}
 
-    // Do the final updates for header/display/show rates.
+/// Reports whether an operating point satisfies each constraint of a level, returning whether it satisfies all of them.
+fn check_level(
+    config: &AppConfig,
+    index: usize,
+    op: &elevator::OperatingPoint,
+    level: Level,
+    multiple_operating_points: bool,
+) -> bool {
+    if multiple_operating_points || config.operating_point.is_some() {
+        report!(
+            config,
+            "Operating point {}: Checking against level {}:",
+            index,
+            level
+        );
+    } else {
+        report!(config, "Checking against level {}:", level);
+    }
 
-    // Single frame clips don't move forward in time, so set a minimum delta of the framerate's inverse.
-    let delta_time = ((cur_tu_time - last_tu_time) as f64 / time_scale)
-        .max(1.0 / time_scale * cur_tu_time as f64);
-    let display_rate = f64::from(show_count) / delta_time;
-    max_display_rate = max_display_rate.max(display_rate);
-    max_decode_rate = max_decode_rate
-        .max(f64::from(frame_count) / delta_time)
-        // Tile decode rate is restricted to the level's maximum decode rate halved, so double the input to achieve that effect.
-        .max(max_tile_decode_rate * 2.0);
+    // Level 31 places no limits on the stream, while reserved levels cannot be conformed to.
+    let checks = match level.check(&op.context) {
+        Some(checks) => checks,
+        None if level.0 == 31 => {
+            report!(config, "  Level {} has no limits", level);
+            return true;
+        }
+        None => {
+            report!(
+                config,
+                "  Level {} is reserved, so no stream conforms to it",
+                level
+            );
+            return false;
+        }
+    };
+
+    for check in &checks {
+        report!(
+            config,
+            "  {:<20}  {:>14} <= {:<14}  {}",
+            check.name,
+            format_value(check.value),
+            format_value(check.limit),
+            if check.passed { "pass" } else { "FAIL" }
+        );
+    }
+
+    let cr_passed = op.min_cr_level.0 <= level.0;
+    report!(
+        config,
+        "  {:<20}  {:>14} <= {:<14}  {}",
+        "min_compressed_ratio",
+        op.min_cr_level.0,
+        level.0,
+        if cr_passed { "pass" } else { "FAIL" }
+    );
+
+    cr_passed && checks.iter().all(|check| check.passed)
+}
 
-    header_counts.push_back(header_count);
-    tu_sizes.push_back(tu_size);
-    tu_times.push_back(cur_tu_time - last_tu_time);
+/// Prints the constraints of a level that an operating point violates, and by how much, as errors.
+fn report_violations(op: &elevator::OperatingPoint, level: Level) {
+    for check in level.check(&op.context).unwrap_or_default() {
+        if !check.passed {
+            eprintln!(
+                "  {} {} exceeds the level {} limit {} by {:.2}x",
+                check.name,
+                format_value(check.value),
+                level,
+                format_value(check.limit),
+                check.value / check.limit
+            );
+        }
+    }
 
-    let mut tu_times_sum = tu_times.iter().sum::<u64>() as f64;
+    if op.min_cr_level.0 > level.0 {
+        eprintln!(
+            "  the compressed ratio of some frames requires level {}",
+            op.min_cr_level
+        );
+    }
+}
 
-    // We do not want to interpolate for short clips, since their effective rate per second is the same as their total rate.
-    // However, for clips that fill the one-second buffers, interpolation should occur for the last frame as well.
-    let factor = if tu_times_sum >= time_scale.round() {
-        time_scale / tu_times_sum
+/// Reports how much of each limit of a level an operating point uses, flagging the limits used above `threshold` percent.
+fn report_headroom(
+    config: &AppConfig,
+    index: usize,
+    op: &elevator::OperatingPoint,
+    level: Level,
+    threshold: f64,
+    multiple_operating_points: bool,
+) {
+    if multiple_operating_points || config.operating_point.is_some() {
+        report!(
+            config,
+            "Operating point {}: Headroom at level {}:",
+            index,
+            level
+        );
     } else {
-        1.0
+        report!(config, "Headroom at level {}:", level);
+    }
+
+    let checks = match level.check(&op.context) {
+        Some(checks) => checks,
+        None => {
+            report!(config, "  Level {} has no limits", level);
+            return;
+        }
     };
 
-    while tu_times_sum > time_scale.round() {
-        header_counts.pop_front();
-        tu_sizes.pop_front();
-        tu_times.pop_front();
+    for check in &checks {
+        let utilization = if check.limit > 0.0 {
+            check.value / check.limit * 100.0
+        } else {
+            0.0
+        };
 
-        tu_times_sum = tu_times.iter().sum::<u64>() as f64
+        report!(
+            config,
+            "  {:<20}  {:>14} / {:<14}  ({:.0}%){}",
+            check.name,
+            format_value(check.value),
+            format_value(check.limit),
+            utilization,
+            if utilization > threshold {
+                "  above threshold"
+            } else {
+                ""
+            }
+        );
     }
+}
 
-    let header_rate = f64::from(header_counts.iter().sum::<u32>()) * factor;
-    max_header_rate = max_header_rate.max(header_rate);
+/// Parses the levels of the patched output again, if verification is enabled.
+fn verify_output(
+    config: &AppConfig,
+    output: &str,
+    analysis: &elevator::Analysis,
+    levels: &[Level],
+) -> Result<(), ElevatorError> {
+    if !config.verify {
+        return Ok(());
+    }
 
-    let mbps = f64::from(tu_sizes.iter().sum::<u32>()) * factor * 8.0 / 1_000_000.0;
-    max_mbps = max_mbps.max(mbps);
+    let reader = BufReader::new(File::open(output)?);
+    elevator::verify_patches(reader, analysis, levels, config.forced_tier)?;
 
-    let sh = seq.sh.unwrap(); // sequence header
-    let tier = if sh.op[0].seq_tier == 0 {
-        Tier::Main
-    } else {
-        Tier::High
-    };
-    let min_pic_compressed_ratio = calculate_min_pic_compress_ratio(tier, display_rate);
+    if config.verbose {
+        report!(config, "Verified the levels of the patched output");
+    }
 
-    for (level_idx, compressed_ratio) in min_pic_compressed_ratio.iter().enumerate() {
-        if min_compressed_ratio >= *compressed_ratio {
-            min_cr_level_idx = min_cr_level_idx.max(level_idx);
-            break;
+    Ok(())
+}
+
+/// Reader of an input file, which is memory-mapped with the `mmap` feature
+enum InputReader {
+    Buffered(BufReader<File>),
+    #[cfg(feature = "mmap")]
+    Mapped(io::Cursor<memmap2::Mmap>),
+}
+
+impl InputReader {
+    #[cfg(not(feature = "mmap"))]
+    fn open(input: &str) -> io::Result<Self> {
+        Ok(InputReader::Buffered(BufReader::new(File::open(input)?)))
+    }
+
+    /// Maps the input file into memory, so that the analysis does not make any system call to read or seek it.
+    /// Files that cannot be mapped, like pipes, are read with a buffered reader instead.
+    #[cfg(feature = "mmap")]
+    fn open(input: &str) -> io::Result<Self> {
+        let input_file = File::open(input)?;
+
+        // Safety: the mapped file must not be truncated by another process while it is read.
+        // In-place patches are written through a separate handle and keep the size of the file.
+        match unsafe { memmap2::Mmap::map(&input_file) } {
+            Ok(map) => Ok(InputReader::Mapped(io::Cursor::new(map))),
+            Err(_) => Ok(InputReader::Buffered(BufReader::new(input_file))),
         }
     }
+}
 
-    total_show_count += show_count;
+impl Read for InputReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            InputReader::Buffered(reader) => reader.read(buf),
+            #[cfg(feature = "mmap")]
+            InputReader::Mapped(reader) => reader.read(buf),
+        }
+    }
+}
 
-    if sh.operating_points_cnt > 1 {
-        unimplemented!("streams with multiple operating points not yet supported");
+impl Seek for InputReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            InputReader::Buffered(reader) => reader.seek(pos),
+            #[cfg(feature = "mmap")]
+            InputReader::Mapped(reader) => reader.seek(pos),
+        }
     }
+}
 
-    if config.verbose {
-        println!("Number of displayed frames: {}", total_show_count);
+fn process_input(config: &AppConfig, input: &str) -> Result<Summary, ElevatorError> {
+    // Open the specified input file using a buffered reader, or a memory map with the `mmap` feature.
+    // It is only reopened for writing once the patches are ready, when patching in place.
+    let mut reader = InputReader::open(input)?;
 
-        println!(
-            "Maximum header, display, and decode rates in a single temporal unit: {:.3}, {:.3}, {:.3}",
-            max_header_rate, max_display_rate, max_decode_rate
+    let fmt = elevator::probe_fileformat(&mut reader)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    // The frames do not need to be analyzed when the level is set manually, unless their statistics are reported
+    // or requested with --analyze. The forced level is then only checked against the frame size.
+    let analyzed = config.forced_level.is_none()
+        || config.analyze
+        || config.verbose
+        || config.dry_run
+        || config.ladder
+        || config.tu_stats.is_some()
+        || config.timeline.is_some()
+        || config.dump_context.is_some()
+        || config.headroom.is_some()
+        || config.format == Format::Json;
+
+    let mut timeline = Vec::new();
+    let analysis = if config.tu_stats.is_some() || config.timeline.is_some() {
+        let mut csv = match config.tu_stats {
+            Some(csv_fname) => {
+                let mut csv = BufWriter::new(File::create(csv_fname)?);
+                writeln!(csv, "{}", TU_STATS_HEADER)?;
+                Some(csv)
+            }
+            None => None,
+        };
+
+        // Rows are written as the stream is read, so keep the first write error for later.
+        let mut csv_result = Ok(());
+        let analysis =
+            elevator::analyze_with_tu_stats(&mut reader, fmt, &config.analysis_options, |tu| {
+                if let Some(csv) = &mut csv {
+                    if csv_result.is_ok() {
+                        csv_result = writeln!(csv, "{}", tu_stats_row(tu));
+                    }
+                }
+
+                if config.timeline.is_some() {
+                    timeline.push(*tu);
+                }
+            })?;
+
+        csv_result?;
+        if let Some(mut csv) = csv {
+            csv.flush()?;
+        }
+        analysis
+    } else if !analyzed {
+        elevator::analyze_headers(&mut reader, fmt, &config.analysis_options)?
+    } else {
+        elevator::analyze(&mut reader, fmt, &config.analysis_options)?
+    };
+
+    if let Some(truncation) = analysis.truncation {
+        report!(
+            config,
+            "Warning: the stream is truncated at byte {}, so the results only cover its first {} temporal units, up to {:.3} s",
+            truncation.position,
+            truncation.temporal_units,
+            truncation.timestamp as f64 / analysis.metadata.time_scale()
         );
+    }
 
-        println!(
-            "Minimum level required to satisfy compressed ratio constraint: {}",
-            LEVELS[min_cr_level_idx]
+    if let Some(jump) = analysis.timestamp_jumps.first() {
+        let time_scale = analysis.metadata.time_scale();
+        report!(
+            config,
+            "Warning: the timestamps go backwards {} time(s), first from {:.3} s to {:.3} s at byte {}, so the preceding temporal units were given the median duration of the last ones",
+            analysis.timestamp_jumps.len(),
+            jump.previous_timestamp as f64 / time_scale,
+            jump.timestamp as f64 / time_scale,
+            jump.position
         );
+    }
 
-        println!("Maximum bitrate: {:.3} Mbps", max_mbps);
+    if let Some(timeline_format) = &config.timeline {
+        print_timeline(config, timeline_format, &timeline, &analysis.metadata);
+    }
 
-        println!(
-            "Maximum number of tiles and tile columns found: {}, {}",
-            max_tiles, max_tile_cols
+    if config.verbose {
+        report!(config, "Container metadata:");
+        report!(config, "{}", analysis.metadata);
+
+        // Encoders that cannot seek back to the IVF header leave its frame count at zero.
+        match analysis.metadata.frame_count {
+            Some(frame_count)
+                if frame_count != 0 && u64::from(frame_count) != analysis.frame_count =>
+            {
+                report!(
+                    config,
+                    "Warning: the IVF header declares {} frames, but {} were found, so the file may be truncated",
+                    frame_count,
+                    analysis.frame_count
+                );
+                report!(config);
+            }
+            _ => {}
+        }
+
+        report!(
+            config,
+            "Color config: {}",
+            describe_color_config(&analysis.sequence_header().color_config)
         );
+
+        // Streams with random access points can repeat the sequence header at each key frame.
+        let positions = analysis.sequence_header_positions();
+        if positions.len() > MAX_LISTED_SEQUENCE_HEADERS {
+            report!(
+                config,
+                "Sequence headers: {}, all declaring the same levels, the first at byte {}",
+                positions.len(),
+                positions[0]
+            );
+        } else {
+            report!(
+                config,
+                "Sequence headers: {}, all declaring the same levels, at bytes {:?}",
+                positions.len(),
+                positions
+            );
+        }
+        report!(config);
     }
 
-    // Determine the output level.
-    let level: Level = if config.forced_level.is_some() {
-        config.forced_level.unwrap()
-    } else {
-        // Generate a SequenceContext using the parsed data.
-        let seq_ctx = SequenceContext {
-            tier: if sh.op[0].seq_tier == 0 {
-                Tier::Main
-            } else {
-                Tier::High
-            },
-            pic_size: (sh.max_frame_width as u16, sh.max_frame_height as u16), // (width, height)
-            display_rate: (max_display_rate * picture_size as f64).ceil() as u64,
-            decode_rate: (max_decode_rate * picture_size as f64).ceil() as u64,
-            header_rate: max_header_rate.ceil() as u16,
-            mbps: max_mbps,
-            tiles: max_tiles as u8,
-            tile_cols: max_tile_cols as u8,
-        };
+    // Muxers may declare a stale or rounded resolution, so the rates are only measured with the frame sizes
+    // signaled in the bitstream.
+    let pic_size = analysis.operating_points[0].context.pic_size;
+
+    if pic_size != analysis.metadata.resolution {
+        report!(
+            config,
+            "Warning: the container declares a resolution of {}x{}, but the sequence header signals a maximum frame size of {}x{}, which is used instead",
+            analysis.metadata.resolution.0,
+            analysis.metadata.resolution.1,
+            pic_size.0,
+            pic_size.1
+        );
+    }
 
-        if config.verbose {
-            println!();
-            println!("Sequence context:");
-            println!("{}", seq_ctx);
+    let multiple_operating_points = analysis.operating_points.len() > 1;
+
+    if let Some(index) = config.operating_point {
+        if index >= analysis.operating_points.len() {
+            return Err(ElevatorError::InvalidOperatingPoint {
+                index,
+                count: analysis.operating_points.len(),
+            });
         }
-        LEVELS[usize::from(calculate_level(&seq_ctx).0).max(min_cr_level_idx)]
-    };
+    }
 
-    let old_level = &LEVELS[usize::from(sh.op[0].seq_level_idx)];
+    // Checks whether an operating point is analyzed and patched, which is every one unless one is selected.
+    let selected = |i| config.operating_point.is_none() || config.operating_point == Some(i);
 
-    // Replace the level, if the output is to a file.
-    if config.output != Output::CommandLine {
-        // Copy the file contents from input to output if needed.
-        let output_fname = match config.output {
-            Output::InPlace => config.input,
-            Output::File(fname) => fname,
-            _ => unreachable!(),
-        };
+    // Determine the output level of each operating point.
+    let mut levels = Vec::new();
 
-        if config.output == Output::File(output_fname) {
-            std::fs::copy(config.input, output_fname)?;
+    for (i, op) in analysis.operating_points.iter().enumerate() {
+        // The operating points that are not selected keep their level.
+        if !selected(i) {
+            levels.push(op.old_level);
+            continue;
         }
 
-        // Locate the first level byte by simply counting the bits that come before it.
-        // This is only valid for single operating point sequences.
-        // TODO: properly offset timing and decoder model info and any other missing data that is not decoded by av1parser
-        // TODO: Maybe we shouldn't assume all sequence headers in a file match (making this valid to do out-of-loop)?
-        let lv_bit_offset_in_seq = if sh.reduced_still_picture_header {
-            5
-        } else {
-            // When timing info is present, there may be more nested header data to skip,
-            // but it is not currently handled by av1parser or coded by rav1e.
-            24 + if sh.timing_info_present_flag {
-                unimplemented!()
-            } else {
-                0
+        if config.verbose {
+            if multiple_operating_points {
+                report!(config, "Operating point {} (idc {:#05x}):", i, op.idc);
             }
-        };
 
-        output_file = OpenOptions::new()
-            .write(true)
-            .open(output_fname)
-            .expect("could not open the specified output file");
-        writer = BufWriter::new(output_file);
+            report!(
+                config,
+                "Number of displayed frames: {}",
+                op.total_show_count
+            );
 
-        // Basic sanity check
-        assert_eq!(
-            seq_positions.len(),
-            seq_sizes.len(),
-            "different amount of sequence header obu positions and sizes"
-        );
+            report!(
+                config,
+                "Maximum header, display, and decode rates over one second: {:.3}, {:.3}, {:.3}",
+                op.max_header_rate,
+                op.max_display_rate,
+                op.max_decode_rate
+            );
 
-        for i in 0..seq_positions.len() {
-            let seq_pos = seq_positions[i];
-            let seq_sz = seq_sizes[i];
-            // Both the reader and writer should point to the first byte which contains level bits.
-            let lv_byte_offset = seq_pos + lv_bit_offset_in_seq / 8;
-            reader.seek(SeekFrom::Start(lv_byte_offset))?;
-            writer.seek(SeekFrom::Start(lv_byte_offset))?;
-
-            // Determine the number of bits preceding the level in the byte.
-            let lv_bit_offset_in_byte = lv_bit_offset_in_seq % 8;
-
-            // Generate a bitstream-aligned two-byte sequence containing the level bits.
-            let level_aligned =
-                ((u32::from(level.0) << 11 >> lv_bit_offset_in_byte) as u16).to_be_bytes();
-            // Generate a two-byte mask to filter out the non-level bits.
-            let level_bit_mask =
-                (((0b0001_1111_u32) << 11 >> lv_bit_offset_in_byte) as u16).to_be_bytes();
-            // Generate a single bit mask to identify the tier bit, which immediately follows the level bits.
-            let tier_bit_mask =
-                (((0b0000_0001_u32) << 11 >> lv_bit_offset_in_byte) as u16 >> 1).to_be_bytes();
-            let post_tier_bit_mask =
-                (((0b1111_1111_1111_1111) << 3 >> lv_bit_offset_in_byte >> 8 >> 1) as u16)
-                    .to_be_bytes();
-
-            if config.verbose {
-                println!(
-                    "Patching sequence header #{} with offset {}",
-                    i, lv_bit_offset_in_byte
-                );
+            let timestamp =
+                |pts| format!("{} ({})", format_timestamp(pts, &analysis.metadata), pts);
+            report!(
+                config,
+                "Measured over the seconds ending at: {}, {}, {}",
+                timestamp(op.peak_timestamps.header_rate),
+                timestamp(op.peak_timestamps.display_rate),
+                timestamp(op.peak_timestamps.decode_rate)
+            );
 
-                if i == 0 {
-                    println!(
-                        "Level bits: {:#010b}, {:#010b}",
-                        level_aligned[0], level_aligned[1]
-                    );
-                    println!(
-                        "Level/tier/post-tier bit masks: {:#018b}/{:#018b}/{:#018b}",
-                        u16::from_be_bytes(level_bit_mask),
-                        u16::from_be_bytes(tier_bit_mask),
-                        u16::from_be_bytes(post_tier_bit_mask)
-                    );
-                }
-            }
+            report!(
+                config,
+                "Minimum, average, and maximum frame rates: {:.3}, {:.3}, {:.3}",
+                op.min_frame_rate,
+                op.avg_frame_rate,
+                op.max_frame_rate
+            );
 
-            let mut byte_buf = [0_u8; 2];
-            reader
-                .read_exact(&mut byte_buf)
-                .expect("could not read the level byte(s)");
+            report!(
+                config,
+                "Minimum level required to satisfy compressed ratio constraint: {}",
+                op.min_cr_level
+            );
 
-            // Ensure that the bytes read from the input file correspond to the level parsed earlier.
-            assert_eq!(
-                old_level.0,
-                (u32::from(u16::from_be_bytes(byte_buf)) >> 11 << lv_bit_offset_in_byte) as u8,
-                "level at the location seeked to patch does not match the parsed value"
+            report!(
+                config,
+                "Maximum bitrate: {:.3} Mbps, measured over the window ending at {}",
+                op.max_mbps,
+                timestamp(op.peak_timestamps.mbps)
+            );
+
+            report!(
+                config,
+                "Maximum number of tiles and tile columns found: {}, {}",
+                op.max_tiles,
+                op.max_tile_cols
             );
 
-            if config.verbose {
-                print!(
-                    "input/output bytes: {:#010b}, {:#010b} / ",
-                    byte_buf[0], byte_buf[1]
+            if let Some(parameters) = op.operating_parameters {
+                report!(
+                    config,
+                    "Decoder model: decoder buffer delay {}, encoder buffer delay {}, low delay mode {}",
+                    parameters.decoder_buffer_delay,
+                    parameters.encoder_buffer_delay,
+                    u8::from(parameters.low_delay_mode_flag)
                 );
             }
 
-            // Modify the input bytes such that the level bits match the target level.
-            byte_buf[0] = byte_buf[0] & !level_bit_mask[0] | level_aligned[0];
-            byte_buf[1] = byte_buf[1] & !level_bit_mask[1] | level_aligned[1];
+            if let Some(tile_lists) = op.tile_lists {
+                report!(
+                    config,
+                    "Large scale tile: {} tile lists, at most {:.3} Mbps, {:.3} frames decoded per second and {} tiles",
+                    tile_lists.count,
+                    tile_lists.mbps,
+                    tile_lists.decode_rate,
+                    tile_lists.max_tiles
+                );
+            }
 
-            let tier_adjusted_bits: [u8; 2];
-            let mut next_input_byte = [0_u8; 1]; // when removing a tier bit (reader runs ahead)
-            let mut carry_bit = 0_u8; // used when adding a tier bit (reader runs behind)
+            if let Some(constraint) = op.binding_constraint {
+                report!(
+                    config,
+                    "Level {} required by: {}{}",
+                    op.level,
+                    constraint,
+                    if matches!(op.tile_lists, Some(tile_lists) if tile_lists.drives_level) {
+                        " of the tile lists"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+
+        for peak in &op.peak_bitrates {
+            if multiple_operating_points {
+                report!(
+                    config,
+                    "Operating point {}: peak bitrate over {} s: {:.3} Mbps",
+                    i,
+                    peak.window,
+                    peak.mbps
+                );
+            } else {
+                report!(
+                    config,
+                    "Peak bitrate over {} s: {:.3} Mbps",
+                    peak.window,
+                    peak.mbps
+                );
+            }
+        }
 
-            if old_level.0 > 7 && level.0 <= 7 {
-                // The tier bit must be removed.
-                // In that case, ensure that the tier bit is 0 (Main tier).
-                if byte_buf[0] & tier_bit_mask[0] > 0 || byte_buf[1] & tier_bit_mask[1] > 0 {
-                    panic!("cannot reduce level below 4.0 when High tier is specified");
+        levels.push(match config.forced_level {
+            Some(level) => level,
+            None => {
+                if config.verbose || config.dry_run {
+                    report!(config);
+                    report!(config, "Sequence context:");
+                    report!(config, "{}", op.context);
                 }
 
-                // Read one byte ahead, to shift the second byte in the current two-byte sequence.
-                reader
-                    .read_exact(&mut next_input_byte)
-                    .expect("could not read the post-tier byte");
-
-                tier_adjusted_bits = [
-                    (byte_buf[0] << 1) | (byte_buf[1] >> 7) & post_tier_bit_mask[0],
-                    (byte_buf[1] << 1 | (next_input_byte[0] >> 7) & post_tier_bit_mask[1]),
-                ];
-            } else if old_level.0 <= 7 && level.0 > 7 {
-                // The tier bit must be added.
-                tier_adjusted_bits = [
-                    (byte_buf[0] >> 1) & !tier_bit_mask[0],
-                    (byte_buf[1] >> 1) & !tier_bit_mask[1] | byte_buf[0] << 7,
-                ];
-
-                // The last bit is shifted out of the two-byte range, and must be
-                // stored to realign the rest of the bitstream. (TODO)
-                carry_bit = byte_buf[1] << 7;
-            } else {
-                // No adjustment is needed.
-                tier_adjusted_bits = byte_buf;
+                if op.level.0 > MAX_DEFINED_LEVEL {
+                    report!(
+                        config,
+                        "Note: the stream exceeds the {} limit of the highest defined level, {}, so it can only signal {}",
+                        op.binding_constraint.unwrap_or("unknown"),
+                        LEVELS[usize::from(MAX_DEFINED_LEVEL)],
+                        op.level
+                    );
+                }
+
+                op.level
             }
+        });
+    }
 
-            byte_buf[0] = level_aligned[0]
-                | (tier_adjusted_bits[0] & (tier_bit_mask[0] | post_tier_bit_mask[0]));
-            byte_buf[1] = level_aligned[1]
-                | (tier_adjusted_bits[1] & (tier_bit_mask[1] | post_tier_bit_mask[1]));
+    if let Some(json_fname) = config.dump_context {
+        let contexts = analysis
+            .operating_points
+            .iter()
+            .zip(&levels)
+            .enumerate()
+            .filter(|&(i, _)| selected(i))
+            .map(|(i, (op, &level))| JsonContext {
+                index: i,
+                context: &op.context,
+                level,
+            })
+            .collect::<Vec<_>>();
+
+        let mut json_file = BufWriter::new(File::create(json_fname)?);
+        serde_json::to_writer_pretty(&mut json_file, &contexts).map_err(io::Error::from)?;
+        writeln!(json_file)?;
+        json_file.flush()?;
+    }
 
-            if config.verbose {
-                println!("{:#010b}, {:#010b}", byte_buf[0], byte_buf[1]);
+    let old_level = analysis.operating_points[0].old_level;
+    let mut conforms = true;
+
+    if let Some(check) = config.check_level {
+        for (i, op) in analysis.operating_points.iter().enumerate() {
+            if selected(i) {
+                let level = match check {
+                    CheckLevel::Declared => op.old_level,
+                    CheckLevel::Level(level) => level,
+                };
+                conforms &= check_level(config, i, op, level, multiple_operating_points);
             }
+        }
+    }
 
-            writer
-                .write_all(&byte_buf)
-                .expect("could not write the level byte(s)");
+    if let Some(threshold) = config.headroom {
+        for (i, (op, &level)) in analysis.operating_points.iter().zip(&levels).enumerate() {
+            if selected(i) {
+                report_headroom(config, i, op, level, threshold, multiple_operating_points);
+            }
+        }
+    }
 
-            // Realign the rest of the sequence header OBU if needed (i.e. if a tier bit is added/removed).
-            let mut pos_in_seq = lv_bit_offset_in_seq / 8 + 2; // writer's position within the sequence header
-            let mut next_output_byte: u8;
+    let mut within_max_level = true;
 
-            while pos_in_seq < seq_sz.into() {
-                if old_level.0 > 7 && level.0 <= 7 {
-                    // Due to the earlier shifting, the reader is always one byte ahead.
-                    let prev_input_byte = next_input_byte;
+    if let Some(max_level) = config.assert_max_level {
+        for (i, op) in analysis.operating_points.iter().enumerate() {
+            if selected(i) && op.level.0 > max_level.0 {
+                within_max_level = false;
+
+                eprintln!(
+                    "error: {}: level {} is required, which exceeds the maximum level {}",
+                    input, op.level, max_level
+                );
+                report_violations(op, max_level);
+            }
+        }
 
-                    reader
-                        .read_exact(&mut next_input_byte)
-                        .expect("could not read sequence header OBU byte");
+        if !within_max_level && config.output != Output::CommandLine {
+            eprintln!("error: {}: no file was written", input);
+        }
+    }
 
-                    next_output_byte = (prev_input_byte[0] << 1) | (next_input_byte[0] >> 7);
-                } else if old_level.0 <= 7 && level.0 > 7 {
-                    reader
-                        .read_exact(&mut next_input_byte)
-                        .expect("could not read sequence header OBU byte");
+    let mut forced_level_conforms = true;
 
-                    next_output_byte = next_input_byte[0] >> 1 | carry_bit;
-                    carry_bit = next_input_byte[0] << 7;
+    // A forced level higher than necessary is fine, but a lower one produces a non-conforming stream.
+    if let Some(forced_level) = config.forced_level {
+        for (i, op) in analysis.operating_points.iter().enumerate() {
+            if selected(i) && op.level.0 > forced_level.0 {
+                let severity = if config.strict {
+                    forced_level_conforms = false;
+                    "error"
                 } else {
-                    break;
+                    "warning"
+                };
+
+                eprintln!(
+                    "{}: {}: the forced level {} is lower than the level {} required by {}operating point {}",
+                    severity,
+                    input,
+                    forced_level,
+                    op.level,
+                    if analyzed { "" } else { "the frame size of " },
+                    i
+                );
+                report_violations(op, forced_level);
+            }
+        }
+
+        if !forced_level_conforms && config.output != Output::CommandLine {
+            eprintln!("error: {}: no file was written", input);
+        }
+    }
+
+    let mut max_params_allowed = true;
+
+    // Level index 31 places no limits on decoders, so it is only signaled when asked for.
+    if config.output != Output::CommandLine
+        && config.forced_level.is_none()
+        && !config.allow_max_params
+    {
+        max_params_allowed = !analysis
+            .operating_points
+            .iter()
+            .enumerate()
+            .any(|(i, op)| selected(i) && op.level.0 > MAX_DEFINED_LEVEL);
+
+        if !max_params_allowed {
+            eprintln!(
+                "error: {}: no file was written, use --allow-max-params to signal level {}",
+                input, LEVELS[31]
+            );
+        }
+    }
+
+    let writable = within_max_level && forced_level_conforms && max_params_allowed;
+
+    // MP4 and WebM files repeat the level in the codec configuration record header.
+    if let Some(record_level) = analysis.config_record_level {
+        if config.verbose {
+            report!(config, "Configuration record level: {}", record_level);
+        }
+
+        if record_level.0 != old_level.0 && config.output != Output::CommandLine && !config.force {
+            return Err(ElevatorError::ConfigRecordMismatch {
+                record_level: record_level.0,
+                sequence_level: old_level.0,
+            });
+        }
+    }
+
+    // The sequence header cannot code High tier below level 4.0, but the configuration record always codes the tier.
+    // Patching rewrites the tier of the record, so such an input is never left unchanged.
+    if let (Some(record_level), Some(Tier::High)) =
+        (analysis.config_record_level, analysis.config_record_tier)
+    {
+        if record_level.0 <= 7 {
+            eprintln!(
+                "warning: {}: the configuration record signals High tier at level {}, but High tier is only defined for levels 4.0 and above",
+                input, record_level
+            );
+        }
+    }
+
+    // An input that already signals the output levels is not opened for writing when patching in place.
+    let unchanged =
+        config.output == Output::InPlace && analysis.signals_levels(&levels, config.forced_tier);
+
+    if unchanged && writable && config.verbose {
+        report!(
+            config,
+            "The levels are unchanged, so the file was not modified"
+        );
+    }
+
+    // Replace the level, if the output is to a file.
+    if config.output != Output::CommandLine && writable && !unchanged {
+        // A new IVF file is rebuilt from the OBUs, rather than patched.
+        let patches = match config.output {
+            Output::Ivf(_) => Vec::new(),
+            _ => elevator::prepare_patches(
+                &mut reader,
+                &analysis,
+                &levels,
+                config.forced_tier,
+                config.output == Output::InPlace,
+            )?,
+        };
+
+        if config.verbose || config.dry_run {
+            if let Output::Ivf(output_fname) = config.output {
+                report!(config, "Rewriting every frame into {}", output_fname);
+            }
+
+            for patch in &patches {
+                match &patch.target {
+                    PatchTarget::SequenceHeader {
+                        index,
+                        level_bit_offsets,
+                    } => report!(
+                        config,
+                        "Patching sequence header #{} with level bit offsets {:?}",
+                        index,
+                        level_bit_offsets
+                    ),
+                    PatchTarget::ConfigRecord => {
+                        report!(config, "Patching codec configuration record")
+                    }
+                    PatchTarget::FrameSize => {
+                        report!(config, "Patching frame size at byte {}", patch.position)
+                    }
                 }
+            }
+        }
 
-                writer
-                    .write_all(&[next_output_byte])
-                    .expect("could not write sequence header OBU byte");
+        match config.output {
+            _ if config.dry_run => report!(config, "Dry run, so no file was written"),
+            Output::InPlace => {
+                // The backup is complete on disk before the input is modified.
+                if let Some(suffix) = config.backup {
+                    let backup_fname = format!("{}{}", input, suffix);
+                    let backup = elevator::Backup::new(&mut reader, &patches)?;
+                    let mut backup_file = BufWriter::new(File::create(&backup_fname)?);
+                    backup.write(&mut backup_file)?;
+                    backup_file.flush()?;
+                    backup_file.get_ref().sync_all()?;
+
+                    if config.verbose {
+                        report!(config, "Saved the original bytes to {}", backup_fname);
+                    }
+                }
 
-                pos_in_seq += 1;
+                let output_file = OpenOptions::new().write(true).open(input)?;
+                elevator::apply_patches(BufWriter::new(output_file), &patches)?;
+                verify_output(config, input, &analysis, &levels)?;
             }
+            Output::File(output_fname) | Output::Ivf(output_fname) => {
+                let output_file = BufWriter::new(File::create(output_fname)?);
+
+                match config.output {
+                    Output::Ivf(_) => elevator::remux_ivf(
+                        &mut reader,
+                        output_file,
+                        &analysis,
+                        &levels,
+                        config.forced_tier,
+                    )?,
+                    _ => elevator::stream_patches(&mut reader, output_file, &patches)?,
+                }
 
-            writer.flush()?;
+                // An output that fails verification is not left behind.
+                if let Err(err) = verify_output(config, output_fname, &analysis, &levels) {
+                    if let Err(remove_err) = std::fs::remove_file(output_fname) {
+                        eprintln!("error: could not delete {}: {}", output_fname, remove_err);
+                    }
+
+                    return Err(err);
+                }
+            }
+            Output::Stdout => {
+                let stdout = io::stdout();
+                elevator::stream_patches(&mut reader, stdout.lock(), &patches)?;
+            }
+            Output::CommandLine => unreachable!(),
         }
     }
 
-    println!("Level: {} -> {}", old_level, level);
+    // Prefix the results with the input name when processing multiple inputs.
+    let prefix = if config.inputs.len() > 1 {
+        format!("{}: ", input)
+    } else {
+        String::new()
+    };
+
+    // Results from a truncated stream are marked as partial.
+    let suffix = match (analysis.truncation.is_some(), unchanged) {
+        (true, true) => " (partial, unchanged)",
+        (true, false) => " (partial)",
+        (false, true) => " (unchanged)",
+        (false, false) => "",
+    };
 
-    Ok(())
+    for (i, (op, level)) in analysis.operating_points.iter().zip(&levels).enumerate() {
+        if !selected(i) {
+            continue;
+        }
+
+        if multiple_operating_points || config.operating_point.is_some() {
+            report!(
+                config,
+                "{}Operating point {}: Level: {} -> {}{}",
+                prefix,
+                i,
+                op.old_level,
+                level,
+                suffix
+            );
+        } else {
+            report!(
+                config,
+                "{}Level: {} -> {}{}",
+                prefix,
+                op.old_level,
+                level,
+                suffix
+            );
+        }
+
+        // Reserved levels have no limits to check against, and still code a tier bit like level 31.
+        if op.old_level.limits().is_none() && op.old_level.0 != 31 {
+            report!(
+                config,
+                "{}Note: the input signals the reserved level index {}, which no stream conforms to",
+                prefix,
+                op.old_level.0
+            );
+        }
+
+        // The tier is only changed on request, since Main tier decoders cannot decode High tier streams.
+        if let (Some(high_tier_level), None, None) =
+            (op.high_tier_level, config.forced_level, config.forced_tier)
+        {
+            report!(
+                config,
+                "{}Note: the stream fits level {} in High tier, which --tier high signals instead",
+                prefix,
+                high_tier_level
+            );
+        }
+    }
+
+    // In ladder mode, the JSON results only describe the ladder as a whole.
+    let mut json = None;
+
+    if config.format == Format::Json && !config.ladder {
+        let report = JsonReport {
+            input,
+            metadata: &analysis.metadata,
+            operating_points: analysis
+                .operating_points
+                .iter()
+                .zip(&levels)
+                .enumerate()
+                .filter(|&(i, _)| selected(i))
+                .map(|(i, (op, &level))| JsonOperatingPoint {
+                    index: i,
+                    idc: op.idc,
+                    context: &op.context,
+                    min_cr_level_idx: op.min_cr_level.0,
+                    old_level: op.old_level,
+                    new_level: level,
+                    binding_constraint: op.binding_constraint,
+                    high_tier_level: op.high_tier_level,
+                    total_show_count: op.total_show_count,
+                    max_header_rate: op.max_header_rate,
+                    max_display_rate: op.max_display_rate,
+                    max_decode_rate: op.max_decode_rate,
+                    min_frame_rate: op.min_frame_rate,
+                    avg_frame_rate: op.avg_frame_rate,
+                    max_frame_rate: op.max_frame_rate,
+                    max_mbps: op.max_mbps,
+                    peak_timestamps: JsonPeakTimestamps::new(
+                        &op.peak_timestamps,
+                        &analysis.metadata,
+                    ),
+                    peak_bitrates: &op.peak_bitrates,
+                    max_tiles: op.max_tiles,
+                    max_tile_cols: op.max_tile_cols,
+                    operating_parameters: op.operating_parameters,
+                    tile_lists: op.tile_lists,
+                })
+                .collect(),
+            patched: config.output != Output::CommandLine
+                && !config.dry_run
+                && writable
+                && !unchanged,
+            truncation: analysis.truncation,
+            timestamp_jumps: &analysis.timestamp_jumps,
+        };
+
+        json = Some(serde_json::to_value(&report).map_err(io::Error::from)?);
+    }
+
+    let index = config.operating_point.unwrap_or(0);
+    let op = &analysis.operating_points[index];
+
+    Ok(Summary {
+        old_level: op.old_level,
+        level: levels[index],
+        computed_level: op.level,
+        frame_rate: op.avg_frame_rate,
+        max_mbps: op.max_mbps,
+        resolution: pic_size,
+        json,
+        conforms,
+        within_max_level,
+        forced_level_conforms,
+        max_params_allowed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a command line, with the input file appended.
+    fn parse(args: &[&str]) -> Result<(), clap::ErrorKind> {
+        let args = std::iter::once("elevator")
+            .chain(args.iter().copied())
+            .chain(std::iter::once("input.ivf"));
+
+        app()
+            .get_matches_from_safe(args)
+            .map(|_| ())
+            .map_err(|err| err.kind)
+    }
+
+    #[test]
+    fn test_conflicting_args() {
+        let conflicts: &[&[&str]] = &[
+            &["analyze", "--verbose", "--quiet"],
+            &["patch", "--inplace", "--output", "output.ivf"],
+            &[
+                "patch",
+                "--output-ivf",
+                "output.ivf",
+                "--output",
+                "copy.ivf",
+            ],
+            &["patch", "--output-ivf", "output.ivf", "--inplace"],
+            &[
+                "patch",
+                "--inplace",
+                "--forcedlevel",
+                "4.0",
+                "--assert-max-level",
+                "5.0",
+            ],
+            &[
+                "patch",
+                "--inplace",
+                "--forcedlevel",
+                "4.0",
+                "--allow-max-params",
+            ],
+            &["patch", "--restore", "--output", "output.ivf"],
+            &["patch", "--restore", "--output-ivf", "output.ivf"],
+            &["patch", "--restore", "--inplace"],
+            &["patch", "--restore", "--forcedlevel", "4.0"],
+            &["patch", "--restore", "--tier", "high"],
+            &["patch", "--restore", "--op", "0"],
+            &["patch", "--restore", "--dry-run"],
+            &["patch", "--inplace", "--verbose", "--quiet"],
+            // The deprecated flat form accepts both the analyze and patch arguments.
+            &["--check", "--output", "output.ivf"],
+            &["--check", "--inplace"],
+            &["--check", "--forcedlevel", "4.0"],
+            &["--check", "--restore"],
+            &["--ladder", "--restore"],
+            &["--forcedlevel", "4.0", "--assert-max-level", "5.0"],
+        ];
+
+        for args in conflicts {
+            assert_eq!(
+                Err(clap::ErrorKind::ArgumentConflict),
+                parse(args),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn test_subcommand_args() {
+        assert_eq!(Ok(()), parse(&["analyze", "--check", "--ladder"]));
+        assert_eq!(
+            Ok(()),
+            parse(&["patch", "--inplace", "--backup", "--verbose"])
+        );
+
+        // Writing the inputs is not an option of the analyze subcommand, nor checking them of the patch subcommand.
+        assert_eq!(
+            Err(clap::ErrorKind::UnknownArgument),
+            parse(&["analyze", "--check", "--inplace"])
+        );
+        assert_eq!(
+            Err(clap::ErrorKind::UnknownArgument),
+            parse(&["patch", "--restore", "--check"])
+        );
+    }
 }
@@ -0,0 +1,222 @@
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+const EBML_SEGMENT: u32 = 0x1853_8067;
+const EBML_INFO: u32 = 0x1549_A966;
+const EBML_TIMECODE_SCALE: u32 = 0x2A_D7B1;
+const EBML_TRACKS: u32 = 0x1654_AE6B;
+const EBML_TRACK_ENTRY: u32 = 0xAE;
+const EBML_TRACK_NUMBER: u32 = 0xD7;
+const EBML_CODEC_ID: u32 = 0x86;
+const EBML_CODEC_PRIVATE: u32 = 0x63A2;
+const EBML_VIDEO: u32 = 0xE0;
+const EBML_PIXEL_WIDTH: u32 = 0xB0;
+const EBML_PIXEL_HEIGHT: u32 = 0xBA;
+const EBML_CLUSTER: u32 = 0x1F43_B675;
+const EBML_TIMECODE: u32 = 0xE7;
+const EBML_SIMPLE_BLOCK: u32 = 0xA3;
+const EBML_BLOCK_GROUP: u32 = 0xA0;
+const EBML_BLOCK: u32 = 0xA1;
+
+/// A single AV1 block (temporal unit) located in the file
+#[derive(Clone)]
+pub struct MkvBlock {
+    /// Absolute position of the first byte of the frame data
+    pub offset: u64,
+    /// Size of the frame data in bytes
+    pub size: u32,
+    /// Presentation timestamp of the block at the time scale of the segment
+    pub pts: u64,
+}
+
+/// The AV1 track of a Matroska/WebM file, with all of its blocks
+pub struct MkvTrack {
+    /// Time scale of the segment, as a rational (numerator, denominator)
+    pub time_scale: (u32, u32),
+    /// Track width and height in pixels
+    pub resolution: (u16, u16),
+    /// Position of the AV1CodecConfigurationRecord stored in `CodecPrivate`, if any
+    pub config_record: Option<u64>,
+    /// Position and size of the configOBUs stored in `CodecPrivate`, if any
+    pub config_obus: Option<(u64, u32)>,
+    /// Blocks in file order
+    pub blocks: Vec<MkvBlock>,
+}
+
+/// Track information gathered from the elements nested in a `TrackEntry`
+#[derive(Default)]
+struct TrackEntry {
+    number: u64,
+    codec_id: String,
+    resolution: (u16, u16),
+    codec_private: Option<(u64, u64)>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads an EBML variable-size integer, returning its value and length in bytes.
+/// The length marker is kept for element IDs and stripped for everything else.
+fn read_vint<R: Read>(reader: &mut R, keep_marker: bool) -> io::Result<(u64, u32)> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+
+    let len = byte[0].leading_zeros() + 1;
+    if len > 8 {
+        return Err(invalid_data("invalid EBML variable-size integer"));
+    }
+
+    let mut value = if keep_marker {
+        u64::from(byte[0])
+    } else {
+        u64::from(byte[0]) & (0xFF >> len)
+    };
+
+    for _ in 1..len {
+        reader.read_exact(&mut byte)?;
+        value = value << 8 | u64::from(byte[0]);
+    }
+
+    Ok((value, len))
+}
+
+/// Reads an element header, returning the element ID and its size (`None` if unknown).
+fn read_element_header<R: Read>(reader: &mut R) -> io::Result<(u32, Option<u64>)> {
+    let (id, _) = read_vint(reader, true)?;
+    let (size, len) = read_vint(reader, false)?;
+
+    // A size field with all value bits set denotes an unknown size.
+    if size == (1 << (7 * len)) - 1 {
+        Ok((id as u32, None))
+    } else {
+        Ok((id as u32, Some(size)))
+    }
+}
+
+fn read_uint<R: Read>(reader: &mut R, size: u64) -> io::Result<u64> {
+    if size > 8 {
+        return Err(invalid_data("invalid EBML unsigned integer size"));
+    }
+
+    let mut value = 0;
+    let mut byte = [0; 1];
+
+    for _ in 0..size {
+        reader.read_exact(&mut byte)?;
+        value = value << 8 | u64::from(byte[0]);
+    }
+
+    Ok(value)
+}
+
+/// Locates the AV1 track of a Matroska/WebM file and all of its blocks.
+pub fn parse_mkv<R: Read + Seek>(reader: &mut R) -> io::Result<MkvTrack> {
+    let file_end = reader.seek(SeekFrom::End(0))?;
+    let mut timecode_scale = 1_000_000; // nanoseconds per timestamp unit
+    let mut tracks = Vec::<TrackEntry>::new();
+    let mut cluster_timecode = 0;
+    let mut blocks = Vec::new(); // (track number, block)
+
+    // Walk the file linearly, descending into the master elements of interest.
+    // This also handles the unknown-size segments and clusters written by live muxers.
+    let mut pos = 0;
+
+    while pos < file_end {
+        reader.seek(SeekFrom::Start(pos))?;
+        let (id, size) = read_element_header(reader)?;
+        let start = reader.seek(SeekFrom::Current(0))?;
+
+        match id {
+            EBML_SEGMENT | EBML_INFO | EBML_TRACKS | EBML_TRACK_ENTRY | EBML_VIDEO
+            | EBML_CLUSTER | EBML_BLOCK_GROUP => {
+                if id == EBML_TRACK_ENTRY {
+                    tracks.push(TrackEntry::default());
+                }
+
+                pos = start;
+                continue;
+            }
+            _ => {}
+        }
+
+        let size = size.ok_or_else(|| invalid_data("unknown size for a non-master element"))?;
+        let end = start + size;
+
+        if end > file_end {
+            return Err(invalid_data(
+                "EBML element extends past the end of the file",
+            ));
+        }
+
+        match id {
+            EBML_TIMECODE_SCALE => timecode_scale = read_uint(reader, size)?,
+            EBML_TRACK_NUMBER | EBML_CODEC_ID | EBML_CODEC_PRIVATE | EBML_PIXEL_WIDTH
+            | EBML_PIXEL_HEIGHT => {
+                if let Some(track) = tracks.last_mut() {
+                    match id {
+                        EBML_TRACK_NUMBER => track.number = read_uint(reader, size)?,
+                        EBML_CODEC_ID => {
+                            let mut codec_id = vec![0; size as usize];
+                            reader.read_exact(&mut codec_id)?;
+                            track.codec_id = String::from_utf8_lossy(&codec_id)
+                                .trim_end_matches('\0')
+                                .to_string();
+                        }
+                        EBML_CODEC_PRIVATE => track.codec_private = Some((start, size)),
+                        EBML_PIXEL_WIDTH => track.resolution.0 = read_uint(reader, size)? as u16,
+                        _ => track.resolution.1 = read_uint(reader, size)? as u16,
+                    }
+                }
+            }
+            EBML_TIMECODE => cluster_timecode = read_uint(reader, size)?,
+            EBML_SIMPLE_BLOCK | EBML_BLOCK => {
+                let (track_number, track_number_len) = read_vint(reader, false)?;
+                let mut header = [0; 3]; // relative timecode and flags
+                reader.read_exact(&mut header)?;
+
+                if header[2] & 0b0000_0110 != 0 {
+                    return Err(invalid_data("laced AV1 blocks are not supported"));
+                }
+
+                let relative_timecode = i16::from_be_bytes([header[0], header[1]]);
+                let offset = start + u64::from(track_number_len) + 3;
+
+                blocks.push((
+                    track_number,
+                    MkvBlock {
+                        offset,
+                        size: end.saturating_sub(offset) as u32,
+                        pts: (cluster_timecode as i64 + i64::from(relative_timecode)).max(0) as u64,
+                    },
+                ));
+            }
+            _ => {}
+        }
+
+        pos = end;
+    }
+
+    let track = tracks
+        .into_iter()
+        .find(|t| t.codec_id == "V_AV1")
+        .ok_or_else(|| invalid_data("no AV1 track found"))?;
+
+    let (config_record, config_obus) = match track.codec_private {
+        // The configOBUs follow the 4-byte AV1CodecConfigurationRecord header.
+        Some((start, size)) if size >= 4 => (Some(start), Some((start + 4, (size - 4) as u32))),
+        _ => (None, None),
+    };
+
+    Ok(MkvTrack {
+        time_scale: (1_000_000_000, timecode_scale as u32),
+        resolution: track.resolution,
+        config_record,
+        config_obus,
+        blocks: blocks
+            .into_iter()
+            .filter(|&(number, _)| number == track.number)
+            .map(|(_, block)| block)
+            .collect(),
+    })
+}
@@ -0,0 +1,673 @@
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+/// A single AV1 sample (temporal unit) located in the file
+#[derive(Clone)]
+pub struct Mp4Sample {
+    /// Absolute position of the first byte of the sample
+    pub offset: u64,
+    /// Size of the sample in bytes
+    pub size: u32,
+    /// Decode timestamp of the sample at the time scale of the track. Composition offsets (`ctts`) are not read,
+    /// so it also stands for the display timestamp.
+    pub dts: u64,
+}
+
+/// The AV1 track of an ISOBMFF file, with all of its samples
+pub struct Mp4Track {
+    /// Media time scale, in units per second (from `mdhd`)
+    pub timescale: u32,
+    /// Track width and height in pixels (from `tkhd`)
+    pub resolution: (u16, u16),
+    /// Position of the AV1CodecConfigurationRecord stored in the `av1C` box, if any
+    pub config_record: Option<u64>,
+    /// Position and size of the configOBUs stored in the `av1C` box, if any
+    pub config_obus: Option<(u64, u32)>,
+    /// Samples in decode order, from both the sample table and any movie fragments
+    pub samples: Vec<Mp4Sample>,
+}
+
+/// An ISOBMFF box header
+#[derive(Clone, Copy)]
+struct BoxHeader {
+    /// Four-character box type
+    box_type: [u8; 4],
+    /// Absolute position of the first byte of the box header
+    offset: u64,
+    /// Absolute position of the first byte of the box payload
+    start: u64,
+    /// Absolute position immediately following the box
+    end: u64,
+}
+
+/// Track information gathered from the boxes nested in a `trak` box
+#[derive(Default)]
+struct TrackBoxes {
+    track_id: u32,
+    resolution: (u16, u16),
+    timescale: u32,
+    is_av1: bool,
+    config_record: Option<u64>,
+    config_obus: Option<(u64, u32)>,
+    /// (sample_count, sample_delta) runs from `stts`
+    time_to_sample: Vec<(u32, u32)>,
+    /// Per-sample sizes from `stsz`
+    sample_sizes: Vec<u32>,
+    /// (first_chunk, samples_per_chunk) runs from `stsc`
+    sample_to_chunk: Vec<(u32, u32)>,
+    /// Chunk offsets from `stco` or `co64`
+    chunk_offsets: Vec<u64>,
+}
+
+/// Default sample parameters for a track, from `trex`
+#[derive(Clone, Copy, Default)]
+struct TrackDefaults {
+    sample_duration: u32,
+    sample_size: u32,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Checks that `entry_count` entries of `entry_size` bytes, following `fields_len` bytes of other fields,
+/// fit in the payload of a box, since the count is read from the file before the entries.
+fn check_entry_count(
+    header: &BoxHeader,
+    fields_len: u64,
+    entry_count: u32,
+    entry_size: u64,
+) -> io::Result<()> {
+    let payload_len = (header.end - header.start).saturating_sub(fields_len);
+
+    if u64::from(entry_count) * entry_size > payload_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the {} box at byte {} declares {} entries, which exceed its size",
+                String::from_utf8_lossy(&header.box_type),
+                header.offset,
+                entry_count
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that `sample_count` samples of `sample_size` bytes fit in a file of `file_end` bytes, for the samples
+/// whose size is given once for all of them rather than per sample.
+fn check_sample_count(
+    header: &BoxHeader,
+    sample_count: u32,
+    sample_size: u32,
+    file_end: u64,
+) -> io::Result<()> {
+    // Every sample holds at least one byte, so that empty samples cannot be repeated without bound either.
+    if u64::from(sample_count) * u64::from(sample_size.max(1)) > file_end {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the {} box at byte {} declares {} samples of {} bytes, which exceed the file size",
+                String::from_utf8_lossy(&header.box_type),
+                header.offset,
+                sample_count,
+                sample_size
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn skip<R: Seek>(reader: &mut R, len: i64) -> io::Result<()> {
+    reader.seek(SeekFrom::Current(len))?;
+    Ok(())
+}
+
+/// Reads the version and flags of a full box.
+fn read_full_box_header<R: Read>(reader: &mut R) -> io::Result<(u8, u32)> {
+    let version_and_flags = read_u32(reader)?;
+
+    Ok((
+        (version_and_flags >> 24) as u8,
+        version_and_flags & 0x00FF_FFFF,
+    ))
+}
+
+/// Reads a box header at the current position, bounded by the end of the enclosing box.
+fn read_box_header<R: Read + Seek>(reader: &mut R, parent_end: u64) -> io::Result<BoxHeader> {
+    let pos = reader.seek(SeekFrom::Current(0))?;
+    let size = read_u32(reader)?;
+    let mut box_type = [0; 4];
+    reader.read_exact(&mut box_type)?;
+
+    let (start, end) = match size {
+        0 => (pos + 8, parent_end), // the box extends to the end of its parent
+        1 => (pos + 16, pos + read_u64(reader)?),
+        _ => (pos + 8, pos + u64::from(size)),
+    };
+
+    if end < start || end > parent_end {
+        return Err(invalid_data("invalid ISOBMFF box size"));
+    }
+
+    Ok(BoxHeader {
+        box_type,
+        offset: pos,
+        start,
+        end,
+    })
+}
+
+/// Calls `f` for each box contained in the range `[start, end)`.
+fn for_each_box<R, F>(reader: &mut R, start: u64, end: u64, mut f: F) -> io::Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(&mut R, &BoxHeader) -> io::Result<()>,
+{
+    let mut pos = start;
+
+    while pos + 8 <= end {
+        reader.seek(SeekFrom::Start(pos))?;
+        let header = read_box_header(reader, end)?;
+        f(reader, &header)?;
+        pos = header.end;
+    }
+
+    Ok(())
+}
+
+/// Gathers the parameters of a track from a box nested in `trak`.
+fn parse_trak_box<R: Read + Seek>(
+    reader: &mut R,
+    header: &BoxHeader,
+    track: &mut TrackBoxes,
+) -> io::Result<()> {
+    match &header.box_type {
+        b"mdia" | b"minf" | b"stbl" => {
+            for_each_box(reader, header.start, header.end, |reader, child| {
+                parse_trak_box(reader, child, track)
+            })?;
+        }
+        b"tkhd" => {
+            let (version, _) = read_full_box_header(reader)?;
+            // creation_time, modification_time
+            skip(reader, if version == 1 { 16 } else { 8 })?;
+            track.track_id = read_u32(reader)?;
+            // reserved, duration, reserved, layer, alternate_group, volume, reserved, matrix
+            skip(reader, 4 + (if version == 1 { 8 } else { 4 }) + 8 + 8 + 36)?;
+            // Width and height are stored as 16.16 fixed-point values.
+            let width = read_u32(reader)? >> 16;
+            let height = read_u32(reader)? >> 16;
+            track.resolution = (width as u16, height as u16);
+        }
+        b"mdhd" => {
+            let (version, _) = read_full_box_header(reader)?;
+            // creation_time, modification_time
+            skip(reader, if version == 1 { 16 } else { 8 })?;
+            track.timescale = read_u32(reader)?;
+        }
+        b"stsd" => {
+            // version/flags, entry_count
+            let entries_start = header.start + 8;
+
+            for_each_box(reader, entries_start, header.end, |reader, entry| {
+                if &entry.box_type == b"av01" {
+                    track.is_av1 = true;
+
+                    // Skip the fixed-size VisualSampleEntry fields to reach the child boxes.
+                    for_each_box(reader, entry.start + 78, entry.end, |_, child| {
+                        if &child.box_type == b"av1C" && child.end >= child.start + 4 {
+                            track.config_record = Some(child.start);
+                            // The configOBUs follow the 4-byte AV1CodecConfigurationRecord header.
+                            let size =
+                                u32::try_from(child.end - child.start - 4).map_err(|_| {
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        format!(
+                                            "the av1C box at byte {} is too large",
+                                            child.offset
+                                        ),
+                                    )
+                                })?;
+                            track.config_obus = Some((child.start + 4, size));
+                        }
+
+                        Ok(())
+                    })?;
+                }
+
+                Ok(())
+            })?;
+        }
+        b"stts" => {
+            read_full_box_header(reader)?;
+            let entry_count = read_u32(reader)?;
+            check_entry_count(header, 8, entry_count, 8)?;
+
+            for _ in 0..entry_count {
+                let sample_count = read_u32(reader)?;
+                let sample_delta = read_u32(reader)?;
+                track.time_to_sample.push((sample_count, sample_delta));
+            }
+        }
+        b"stsz" => {
+            read_full_box_header(reader)?;
+            let sample_size = read_u32(reader)?;
+            let sample_count = read_u32(reader)?;
+
+            if sample_size == 0 {
+                check_entry_count(header, 12, sample_count, 4)?;
+
+                for _ in 0..sample_count {
+                    track.sample_sizes.push(read_u32(reader)?);
+                }
+            } else {
+                let file_end = reader.seek(SeekFrom::End(0))?;
+                check_sample_count(header, sample_count, sample_size, file_end)?;
+                track.sample_sizes = vec![sample_size; sample_count as usize];
+            }
+        }
+        b"stsc" => {
+            read_full_box_header(reader)?;
+            let entry_count = read_u32(reader)?;
+            check_entry_count(header, 8, entry_count, 12)?;
+
+            for _ in 0..entry_count {
+                let first_chunk = read_u32(reader)?;
+                let samples_per_chunk = read_u32(reader)?;
+                // sample_description_index
+                skip(reader, 4)?;
+                track.sample_to_chunk.push((first_chunk, samples_per_chunk));
+            }
+        }
+        b"stco" => {
+            read_full_box_header(reader)?;
+            let entry_count = read_u32(reader)?;
+            check_entry_count(header, 8, entry_count, 4)?;
+
+            for _ in 0..entry_count {
+                track.chunk_offsets.push(u64::from(read_u32(reader)?));
+            }
+        }
+        b"co64" => {
+            read_full_box_header(reader)?;
+            let entry_count = read_u32(reader)?;
+            check_entry_count(header, 8, entry_count, 8)?;
+
+            for _ in 0..entry_count {
+                track.chunk_offsets.push(read_u64(reader)?);
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Lays out the samples described by the sample table of a track.
+fn build_samples(track: &TrackBoxes) -> Vec<Mp4Sample> {
+    let mut samples = Vec::with_capacity(track.sample_sizes.len());
+    let mut sizes = track.sample_sizes.iter();
+    let mut durations = track
+        .time_to_sample
+        .iter()
+        .flat_map(|&(count, delta)| std::iter::repeat_n(delta, count as usize));
+    let mut run = 0; // current sample-to-chunk run
+    let mut dts = 0;
+
+    for (chunk_idx, &chunk_offset) in track.chunk_offsets.iter().enumerate() {
+        let chunk = chunk_idx as u32 + 1; // chunks are numbered from 1
+
+        while run + 1 < track.sample_to_chunk.len() && track.sample_to_chunk[run + 1].0 <= chunk {
+            run += 1;
+        }
+
+        let samples_per_chunk = track.sample_to_chunk.get(run).map_or(0, |&(_, n)| n);
+        let mut offset = chunk_offset;
+
+        for _ in 0..samples_per_chunk {
+            let size = match sizes.next() {
+                Some(&size) => size,
+                None => return samples,
+            };
+
+            samples.push(Mp4Sample { offset, size, dts });
+            offset += u64::from(size);
+            dts += u64::from(durations.next().unwrap_or(0));
+        }
+    }
+
+    samples
+}
+
+/// Appends the samples of the given track described by a movie fragment.
+fn parse_moof<R: Read + Seek>(
+    reader: &mut R,
+    moof: &BoxHeader,
+    track_id: u32,
+    defaults: TrackDefaults,
+    dts: &mut u64,
+    samples: &mut Vec<Mp4Sample>,
+    file_end: u64,
+) -> io::Result<()> {
+    for_each_box(reader, moof.start, moof.end, |reader, traf| {
+        if &traf.box_type != b"traf" {
+            return Ok(());
+        }
+
+        let mut is_track = false;
+        let mut base_offset = moof.offset; // default-base-is-moof
+        let mut defaults = defaults;
+        let mut data_end = None; // end of the data of the previous track run
+
+        for_each_box(reader, traf.start, traf.end, |reader, child| {
+            match &child.box_type {
+                b"tfhd" => {
+                    let (_, flags) = read_full_box_header(reader)?;
+                    is_track = read_u32(reader)? == track_id;
+
+                    if flags & 0x01 != 0 {
+                        base_offset = read_u64(reader)?;
+                    }
+                    if flags & 0x02 != 0 {
+                        // sample_description_index
+                        skip(reader, 4)?;
+                    }
+                    if flags & 0x08 != 0 {
+                        defaults.sample_duration = read_u32(reader)?;
+                    }
+                    if flags & 0x10 != 0 {
+                        defaults.sample_size = read_u32(reader)?;
+                    }
+                }
+                b"tfdt" if is_track => {
+                    let (version, _) = read_full_box_header(reader)?;
+                    *dts = if version == 1 {
+                        read_u64(reader)?
+                    } else {
+                        u64::from(read_u32(reader)?)
+                    };
+                }
+                b"trun" if is_track => {
+                    let (_, flags) = read_full_box_header(reader)?;
+                    let sample_count = read_u32(reader)?;
+
+                    let mut offset = if flags & 0x01 != 0 {
+                        let data_offset = read_u32(reader)? as i32;
+                        base_offset
+                            .checked_add_signed(i64::from(data_offset))
+                            .ok_or_else(|| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!(
+                                        "the trun box at byte {} has a data offset of {} bytes from byte {}, which lies outside the file",
+                                        child.offset, data_offset, base_offset
+                                    ),
+                                )
+                            })?
+                    } else {
+                        data_end.unwrap_or(base_offset)
+                    };
+
+                    if flags & 0x04 != 0 {
+                        // first_sample_flags
+                        skip(reader, 4)?;
+                    }
+
+                    // Each of the optional per-sample fields takes 4 bytes.
+                    let entry_size = 4 * u64::from((flags & 0xF00).count_ones());
+                    if entry_size > 0 {
+                        let fields_len = 8 + 4 * u64::from((flags & 0x05).count_ones());
+                        check_entry_count(child, fields_len, sample_count, entry_size)?;
+                    } else {
+                        check_sample_count(child, sample_count, defaults.sample_size, file_end)?;
+                    }
+
+                    for _ in 0..sample_count {
+                        let duration = if flags & 0x100 != 0 {
+                            read_u32(reader)?
+                        } else {
+                            defaults.sample_duration
+                        };
+                        let size = if flags & 0x200 != 0 {
+                            read_u32(reader)?
+                        } else {
+                            defaults.sample_size
+                        };
+                        if flags & 0x400 != 0 {
+                            // sample_flags
+                            skip(reader, 4)?;
+                        }
+                        if flags & 0x800 != 0 {
+                            // sample_composition_time_offset
+                            skip(reader, 4)?;
+                        }
+
+                        samples.push(Mp4Sample {
+                            offset,
+                            size,
+                            dts: *dts,
+                        });
+                        offset += u64::from(size);
+                        *dts += u64::from(duration);
+                    }
+
+                    data_end = Some(offset);
+                }
+                _ => {}
+            }
+
+            Ok(())
+        })
+    })
+}
+
+/// Locates the AV1 track of an ISOBMFF file and all of its samples.
+pub fn parse_mp4<R: Read + Seek>(reader: &mut R) -> io::Result<Mp4Track> {
+    let file_end = reader.seek(SeekFrom::End(0))?;
+    let mut tracks = Vec::new();
+    let mut track_defaults = Vec::new(); // (track_ID, defaults) from `trex`
+    let mut fragments = Vec::new();
+
+    for_each_box(reader, 0, file_end, |reader, header| {
+        match &header.box_type {
+            b"moov" => {
+                for_each_box(reader, header.start, header.end, |reader, child| {
+                    match &child.box_type {
+                        b"trak" => {
+                            let mut track = TrackBoxes::default();
+                            for_each_box(reader, child.start, child.end, |reader, b| {
+                                parse_trak_box(reader, b, &mut track)
+                            })?;
+                            tracks.push(track);
+                        }
+                        b"mvex" => {
+                            for_each_box(reader, child.start, child.end, |reader, b| {
+                                if &b.box_type == b"trex" {
+                                    read_full_box_header(reader)?;
+                                    let track_id = read_u32(reader)?;
+                                    // default_sample_description_index
+                                    skip(reader, 4)?;
+                                    let sample_duration = read_u32(reader)?;
+                                    let sample_size = read_u32(reader)?;
+                                    track_defaults.push((
+                                        track_id,
+                                        TrackDefaults {
+                                            sample_duration,
+                                            sample_size,
+                                        },
+                                    ));
+                                }
+
+                                Ok(())
+                            })?;
+                        }
+                        _ => {}
+                    }
+
+                    Ok(())
+                })?;
+            }
+            b"moof" => fragments.push(*header),
+            _ => {}
+        }
+
+        Ok(())
+    })?;
+
+    let track = tracks
+        .into_iter()
+        .find(|t| t.is_av1)
+        .ok_or_else(|| invalid_data("no AV1 track found"))?;
+
+    let mut samples = build_samples(&track);
+
+    // Movie fragments continue from the end of the samples described in `moov`.
+    let defaults = track_defaults
+        .iter()
+        .find(|&&(id, _)| id == track.track_id)
+        .map_or_else(TrackDefaults::default, |&(_, d)| d);
+    let mut dts = samples.last().map_or(0, |s| {
+        s.dts + u64::from(track.time_to_sample.last().map_or(0, |&(_, delta)| delta))
+    });
+
+    for moof in &fragments {
+        parse_moof(
+            reader,
+            moof,
+            track.track_id,
+            defaults,
+            &mut dts,
+            &mut samples,
+            file_end,
+        )?;
+    }
+
+    Ok(Mp4Track {
+        timescale: track.timescale,
+        resolution: track.resolution,
+        config_record: track.config_record,
+        config_obus: track.config_obus,
+        samples,
+    })
+}
+
+/// Reads the level and tier of the first operating point from an AV1CodecConfigurationRecord.
+pub fn read_config_level<R: Read + Seek>(reader: &mut R, record_pos: u64) -> io::Result<(u8, u8)> {
+    let mut record = [0; 3];
+    reader.seek(SeekFrom::Start(record_pos))?;
+    reader.read_exact(&mut record)?;
+
+    // marker, version | seq_profile, seq_level_idx_0 | seq_tier_0, ...
+    Ok((record[1] & 0b0001_1111, record[2] >> 7))
+}
+
+/// Rewrites the level and tier of the first operating point in an AV1CodecConfigurationRecord,
+/// providing the two patched bytes that start after the first byte of the record.
+pub fn patch_config_level<R: Read + Seek>(
+    reader: &mut R,
+    record_pos: u64,
+    level: u8,
+    tier: u8,
+) -> io::Result<[u8; 2]> {
+    let mut record = [0; 3];
+    reader.seek(SeekFrom::Start(record_pos))?;
+    reader.read_exact(&mut record)?;
+
+    Ok([
+        record[1] & 0b1110_0000 | level & 0b0001_1111,
+        record[2] & 0b0111_1111 | tier << 7,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_count_larger_than_box() {
+        // A time-to-sample box declaring 2^32 - 1 entries, with room for a single one
+        let data = [
+            0, 0, 0, 24, b's', b't', b't', b's', 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 1, 0,
+            0, 2, 0,
+        ];
+        let mut reader = io::Cursor::new(&data[..]);
+        let header = read_box_header(&mut reader, data.len() as u64).unwrap();
+        let mut track = TrackBoxes::default();
+
+        let err = parse_trak_box(&mut reader, &header, &mut track).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(track.time_to_sample.is_empty());
+    }
+
+    #[test]
+    fn test_trun_data_offset_before_file() {
+        // A movie fragment whose track run points 100 bytes before the fragment, at the start of the file
+        let data = [
+            0, 0, 0, 52, b'm', b'o', b'o', b'f', 0, 0, 0, 44, b't', b'r', b'a', b'f', 0, 0, 0, 16,
+            b't', b'f', b'h', b'd', 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 20, b't', b'r', b'u', b'n', 0,
+            0, 0, 1, 0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0x9C,
+        ];
+        let mut reader = io::Cursor::new(&data[..]);
+        let moof = read_box_header(&mut reader, data.len() as u64).unwrap();
+        let mut samples = Vec::new();
+
+        let err = parse_moof(
+            &mut reader,
+            &moof,
+            1,
+            TrackDefaults::default(),
+            &mut 0,
+            &mut samples,
+            data.len() as u64,
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(err.to_string().contains("trun box at byte 32"));
+        assert!(samples.is_empty());
+    }
+
+    #[test]
+    fn test_build_samples_multiple_chunk_runs() {
+        let track = TrackBoxes {
+            time_to_sample: vec![(5, 512)],
+            sample_sizes: vec![10, 20, 30, 40, 50],
+            // Two samples in the first two chunks, then one sample per chunk.
+            sample_to_chunk: vec![(1, 2), (3, 1)],
+            chunk_offsets: vec![100, 1_000, 0x1_0000_0000],
+            ..TrackBoxes::default()
+        };
+
+        let samples = build_samples(&track);
+        let layout = samples
+            .iter()
+            .map(|s| (s.offset, s.size, s.dts))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                (100, 10, 0),
+                (110, 20, 512),
+                (1_000, 30, 1024),
+                (1_030, 40, 1536),
+                (0x1_0000_0000, 50, 2048),
+            ],
+            layout
+        );
+    }
+}
@@ -1,4 +1,7 @@
+use crate::annexb;
+use crate::error::ElevatorError;
 use av1parser::*;
+use serde::Serialize;
 use std::io;
 
 // Adapted from av1parser. TODO: clean up/refactor/rewrite
@@ -11,12 +14,12 @@ pub fn process_obu<R: io::Read>(reader: &mut R, seq: &mut av1::Sequence, obu: &o
             }
         }
         obu::OBU_FRAME_HEADER | obu::OBU_FRAME => {
-            if seq.sh.is_none() {
-                return;
-            }
-            if let Some(fh) =
-                obu::parse_frame_header(reader, seq.sh.as_ref().unwrap(), &mut seq.rfman)
-            {
+            // Frame headers cannot be parsed without a sequence header.
+            let sh = match &seq.sh {
+                Some(sh) => sh,
+                None => return,
+            };
+            if let Some(fh) = obu::parse_frame_header(reader, sh, &mut seq.rfman) {
                 // decode_frame_wrapup(): Decode frame wrapup process
                 if fh.show_frame || fh.show_existing_frame {
                     seq.rfman.output_process(&fh);
@@ -29,3 +32,677 @@ pub fn process_obu<R: io::Read>(reader: &mut R, seq: &mut av1::Sequence, obu: &o
         _ => {}
     }
 }
+
+/// Reads bits from a byte slice, most significant bit first.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position of the next bit to read
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0 }
+    }
+
+    /// Provides the position of the next bit to read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn read_bit(&mut self) -> io::Result<bool> {
+        let byte = self.data.get(self.position / 8).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "sequence header ended unexpectedly",
+            )
+        })?;
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+
+        Ok(bit != 0)
+    }
+
+    pub fn read_bits(&mut self, n: usize) -> io::Result<u32> {
+        let mut value = 0;
+
+        for _ in 0..n {
+            value = value << 1 | u32::from(self.read_bit()?);
+        }
+
+        Ok(value)
+    }
+
+    /// Reads a variable length unsigned integer.
+    pub fn read_uvlc(&mut self) -> io::Result<u32> {
+        let mut leading_zeros = 0;
+
+        while !self.read_bit()? {
+            leading_zeros += 1;
+        }
+
+        if leading_zeros >= 32 {
+            return Ok(std::u32::MAX);
+        }
+
+        Ok(self.read_bits(leading_zeros)? + ((1_u32 << leading_zeros) - 1))
+    }
+}
+
+/// Decoder model parameters of an operating point, signaled by `operating_parameters_info()` in a sequence header
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct OperatingParameters {
+    /// Smoothing buffer delay of the decoder, in units of the 90 kHz clock
+    pub decoder_buffer_delay: u32,
+    /// Smoothing buffer delay of the encoder, in units of the 90 kHz clock
+    pub encoder_buffer_delay: u32,
+    /// Whether the decoder model operates in low delay mode, in which the buffer delays are not constrained
+    pub low_delay_mode_flag: bool,
+}
+
+/// Location and value of the level of an operating point in a sequence header
+pub struct LevelField {
+    /// Position of the first level bit, relative to the start of the sequence header OBU payload
+    pub bit_offset: usize,
+    /// Operating point layer mask
+    pub idc: u16,
+    /// Level index signaled for the operating point
+    pub level: u8,
+    /// Tier bit, which is only coded for levels above 3.3 (index 7)
+    pub tier: Option<bool>,
+    /// Decoder model parameters, if the sequence header signals them for the operating point
+    pub operating_parameters: Option<OperatingParameters>,
+}
+
+/// Locates the level of each operating point in a sequence header OBU payload.
+pub fn parse_level_fields(data: &[u8]) -> Result<Vec<LevelField>, ElevatorError> {
+    let mut bits = BitReader::new(data);
+
+    bits.read_bits(3)?; // seq_profile
+    bits.read_bit()?; // still_picture
+    let reduced_still_picture_header = bits.read_bit()?;
+
+    if reduced_still_picture_header {
+        let bit_offset = bits.position();
+
+        return Ok(vec![LevelField {
+            bit_offset,
+            idc: 0,
+            level: bits.read_bits(5)? as u8,
+            tier: None,
+            operating_parameters: None,
+        }]);
+    }
+
+    let timing_info_present_flag = bits.read_bit()?;
+    let mut decoder_model_info_present_flag = false;
+    let mut buffer_delay_length = 0;
+
+    if timing_info_present_flag {
+        // timing_info()
+        bits.read_bits(32)?; // num_units_in_display_tick
+        bits.read_bits(32)?; // time_scale
+
+        if bits.read_bit()? {
+            // equal_picture_interval
+            bits.read_uvlc()?; // num_ticks_per_picture_minus_1
+        }
+
+        decoder_model_info_present_flag = bits.read_bit()?;
+
+        if decoder_model_info_present_flag {
+            // decoder_model_info()
+            buffer_delay_length = bits.read_bits(5)? as usize + 1;
+            bits.read_bits(32)?; // num_units_in_decoding_tick
+            bits.read_bits(5)?; // buffer_removal_time_length_minus_1
+            bits.read_bits(5)?; // frame_presentation_time_length_minus_1
+        }
+    }
+
+    let initial_display_delay_present_flag = bits.read_bit()?;
+    let operating_points_cnt = bits.read_bits(5)? + 1;
+    let mut fields = Vec::new();
+
+    for _ in 0..operating_points_cnt {
+        let idc = bits.read_bits(12)? as u16;
+        let bit_offset = bits.position();
+        let level = bits.read_bits(5)? as u8;
+        let tier = if level > 7 {
+            Some(bits.read_bit()?)
+        } else {
+            None
+        };
+
+        let operating_parameters = if decoder_model_info_present_flag && bits.read_bit()? {
+            // operating_parameters_info()
+            Some(OperatingParameters {
+                decoder_buffer_delay: bits.read_bits(buffer_delay_length)?,
+                encoder_buffer_delay: bits.read_bits(buffer_delay_length)?,
+                low_delay_mode_flag: bits.read_bit()?,
+            })
+        } else {
+            None
+        };
+
+        if initial_display_delay_present_flag && bits.read_bit()? {
+            bits.read_bits(4)?; // initial_display_delay_minus_1
+        }
+
+        fields.push(LevelField {
+            bit_offset,
+            idc,
+            level,
+            tier,
+            operating_parameters,
+        });
+    }
+
+    Ok(fields)
+}
+
+/// Rewrites the level of each operating point in a sequence header OBU payload,
+/// adding or removing tier bits as needed. The payload size is unchanged unless
+/// the tier bits move its trailing one bit to another byte, in which case it grows or shrinks,
+/// keeping any zero bytes that padded the input.
+/// The tier of every operating point is replaced with `tier` if given, where `true` denotes High tier.
+pub fn rewrite_levels(
+    data: &[u8],
+    fields: &[LevelField],
+    levels: &[u8],
+    tier: Option<bool>,
+    reduced_still_picture_header: bool,
+) -> Result<Vec<u8>, ElevatorError> {
+    if tier == Some(true) && reduced_still_picture_header {
+        return Err(ElevatorError::Unpatchable(String::from(
+            "High tier cannot be signaled in a reduced still picture header",
+        )));
+    }
+
+    let mut bits = BitReader::new(data);
+    let mut output = Vec::with_capacity(data.len() * 8 + fields.len());
+
+    for (field, &level) in fields.iter().zip(levels) {
+        // Copy everything up to the current level unchanged.
+        while bits.position() < field.bit_offset {
+            output.push(bits.read_bit()?);
+        }
+
+        bits.read_bits(5)?;
+        output.extend((0..5).rev().map(|i| level >> i & 1 != 0));
+
+        let old_tier = match field.tier {
+            Some(_) => bits.read_bit()?,
+            None => false,
+        };
+
+        // Only Main tier can be signaled for levels below 4.0.
+        if level <= 7 {
+            match tier {
+                Some(true) => {
+                    return Err(ElevatorError::Unpatchable(String::from(
+                        "High tier is only defined for levels 4.0 and above",
+                    )))
+                }
+                None if old_tier => {
+                    return Err(ElevatorError::Unpatchable(String::from(
+                        "the level cannot be reduced below 4.0 when High tier is specified",
+                    )))
+                }
+                _ => {}
+            }
+        }
+
+        if level > 7 && !reduced_still_picture_header {
+            output.push(tier.unwrap_or(old_tier));
+        }
+    }
+
+    while bits.position() < data.len() * 8 {
+        output.push(bits.read_bit()?);
+    }
+
+    // The payload ends on the byte holding the trailing one bit, followed by the zero bytes that padded the input.
+    let padding = match data.iter().rposition(|&byte| byte != 0) {
+        Some(last_byte) => data.len() - last_byte - 1,
+        None => 0,
+    };
+    let len = match output.iter().rposition(|&bit| bit) {
+        Some(last_one) => last_one / 8 + 1 + padding,
+        None => data.len(),
+    };
+    output.resize(len * 8, false);
+
+    Ok(output
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | u8::from(bit)))
+        .collect())
+}
+
+/// Rewrites the size field of an OBU header for a new payload size, keeping the length of the field if possible.
+pub fn resize_obu_header(header: &[u8], obu_size: u32) -> Result<Vec<u8>, ElevatorError> {
+    let obu_extension_flag = header[0] & 0b0000_0100 != 0;
+    let obu_has_size_field = header[0] & 0b0000_0010 != 0;
+
+    if !obu_has_size_field {
+        return Err(ElevatorError::Unpatchable(String::from(
+            "the sequence header would grow, but its OBU has no size field",
+        )));
+    }
+
+    let size_offset = 1 + usize::from(obu_extension_flag);
+    let mut output = header[..size_offset].to_vec();
+    output.extend(annexb::encode_leb128(obu_size, header.len() - size_offset));
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two operating points at levels 5.1 (index 13, High tier) and 3.0 (index 4),
+    // with an initial display delay for the first one, followed by arbitrary data.
+    const SEQUENCE_HEADER: [u8; 8] = [
+        0b0000_0010, // seq_profile, still_picture, reduced, timing info, display delay, op count
+        0b0001_0000, // op count, op 0 idc (0x030)
+        0b0011_0000,
+        0b0110_1111, // op 0 level and tier, display delay
+        0b1110_0000, // display delay, op 1 idc (0x001)
+        0b0000_0010, // op 1 level
+        0b0100_0101, // op 1 display delay flag, data
+        0b0100_0000, // data, trailing bits
+    ];
+
+    #[test]
+    fn test_parse_operating_parameters() {
+        let bits = [
+            "000 0 0 1",                        // seq_profile, still_picture, reduced, timing info
+            "00000000000000000000000000000001", // num_units_in_display_tick
+            "00000000000000000000000000111100", // time_scale
+            "0 1",                              // equal_picture_interval, decoder model info
+            "01001",                            // buffer_delay_length_minus_1
+            "00000000000000000000000000000001", // num_units_in_decoding_tick
+            "00000 00000",                      // buffer removal and presentation time lengths
+            "0 00000 000000000000 01000 0",     // display delay, op count, idc, level, tier
+            "1 0100101100 0011001000 1",        // decoder model, delays of 300 and 200, low delay
+            "1",                                // trailing bit
+        ]
+        .concat()
+        .replace(' ', "");
+        let mut data = vec![0; bits.len() / 8 + 1];
+        for (i, bit) in bits.chars().enumerate() {
+            if bit == '1' {
+                data[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+
+        let fields = parse_level_fields(&data).unwrap();
+        assert_eq!(1, fields.len());
+        assert_eq!(8, fields[0].level);
+
+        let parameters = fields[0].operating_parameters.unwrap();
+        assert_eq!(
+            (300, 200, true),
+            (
+                parameters.decoder_buffer_delay,
+                parameters.encoder_buffer_delay,
+                parameters.low_delay_mode_flag
+            )
+        );
+        assert!(parse_level_fields(&SEQUENCE_HEADER).unwrap()[0]
+            .operating_parameters
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_level_fields_multiple_operating_points() {
+        let fields = parse_level_fields(&SEQUENCE_HEADER).unwrap();
+
+        assert_eq!(2, fields.len());
+        assert_eq!(
+            (0x030, 13, Some(true)),
+            (fields[0].idc, fields[0].level, fields[0].tier)
+        );
+        assert_eq!(
+            (0x001, 4, None),
+            (fields[1].idc, fields[1].level, fields[1].tier)
+        );
+        assert_eq!(24, fields[0].bit_offset);
+        assert_eq!(47, fields[1].bit_offset);
+    }
+
+    #[test]
+    fn test_parse_level_fields_timing_and_decoder_model_info() {
+        // Timing info with an equal picture interval, decoder model info with 10-bit buffer delays,
+        // and one operating point at level 4.0 (index 8) with decoder model and display delay parameters.
+        let sequence_header = [
+            0x04, 0x00, 0x00, 0x0F, 0xA4, 0x00, 0x01, 0xD4, 0xC3, 0xA4, 0x00, 0x00, 0x0F, 0xA4,
+            0x84, 0x80, 0x00, 0x10, 0xFD, 0x0F, 0xA2, 0x74, 0xB0,
+        ];
+        let fields = parse_level_fields(&sequence_header).unwrap();
+
+        assert_eq!(1, fields.len());
+        assert_eq!(138, fields[0].bit_offset);
+        assert_eq!((8, Some(false)), (fields[0].level, fields[0].tier));
+
+        // The fields following the level must still line up after patching across the tier bit.
+        let patched = rewrite_levels(&sequence_header, &fields, &[5], None, false).unwrap();
+        let restored = rewrite_levels(
+            &patched,
+            &parse_level_fields(&patched).unwrap(),
+            &[8],
+            None,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(&sequence_header[..], restored.as_slice());
+    }
+
+    #[test]
+    fn test_rewrite_levels_decoder_model_multiple_operating_points() {
+        // Decoder model info with 5-bit buffer delays, and two operating points at levels 4.0 (index 8)
+        // and 3.0 (index 4), where only the first one has decoder model parameters.
+        let sequence_header = [
+            0x04, 0x00, 0x00, 0x0F, 0xA4, 0x00, 0x03, 0xA9, 0x81, 0x20, 0x00, 0x0A, 0xFC, 0x82,
+            0x52, 0x08, 0x81, 0xA1, 0xAA, 0xA0, 0x02, 0x45, 0xC0,
+        ];
+        let fields = parse_level_fields(&sequence_header).unwrap();
+
+        assert_eq!(2, fields.len());
+        assert_eq!((137, 167), (fields[0].bit_offset, fields[1].bit_offset));
+        assert_eq!((0x103, 0x001), (fields[0].idc, fields[1].idc));
+
+        // Adding a tier bit to the second operating point must not disturb the decoder model parameters of the first.
+        let patched = rewrite_levels(&sequence_header, &fields, &[12, 9], None, false).unwrap();
+        let patched_fields = parse_level_fields(&patched).unwrap();
+
+        assert_eq!(
+            (137, 167),
+            (patched_fields[0].bit_offset, patched_fields[1].bit_offset)
+        );
+        assert_eq!(
+            (12, Some(false)),
+            (patched_fields[0].level, patched_fields[0].tier)
+        );
+        assert_eq!(
+            (9, Some(false)),
+            (patched_fields[1].level, patched_fields[1].tier)
+        );
+
+        let restored = rewrite_levels(&patched, &patched_fields, &[8, 4], None, false).unwrap();
+
+        assert_eq!(&sequence_header[..], restored.as_slice());
+    }
+
+    #[test]
+    fn test_rewrite_levels_round_trip() {
+        let fields = parse_level_fields(&SEQUENCE_HEADER).unwrap();
+        let patched = rewrite_levels(&SEQUENCE_HEADER, &fields, &[12, 8], None, false).unwrap();
+        let patched_fields = parse_level_fields(&patched).unwrap();
+
+        assert_eq!(12, patched_fields[0].level);
+        assert_eq!(Some(true), patched_fields[0].tier);
+        assert_eq!(
+            (8, Some(false)),
+            (patched_fields[1].level, patched_fields[1].tier)
+        );
+
+        let restored = rewrite_levels(&patched, &patched_fields, &[13, 4], None, false).unwrap();
+
+        assert_eq!(SEQUENCE_HEADER, restored.as_slice());
+    }
+
+    #[test]
+    fn test_rewrite_levels_initial_display_delay() {
+        let fields = parse_level_fields(&SEQUENCE_HEADER).unwrap();
+
+        // Removing the tier bit of the first operating point shifts its display delay and every later field by one bit.
+        let patched =
+            rewrite_levels(&SEQUENCE_HEADER, &fields, &[4, 4], Some(false), false).unwrap();
+
+        assert_eq!(
+            &[
+                0b0000_0010,
+                0b0001_0000,
+                0b0011_0000,
+                0b0010_0111, // op 0 level, display delay
+                0b1100_0000, // display delay, op 1 idc
+                0b0000_0100, // op 1 level
+                0b1000_1010, // op 1 display delay flag, data
+                0b1000_0000, // data, trailing bits
+            ],
+            patched.as_slice()
+        );
+
+        let patched_fields = parse_level_fields(&patched).unwrap();
+
+        assert_eq!(46, patched_fields[1].bit_offset);
+        assert_eq!(
+            (0x001, 4, None),
+            (
+                patched_fields[1].idc,
+                patched_fields[1].level,
+                patched_fields[1].tier
+            )
+        );
+    }
+
+    #[test]
+    fn test_rewrite_levels_grows_payload_for_trailing_one_bit() {
+        // One operating point at level 3.0 (index 4), with the trailing one bit in the last bit of the payload.
+        let sequence_header = [0b0000_0000, 0b0000_0000, 0b0000_0000, 0b0010_0001];
+        let fields = parse_level_fields(&sequence_header).unwrap();
+        let patched = rewrite_levels(&sequence_header, &fields, &[8], None, false).unwrap();
+
+        assert_eq!(
+            &[
+                0b0000_0000,
+                0b0000_0000,
+                0b0000_0000,
+                0b0100_0000,
+                0b1000_0000
+            ],
+            patched.as_slice()
+        );
+
+        let patched_fields = parse_level_fields(&patched).unwrap();
+
+        assert_eq!(
+            (8, Some(false)),
+            (patched_fields[0].level, patched_fields[0].tier)
+        );
+    }
+
+    // A 1920x1080 sequence header at level 3.0 (index 4), whose trailing one bit is the last bit of the payload
+    const SEQUENCE_HEADER_1080P: [u8; 11] = [
+        0x00, 0x00, 0x00, 0x25, 0x57, 0x7F, 0x86, 0xEF, 0xFE, 0xE6, 0x01,
+    ];
+
+    #[test]
+    fn test_rewrite_levels_across_4_0_keeps_sequence_header_fields() {
+        let fields = parse_level_fields(&SEQUENCE_HEADER_1080P).unwrap();
+        let patched = rewrite_levels(&SEQUENCE_HEADER_1080P, &fields, &[9], None, false).unwrap();
+
+        assert_eq!(
+            &[0x00, 0x00, 0x00, 0x4A, 0xAB, 0xBF, 0xC3, 0x77, 0xFF, 0x73, 0x00, 0x80],
+            patched.as_slice()
+        );
+
+        let patched_fields = parse_level_fields(&patched).unwrap();
+
+        assert_eq!(
+            (9, Some(false)),
+            (patched_fields[0].level, patched_fields[0].tier)
+        );
+
+        // The frame size fields follow the tier bit.
+        let mut bits = BitReader::new(&patched);
+        bits.read_bits(30).unwrap();
+        let frame_width_bits = bits.read_bits(4).unwrap() as usize + 1;
+        let frame_height_bits = bits.read_bits(4).unwrap() as usize + 1;
+
+        assert_eq!(1919, bits.read_bits(frame_width_bits).unwrap());
+        assert_eq!(1079, bits.read_bits(frame_height_bits).unwrap());
+
+        // Removing the tier bit again shrinks the payload back to its original size.
+        let restored = rewrite_levels(&patched, &patched_fields, &[4], None, false).unwrap();
+
+        assert_eq!(&SEQUENCE_HEADER_1080P[..], restored.as_slice());
+    }
+
+    /// Provides the bits of a sequence header payload preceding the level of an operating point,
+    /// and those following its level and tier bit, up to the trailing one bit.
+    fn bits_around_level(data: &[u8], field: &LevelField) -> (Vec<bool>, Vec<bool>) {
+        let mut bits = BitReader::new(data);
+        let all = (0..data.len() * 8)
+            .map(|_| bits.read_bit().unwrap())
+            .collect::<Vec<_>>();
+        let level_end = field.bit_offset + 5 + usize::from(field.tier.is_some());
+        let trailing_one = all.iter().rposition(|&bit| bit).unwrap();
+
+        (
+            all[..field.bit_offset].to_vec(),
+            all[level_end..trailing_one].to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_rewrite_levels_every_level_pair() {
+        let levels = (0..32).collect::<Vec<u8>>();
+
+        // Every operating point of both sequence headers, which hold a single one and two respectively,
+        // is patched from and to every level, including the reserved ones, on either side of the tier boundary.
+        for &(data, index) in &[
+            (&SEQUENCE_HEADER_1080P[..], 0),
+            (&SEQUENCE_HEADER[..], 0),
+            (&SEQUENCE_HEADER[..], 1),
+        ] {
+            let fields = parse_level_fields(data).unwrap();
+            let with_level = |data: &[u8], level| {
+                let mut levels = fields.iter().map(|field| field.level).collect::<Vec<_>>();
+                levels[index] = level;
+                rewrite_levels(
+                    data,
+                    &parse_level_fields(data).unwrap(),
+                    &levels,
+                    Some(false),
+                    false,
+                )
+                .unwrap()
+            };
+
+            // The other operating points are patched to Main tier along with it.
+            let main_tier = with_level(data, fields[index].level);
+            let original =
+                bits_around_level(&main_tier, &parse_level_fields(&main_tier).unwrap()[index]);
+
+            for &old_level in &levels {
+                let input = with_level(data, old_level);
+                for &new_level in &levels {
+                    let patched = with_level(&input, new_level);
+                    let patched_fields = parse_level_fields(&patched).unwrap();
+                    let tier = if new_level > 7 { Some(false) } else { None };
+
+                    assert_eq!(
+                        (new_level, tier),
+                        (patched_fields[index].level, patched_fields[index].tier),
+                        "{} -> {}",
+                        old_level,
+                        new_level
+                    );
+                    assert_eq!(
+                        original,
+                        bits_around_level(&patched, &patched_fields[index]),
+                        "{} -> {}",
+                        old_level,
+                        new_level
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rewrite_levels_from_maximum_parameters() {
+        // Level 31 codes a tier bit like any level above 4.0, here signaling High tier.
+        let fields = parse_level_fields(&SEQUENCE_HEADER_1080P).unwrap();
+        let input =
+            rewrite_levels(&SEQUENCE_HEADER_1080P, &fields, &[31], Some(true), false).unwrap();
+        let fields = parse_level_fields(&input).unwrap();
+        assert_eq!((31, Some(true)), (fields[0].level, fields[0].tier));
+
+        // Lowering it above 4.0 keeps the tier bit.
+        let patched = rewrite_levels(&input, &fields, &[13], None, false).unwrap();
+        let patched_fields = parse_level_fields(&patched).unwrap();
+        assert_eq!(
+            (13, Some(true)),
+            (patched_fields[0].level, patched_fields[0].tier)
+        );
+
+        // Lowering it below 4.0 removes the tier bit, which restores the original header, but only in Main tier.
+        assert!(matches!(
+            rewrite_levels(&input, &fields, &[4], None, false),
+            Err(ElevatorError::Unpatchable(_))
+        ));
+        let patched = rewrite_levels(&input, &fields, &[4], Some(false), false).unwrap();
+        assert_eq!(&SEQUENCE_HEADER_1080P[..], patched.as_slice());
+    }
+
+    #[test]
+    fn test_rewrite_levels_keeps_zero_padding() {
+        let mut sequence_header = SEQUENCE_HEADER_1080P.to_vec();
+        sequence_header.extend(&[0, 0]);
+
+        let fields = parse_level_fields(&sequence_header).unwrap();
+        let patched = rewrite_levels(&sequence_header, &fields, &[9], None, false).unwrap();
+
+        assert_eq!(SEQUENCE_HEADER_1080P.len() + 3, patched.len());
+        assert_eq!(&[0x80, 0, 0], &patched[patched.len() - 3..]);
+    }
+
+    #[test]
+    fn test_resize_obu_header() {
+        // Sequence header OBU with a padded 2-byte size field
+        assert_eq!(
+            vec![0x0A, 0x85, 0x00],
+            resize_obu_header(&[0x0A, 0x84, 0x00], 5).unwrap()
+        );
+        assert_eq!(
+            vec![0x0A, 0x80, 0x01],
+            resize_obu_header(&[0x0A, 0x7F], 128).unwrap()
+        );
+        assert!(resize_obu_header(&[0x08], 5).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_levels_high_tier_below_4_0() {
+        let fields = parse_level_fields(&SEQUENCE_HEADER).unwrap();
+
+        assert!(rewrite_levels(&SEQUENCE_HEADER, &fields, &[4, 4], None, false).is_err());
+        assert!(rewrite_levels(&SEQUENCE_HEADER, &fields, &[4, 4], Some(false), false).is_ok());
+        assert!(rewrite_levels(&SEQUENCE_HEADER, &fields, &[12, 4], Some(true), false).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_levels_forced_tier() {
+        let fields = parse_level_fields(&SEQUENCE_HEADER).unwrap();
+        let patched =
+            rewrite_levels(&SEQUENCE_HEADER, &fields, &[13, 8], Some(true), false).unwrap();
+        let patched_fields = parse_level_fields(&patched).unwrap();
+
+        assert_eq!(
+            (13, Some(true)),
+            (patched_fields[0].level, patched_fields[0].tier)
+        );
+        assert_eq!(
+            (8, Some(true)),
+            (patched_fields[1].level, patched_fields[1].tier)
+        );
+
+        let patched =
+            rewrite_levels(&SEQUENCE_HEADER, &fields, &[13, 4], Some(false), false).unwrap();
+
+        assert_eq!(Some(false), parse_level_fields(&patched).unwrap()[0].tier);
+    }
+}
@@ -0,0 +1,481 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+/// Compares a duration in time scale units with a length of time in microseconds.
+/// The time scale is a rational number of units per second, so the comparison is exact,
+/// even when a second does not hold a whole number of units.
+fn cmp_duration(duration: u64, time_scale: (u32, u32), micros: u64) -> Ordering {
+    let (num, den) = time_scale;
+
+    (u128::from(duration) * u128::from(den) * 1_000_000)
+        .cmp(&(u128::from(micros) * u128::from(num)))
+}
+
+/// Provides the rate per second of `count` occurrences over `duration` time scale units.
+fn per_second(count: f64, duration: u64, time_scale: (u32, u32)) -> f64 {
+    count * (f64::from(time_scale.0) / f64::from(time_scale.1)) / duration as f64
+}
+
+/// Counts of a single temporal unit, as measured by [`RateWindow`]
+#[derive(Clone, Copy, Default)]
+pub struct TemporalUnitCounts {
+    /// Duration of the temporal unit, in time scale units
+    pub duration: u64,
+    pub bytes: u32,
+    pub shown_frames: u32,
+    pub decoded_frames: u32,
+    pub headers: u32,
+    /// Number of luma samples of the shown frames
+    pub shown_samples: u64,
+    /// Number of luma samples of the decoded frames
+    pub decoded_samples: u64,
+}
+
+/// Rates per second over the window ending with a temporal unit
+#[derive(Clone, Copy, Default)]
+pub struct WindowRates {
+    pub display_rate: f64,
+    pub decode_rate: f64,
+    pub header_rate: f64,
+    pub mbps: f64,
+    /// Number of luma samples shown per second
+    pub display_sample_rate: f64,
+    /// Number of luma samples decoded per second
+    pub decode_sample_rate: f64,
+}
+
+/// Sliding window holding the temporal units of the last second of a stream.
+/// It also keeps track of the instantaneous frame rate of each temporal unit, which varies in VFR streams.
+pub struct RateWindow {
+    time_scale: (u32, u32), // rational number of time scale units per second
+    units: VecDeque<TemporalUnitCounts>,
+    filled: bool, // whether the stream covered a whole window yet
+    min_frame_rate: f64,
+    max_frame_rate: f64,
+    last_shown: (u32, u64), // frames shown by the last temporal unit showing any, and its duration with the ones after it
+    total_shown_frames: u64,
+    total_duration: u64,
+}
+
+impl RateWindow {
+    pub fn new(time_scale: (u32, u32)) -> Self {
+        RateWindow {
+            time_scale,
+            units: VecDeque::new(),
+            filled: false,
+            min_frame_rate: std::f64::MAX,
+            max_frame_rate: 0.0,
+            last_shown: (0, 0),
+            total_shown_frames: 0,
+            total_duration: 0,
+        }
+    }
+
+    /// Adds a temporal unit to the window, dropping the oldest ones once the window spans more than one second,
+    /// and provides the rates over the window.
+    ///
+    /// Until the stream covers a whole second, the rates are the totals so far rather than extrapolations,
+    /// since a window starting before the stream holds nothing else. Clips that end before that are extrapolated
+    /// once complete, with [`RateWindow::extrapolate`].
+    pub fn push(&mut self, unit: TemporalUnitCounts) -> WindowRates {
+        // The frames of a temporal unit stay shown during the following ones that show none.
+        if unit.shown_frames > 0 {
+            let (min, max) = self.last_frame_rate();
+            self.min_frame_rate = min;
+            self.max_frame_rate = max;
+            self.last_shown = (unit.shown_frames, unit.duration);
+        } else {
+            self.last_shown.1 += unit.duration;
+        }
+        self.total_shown_frames += u64::from(unit.shown_frames);
+        self.total_duration += unit.duration;
+
+        self.units.push_back(unit);
+
+        let mut duration = self.units.iter().map(|unit| unit.duration).sum::<u64>();
+        self.filled |= cmp_duration(duration, self.time_scale, 1_000_000) != Ordering::Less;
+
+        // The last temporal unit is always kept, even if it lasts longer than the window on its own.
+        while cmp_duration(duration, self.time_scale, 1_000_000) == Ordering::Greater
+            && self.units.len() > 1
+        {
+            if let Some(unit) = self.units.pop_front() {
+                duration -= unit.duration;
+            }
+        }
+
+        // adjustment to measure rates per second
+        let factor = if self.filled && duration > 0 {
+            per_second(1.0, duration, self.time_scale)
+        } else {
+            1.0
+        };
+
+        self.rates(factor)
+    }
+
+    /// Provides the rates of a clip shorter than one second, as if it repeated to fill one second,
+    /// or `None` if the stream covered a whole window.
+    pub fn extrapolate(&self) -> Option<WindowRates> {
+        let duration = self.units.iter().map(|unit| unit.duration).sum::<u64>();
+        if self.filled || duration == 0 {
+            return None;
+        }
+
+        Some(self.rates(per_second(1.0, duration, self.time_scale)))
+    }
+
+    /// Provides the sums of the counts of the temporal units in the window, multiplied by `factor`.
+    fn rates(&self, factor: f64) -> WindowRates {
+        let sum = |count: fn(&TemporalUnitCounts) -> f64| {
+            self.units.iter().map(count).sum::<f64>() * factor
+        };
+
+        WindowRates {
+            display_rate: sum(|unit| f64::from(unit.shown_frames)),
+            decode_rate: sum(|unit| f64::from(unit.decoded_frames)),
+            header_rate: sum(|unit| f64::from(unit.headers)),
+            mbps: sum(|unit| f64::from(unit.bytes)) * 8.0 / 1_000_000.0,
+            display_sample_rate: sum(|unit| unit.shown_samples as f64),
+            decode_sample_rate: sum(|unit| unit.decoded_samples as f64),
+        }
+    }
+
+    /// Provides the minimum and maximum instantaneous frame rates, including that of the last temporal unit showing frames.
+    fn last_frame_rate(&self) -> (f64, f64) {
+        let (shown_frames, duration) = self.last_shown;
+        if shown_frames == 0 || duration == 0 {
+            return (self.min_frame_rate, self.max_frame_rate);
+        }
+
+        let frame_rate = self.frame_rate(shown_frames, duration);
+        (
+            self.min_frame_rate.min(frame_rate),
+            self.max_frame_rate.max(frame_rate),
+        )
+    }
+
+    /// Provides the instantaneous frame rate of a temporal unit showing `shown_frames` frames in `duration` time scale units.
+    pub fn frame_rate(&self, shown_frames: u32, duration: u64) -> f64 {
+        per_second(f64::from(shown_frames), duration, self.time_scale)
+    }
+
+    /// Provides the number of luma samples per second of a temporal unit holding `samples` luma samples
+    /// over `duration` time scale units.
+    pub fn sample_rate(&self, samples: u64, duration: u64) -> f64 {
+        per_second(samples as f64, duration, self.time_scale)
+    }
+
+    /// Provides the minimum, average, and maximum instantaneous frame rates of the temporal units pushed so far.
+    /// The average is the number of shown frames over the total duration.
+    pub fn frame_rates(&self) -> (f64, f64, f64) {
+        if self.total_duration == 0 {
+            return (0.0, 0.0, 0.0);
+        }
+
+        let avg = per_second(
+            self.total_shown_frames as f64,
+            self.total_duration,
+            self.time_scale,
+        );
+        let (min, max) = self.last_frame_rate();
+
+        (min.min(avg), avg, max.max(avg))
+    }
+}
+
+/// Number of temporal unit durations that [`TimestampClock`] takes the median of
+const RECENT_DURATIONS: usize = 15;
+
+/// Derives the duration of each temporal unit from the timestamps of consecutive ones.
+/// Some muxers write timestamps that go backwards, for example after edits or in looped content; the temporal unit
+/// preceding such a jump is given the median duration of the last ones instead of an underflowing difference.
+pub struct TimestampClock {
+    recent: VecDeque<u64>, // durations of the last temporal units with increasing timestamps
+    fallback: u64,         // duration used before any was measured
+}
+
+impl TimestampClock {
+    pub fn new(fallback: u64) -> Self {
+        TimestampClock {
+            recent: VecDeque::with_capacity(RECENT_DURATIONS),
+            fallback: fallback.max(1),
+        }
+    }
+
+    /// Provides the duration of a temporal unit starting at `previous_pts`, followed by one starting at `pts`,
+    /// and whether it had to be renormalized because `pts` precedes `previous_pts`.
+    pub fn duration(&mut self, previous_pts: u64, pts: u64) -> (u64, bool) {
+        if pts < previous_pts {
+            return (self.median(), true);
+        }
+
+        if self.recent.len() == RECENT_DURATIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(pts - previous_pts);
+
+        (pts - previous_pts, false)
+    }
+
+    fn median(&self) -> u64 {
+        let mut durations = self.recent.iter().copied().collect::<Vec<_>>();
+        durations.sort_unstable();
+
+        durations
+            .get(durations.len() / 2)
+            .copied()
+            .unwrap_or(self.fallback)
+    }
+}
+
+/// Sliding window measuring the bitrate over a given length of time, which may differ from one second.
+pub struct BitrateWindow {
+    time_scale: (u32, u32),      // rational number of time scale units per second
+    length: u64,                 // length of the window, in microseconds
+    units: VecDeque<(u64, u32)>, // duration and size in bytes of each temporal unit
+    duration: u64,
+    bytes: u64,
+    filled: bool, // whether the stream covered a whole window yet
+    peak_mbps: f64,
+}
+
+impl BitrateWindow {
+    pub fn new(time_scale: (u32, u32), seconds: f64) -> Self {
+        BitrateWindow {
+            time_scale,
+            length: (seconds * 1_000_000.0).round() as u64,
+            units: VecDeque::new(),
+            duration: 0,
+            bytes: 0,
+            filled: false,
+            peak_mbps: 0.0,
+        }
+    }
+
+    /// Adds a temporal unit of `bytes` bytes lasting `duration` time scale units to the window,
+    /// and provides the bitrate over the window in megabits per second.
+    ///
+    /// As in [`RateWindow::push`], the bitrate is not extrapolated until the stream covers a whole window,
+    /// and the last temporal unit is always kept.
+    pub fn push(&mut self, duration: u64, bytes: u32) -> f64 {
+        self.units.push_back((duration, bytes));
+        self.duration += duration;
+        self.bytes += u64::from(bytes);
+        self.filled |= cmp_duration(self.duration, self.time_scale, self.length) != Ordering::Less;
+
+        while cmp_duration(self.duration, self.time_scale, self.length) == Ordering::Greater
+            && self.units.len() > 1
+        {
+            if let Some((duration, bytes)) = self.units.pop_front() {
+                self.duration -= duration;
+                self.bytes -= u64::from(bytes);
+            }
+        }
+
+        let megabits = self.bytes as f64 * 8.0 / 1_000_000.0;
+        let mbps = if self.filled && self.duration > 0 {
+            per_second(megabits, self.duration, self.time_scale)
+        } else {
+            megabits * 1_000_000.0 / self.length.max(1) as f64
+        };
+        self.peak_mbps = self.peak_mbps.max(mbps);
+
+        mbps
+    }
+
+    /// Provides the bitrate of a clip shorter than the window, as if it repeated to fill the window,
+    /// or `None` if the stream covered a whole window.
+    pub fn extrapolate(&mut self) -> Option<f64> {
+        if self.filled || self.duration == 0 {
+            return None;
+        }
+
+        let megabits = self.bytes as f64 * 8.0 / 1_000_000.0;
+        let mbps = per_second(megabits, self.duration, self.time_scale);
+        self.peak_mbps = self.peak_mbps.max(mbps);
+
+        Some(mbps)
+    }
+
+    /// Provides the highest bitrate over the window so far, in megabits per second.
+    pub fn peak_mbps(&self) -> f64 {
+        self.peak_mbps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(duration: u64, bytes: u32) -> TemporalUnitCounts {
+        TemporalUnitCounts {
+            duration,
+            bytes,
+            shown_frames: 1,
+            decoded_frames: 1,
+            headers: 1,
+            shown_samples: 0,
+            decoded_samples: 0,
+        }
+    }
+
+    #[test]
+    fn test_partial_window_is_not_extrapolated() {
+        let mut window = RateWindow::new((30, 1));
+
+        for _ in 0..9 {
+            window.push(unit(1, 1000));
+        }
+        let rates = window.push(unit(1, 1000));
+
+        assert_eq!(rates.display_rate, 10.0);
+        assert_eq!(rates.header_rate, 10.0);
+        assert_eq!(rates.mbps, 0.08);
+    }
+
+    #[test]
+    fn test_variable_frame_rate() {
+        let mut window = RateWindow::new((1000, 1));
+
+        // One second at 25 fps, followed by one second at 50 fps.
+        let mut last = WindowRates::default();
+        for _ in 0..25 {
+            last = window.push(unit(40, 0));
+        }
+        assert_eq!(last.display_rate, 25.0);
+
+        for _ in 0..50 {
+            last = window.push(unit(20, 0));
+        }
+        assert_eq!(last.display_rate, 50.0);
+        assert_eq!(last.decode_rate, 50.0);
+
+        assert_eq!(window.frame_rates(), (25.0, 37.5, 50.0));
+    }
+
+    #[test]
+    fn test_temporal_units_without_shown_frames() {
+        let mut window = RateWindow::new((60, 1));
+
+        // Each shown frame is followed by a temporal unit of another temporal layer, holding no frame.
+        let mut last = WindowRates::default();
+        for _ in 0..30 {
+            window.push(unit(1, 0));
+            last = window.push(TemporalUnitCounts {
+                duration: 1,
+                ..TemporalUnitCounts::default()
+            });
+        }
+        assert_eq!(last.display_rate, 30.0);
+        assert_eq!(window.frame_rates(), (30.0, 30.0, 30.0));
+    }
+
+    #[test]
+    fn test_long_temporal_unit() {
+        let mut window = RateWindow::new((30, 1));
+
+        window.push(unit(1, 0));
+        let rates = window.push(unit(60, 0));
+
+        // The window only holds the last temporal unit, which shows a frame every two seconds.
+        assert_eq!(rates.display_rate, 0.5);
+        assert_eq!(window.frame_rates(), (0.5, 60.0 / 61.0, 30.0));
+    }
+
+    #[test]
+    fn test_fractional_frame_rates() {
+        use crate::level::{calculate_level, SequenceContext};
+
+        let level = |mbps| {
+            calculate_level(&SequenceContext {
+                mbps,
+                ..SequenceContext::default()
+            })
+            .unwrap()
+            .0
+        };
+        // Ten seconds of a clip with a single larger temporal unit in the middle.
+        let peak_mbps = |time_scale, duration, bytes, spike| {
+            let mut window = RateWindow::new(time_scale);
+            (0..300)
+                .map(|i| {
+                    let bytes = if i == 150 { spike } else { bytes };
+                    window.push(unit(duration, bytes)).mbps
+                })
+                .fold(0.0, f64::max)
+        };
+
+        // The spike pushes the bitrate over the 20 Mbps of level 4.1, but only over the window holding
+        // the frames that fit in one second, rather than the whole number of frames closest to it.
+        for &(num, den, decimal, bytes, spike) in &[
+            (30000, 1001, (2997, 100), 80_080, 178_500),
+            (60000, 1001, (5994, 100), 40_040, 139_300),
+            (24000, 1001, (23976, 1000), 100_100, 198_000),
+        ] {
+            let exact = peak_mbps((num, den), 1, bytes, spike);
+            assert_eq!(12, level(exact));
+
+            // The same clip with one time scale unit per 1/num second, as in MP4 files.
+            let fine = peak_mbps((num, 1), u64::from(den), bytes, spike);
+            assert!((fine - exact).abs() < 1e-9);
+            assert_eq!(12, level(fine));
+
+            // The same clip with a decimal time scale.
+            assert_eq!(12, level(peak_mbps(decimal, 1, bytes, spike)));
+        }
+    }
+
+    #[test]
+    fn test_backwards_timestamps() {
+        let mut clock = TimestampClock::new(30);
+
+        // Before any duration is measured, the fallback is used.
+        assert_eq!((30, true), clock.duration(100, 0));
+
+        // Looped content, jumping back to the start after four temporal units.
+        let timestamps = [0, 2, 4, 5, 7, 0, 2];
+        let durations = timestamps
+            .windows(2)
+            .map(|pts| clock.duration(pts[0], pts[1]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (2, false),
+                (2, false),
+                (1, false),
+                (2, false),
+                (2, true),
+                (2, false)
+            ],
+            durations
+        );
+    }
+
+    #[test]
+    fn test_bitrate_window_length() {
+        // A 125 kB temporal unit every 30th of a second, with one twice as large.
+        let sizes = (0..60).map(|i| if i == 45 { 250_000 } else { 125_000 });
+
+        let mut windows = [0.5, 1.0, 2.0]
+            .iter()
+            .map(|&seconds| BitrateWindow::new((30, 1), seconds))
+            .collect::<Vec<_>>();
+        let mut rate_window = RateWindow::new((30, 1));
+        for bytes in sizes {
+            let rates = rate_window.push(unit(1, bytes));
+            let mbps = windows
+                .iter_mut()
+                .map(|window| window.push(1, bytes))
+                .collect::<Vec<_>>();
+
+            // The one-second window measures the same bitrate as the other rates.
+            assert_eq!(rates.mbps, mbps[1]);
+        }
+
+        // The shorter the window, the more the larger temporal unit stands out.
+        assert_eq!(32.0, windows[0].peak_mbps());
+        assert_eq!(31.0, windows[1].peak_mbps());
+        assert_eq!(30.5, windows[2].peak_mbps());
+    }
+}
@@ -0,0 +1,72 @@
+//! Regression tests for malformed inputs found by fuzzing, which must fail with an error rather than panic or hang.
+
+use elevator::{AnalysisOptions, ElevatorError, FileFormat};
+use std::io;
+use std::io::Cursor;
+
+fn analyze(data: &[u8]) -> Result<elevator::Analysis, ElevatorError> {
+    let options = AnalysisOptions {
+        time_scale: Some((30, 1)),
+        ..AnalysisOptions::default()
+    };
+
+    elevator::analyze(Cursor::new(data), FileFormat::Bitstream, &options)
+}
+
+#[test]
+fn test_obu_larger_than_stream() {
+    // A padding OBU declaring 127 bytes, with only 2 left
+    let result = analyze(include_bytes!("fixtures/truncated_obu.obu"));
+    assert!(matches!(
+        result,
+        Err(ElevatorError::TruncatedObu { position: 2 })
+    ));
+}
+
+#[test]
+fn test_obu_size_overflow() {
+    // A padding OBU whose size field decodes to the largest 32-bit value
+    let result = analyze(include_bytes!("fixtures/clobbered_obu_size.obu"));
+    assert!(matches!(
+        result,
+        Err(ElevatorError::TruncatedObu { position: 2 })
+    ));
+}
+
+#[test]
+fn test_obu_extension_past_end() {
+    // The last byte of the stream is an OBU header without a size field, announcing an extension header
+    let result = analyze(include_bytes!("fixtures/extension_without_room.obu"));
+    assert!(matches!(
+        result,
+        Err(ElevatorError::TruncatedObu { position: 2 })
+    ));
+}
+
+#[test]
+fn test_temporal_unit_larger_than_stream() {
+    // An Annex B temporal unit declaring 16 bytes, with only 3 left
+    let result = analyze(include_bytes!("fixtures/oversized_temporal_unit.annexb"));
+    assert!(matches!(
+        result,
+        Err(ElevatorError::TruncatedFrame {
+            position: 1,
+            size: 16
+        })
+    ));
+}
+
+#[test]
+fn test_mp4_sample_count_larger_than_file() {
+    // An MP4 sample table declaring 2^32 - 1 samples of 1 byte each, in a 60-byte file
+    let result = elevator::analyze(
+        Cursor::new(&include_bytes!("fixtures/huge_sample_count.mp4")[..]),
+        FileFormat::MP4,
+        &AnalysisOptions::default(),
+    );
+    assert!(matches!(
+        result,
+        Err(ElevatorError::Io(ref err))
+            if err.kind() == io::ErrorKind::InvalidData && err.to_string().contains("at byte 40")
+    ));
+}